@@ -1,9 +1,23 @@
-use log::debug;
-use matchit::Router;
+use flate2::read::GzDecoder;
+use log::{debug, warn};
 use serde_json::Value;
+use std::collections::HashSet;
+use std::io::Read;
 use std::rc::Rc;
 
-use crate::router::normalize_path;
+use crate::headers::{
+    base64_decode, BucketSource, Bucketing, MissingMethod, PathConflictPolicy, TemplateEncoding,
+    TemplateStyle,
+};
+use crate::matcher::{MatchEngine, PathMatcher};
+use crate::router::{
+    normalize_path, AnyMethodPrecedence, BasePathMatch, ExtensionServices, HostMatchOrder,
+    LargeBody, MatchPrecedence, ParamCoercion, ParamType, Route, RoutingKey, TieBreak, ANY_METHOD,
+};
+
+/// How many times `maxConfigBytes` a decompressed configuration may be,
+/// guarding against gzip bombs.
+const MAX_DECOMPRESSION_RATIO: u64 = 4;
 
 #[derive(Clone, Debug)]
 pub(crate) struct ServerSpec {
@@ -11,8 +25,57 @@ pub(crate) struct ServerSpec {
     pub(crate) base_path: String,
 }
 
+pub(crate) struct PrefixSpec {
+    pub(crate) route_path: String,
+    pub(crate) template: String,
+}
+
+/// Whether `bytes` start with the gzip magic number.
+pub(crate) fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0x1f, 0x8b])
+}
+
+/// Decompresses a gzipped configuration of at most `max_config_bytes`
+/// times [`MAX_DECOMPRESSION_RATIO`].
+pub(crate) fn gunzip_config(
+    bytes: &[u8],
+    max_config_bytes: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let limit = (max_config_bytes as u64).saturating_mul(MAX_DECOMPRESSION_RATIO);
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes)
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > limit {
+        return Err(format!(
+            "Decompressed configuration exceeds {} bytes ({} times maxConfigBytes)",
+            limit, MAX_DECOMPRESSION_RATIO
+        )
+        .into());
+    }
+    Ok(decompressed)
+}
+
+/// Unwraps a `{ "configGzipB64": "..." }` configuration into the JSON it
+/// carries. Any other configuration is returned unchanged.
+pub(crate) fn unwrap_compressed_config(
+    config: Value,
+    max_config_bytes: usize,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let Some(blob) = config.get("configGzipB64") else {
+        return Ok(config);
+    };
+    let blob = blob
+        .as_str()
+        .ok_or("'configGzipB64' must be a base64 string")?;
+    let compressed = base64_decode(blob).ok_or("'configGzipB64' is not valid base64")?;
+    let decompressed = gunzip_config(&compressed, max_config_bytes)?;
+    Ok(serde_json::from_slice(&decompressed)?)
+}
+
 pub(crate) fn parse_servers(
     service: &Value,
+    allowed_schemes: &HashSet<String>,
 ) -> Result<Vec<ServerSpec>, Box<dyn std::error::Error>> {
     let servers_value = service.get("servers");
     if servers_value.is_none() {
@@ -32,15 +95,588 @@ pub(crate) fn parse_servers(
     for server in servers {
         let urls = expand_server_urls(server)?;
         for url in urls {
-            specs.push(parse_server_url(&url)?);
+            specs.push(parse_server_url(&url, allowed_schemes)?);
+        }
+    }
+    Ok(specs)
+}
+
+/// Reads a positive integer option. Numeric strings such as `"256"` are
+/// accepted too, as some control planes render every value as a string.
+pub(crate) fn parse_positive_integer(
+    config: &Value,
+    key: &str,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    match config.get(key) {
+        None => Ok(None),
+        Some(value) => {
+            let number = match value {
+                Value::String(text) => text.trim().parse::<u64>().ok(),
+                _ => value.as_u64(),
+            };
+            let number = number
+                .filter(|number| *number > 0)
+                .ok_or_else(|| format!("'{}' must be a positive integer", key))?;
+            Ok(Some(number))
+        }
+    }
+}
+
+pub(crate) fn parse_service_param(
+    service: &Value,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match service.get("x-service-param") {
+        None => Ok(None),
+        Some(value) => {
+            let param = value
+                .as_str()
+                .filter(|param| !param.is_empty())
+                .ok_or("Invalid 'x-service-param' in service configuration")?;
+            Ok(Some(param.to_string()))
+        }
+    }
+}
+
+pub(crate) fn parse_base_path_exclusive(
+    service: &Value,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match service.get("basePathExclusive") {
+        None => Ok(false),
+        Some(value) => value
+            .as_bool()
+            .ok_or_else(|| "Invalid 'basePathExclusive' in service configuration".into()),
+    }
+}
+
+/// Reads a service's `routingValue`, the routing header value that selects
+/// it when `routingKey` is a header.
+pub(crate) fn parse_routing_value(
+    service: &Value,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match service.get("routingValue") {
+        None => Ok(None),
+        Some(value) => value
+            .as_str()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| Some(value.to_ascii_lowercase()))
+            .ok_or_else(|| "Invalid 'routingValue' in service configuration".into()),
+    }
+}
+
+pub(crate) fn parse_template_style(
+    service: &Value,
+) -> Result<TemplateStyle, Box<dyn std::error::Error>> {
+    match service.get("templateStyle").map(|value| value.as_str()) {
+        None => Ok(TemplateStyle::OpenApi),
+        Some(Some("openapi")) => Ok(TemplateStyle::OpenApi),
+        Some(Some("colon")) => Ok(TemplateStyle::Colon),
+        Some(Some("regex")) => Ok(TemplateStyle::Regex),
+        Some(_) => Err("Service 'templateStyle' must be 'openapi', 'colon' or 'regex'".into()),
+    }
+}
+
+pub(crate) fn parse_match_precedence(
+    config: &Value,
+) -> Result<MatchPrecedence, Box<dyn std::error::Error>> {
+    match config.get("matchPrecedence").map(|value| value.as_str()) {
+        None => Ok(MatchPrecedence::GroupFirst),
+        Some(Some("group-first")) => Ok(MatchPrecedence::GroupFirst),
+        Some(Some("method-first")) => Ok(MatchPrecedence::MethodFirst),
+        Some(_) => Err("'matchPrecedence' must be 'group-first' or 'method-first'".into()),
+    }
+}
+
+pub(crate) fn parse_host_match_order(
+    config: &Value,
+) -> Result<HostMatchOrder, Box<dyn std::error::Error>> {
+    match config.get("hostMatchOrder").map(|value| value.as_str()) {
+        None => Ok(HostMatchOrder::HostFirst),
+        Some(Some("host-first")) => Ok(HostMatchOrder::HostFirst),
+        Some(Some("wildcard-first")) => Ok(HostMatchOrder::WildcardFirst),
+        Some(_) => Err("'hostMatchOrder' must be 'host-first' or 'wildcard-first'".into()),
+    }
+}
+
+pub(crate) fn parse_match_engine(
+    config: &Value,
+) -> Result<MatchEngine, Box<dyn std::error::Error>> {
+    match config.get("matchEngine").map(|value| value.as_str()) {
+        // `OEF_TEST_MATCH_ENGINE=trie cargo test` runs the suite against the trie
+        None if cfg!(test) && std::env::var("OEF_TEST_MATCH_ENGINE").as_deref() == Ok("trie") => {
+            Ok(MatchEngine::Trie)
+        }
+        None => Ok(MatchEngine::Matchit),
+        Some(Some("matchit")) => Ok(MatchEngine::Matchit),
+        Some(Some("trie")) => Ok(MatchEngine::Trie),
+        Some(_) => Err("'matchEngine' must be 'matchit' or 'trie'".into()),
+    }
+}
+
+pub(crate) fn parse_any_method_precedence(
+    config: &Value,
+) -> Result<AnyMethodPrecedence, Box<dyn std::error::Error>> {
+    match config
+        .get("anyMethodPrecedence")
+        .map(|value| value.as_str())
+    {
+        None => Ok(AnyMethodPrecedence::Low),
+        Some(Some("low")) => Ok(AnyMethodPrecedence::Low),
+        Some(Some("high")) => Ok(AnyMethodPrecedence::High),
+        Some(_) => Err("'anyMethodPrecedence' must be 'low' or 'high'".into()),
+    }
+}
+
+/// Path normalization options that `normalizationProfile` sets.
+const NORMALIZATION_KEYS: [&str; 7] = [
+    "stripFragment",
+    "resolveDotSegments",
+    "uppercasePercentEncoding",
+    "decodePlusInPath",
+    "trimPath",
+    "rejectWhitespacePath",
+    "preserveLeadingDoubleSlash",
+];
+
+/// The path normalization options, as set by `normalizationProfile` and
+/// overridden by any of them set explicitly. Without a profile, only the
+/// explicit ones are present.
+pub(crate) fn expand_normalization_profile(
+    config: &Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    // Values in NORMALIZATION_KEYS order
+    let preset = match config
+        .get("normalizationProfile")
+        .map(|value| value.as_str())
+    {
+        None => None,
+        // As few equivalences as possible; unusual paths stay distinct or miss
+        Some(Some("strict")) => Some([true, false, false, false, false, true, true]),
+        // Forgive sloppy clients: stray whitespace, `+` for spaces, dot
+        // segments and lowercase escapes
+        Some(Some("lenient")) => Some([true, true, true, true, true, false, false]),
+        // The equivalences of RFC 3986 section 6.2.2, where whitespace is
+        // invalid and `+` is a literal character
+        Some(Some("rfc3986")) => Some([true, true, true, false, false, true, false]),
+        Some(_) => {
+            return Err("'normalizationProfile' must be 'strict', 'lenient' or 'rfc3986'".into())
+        }
+    };
+    let mut expanded = serde_json::Map::new();
+    for (i, key) in NORMALIZATION_KEYS.iter().enumerate() {
+        let value = config
+            .get(*key)
+            .cloned()
+            .or_else(|| preset.map(|preset| Value::Bool(preset[i])));
+        if let Some(value) = value {
+            expanded.insert(key.to_string(), value);
+        }
+    }
+    Ok(Value::Object(expanded))
+}
+
+pub(crate) fn parse_tie_break(config: &Value) -> Result<TieBreak, Box<dyn std::error::Error>> {
+    match config.get("tieBreak").map(|value| value.as_str()) {
+        None => Ok(TieBreak::ConfigOrder),
+        Some(Some("config-order")) => Ok(TieBreak::ConfigOrder),
+        Some(Some("service-name")) => Ok(TieBreak::ServiceName),
+        Some(_) => Err("'tieBreak' must be 'config-order' or 'service-name'".into()),
+    }
+}
+
+pub(crate) fn parse_param_coercion(
+    config: &Value,
+) -> Result<ParamCoercion, Box<dyn std::error::Error>> {
+    match config.get("paramCoercion").map(|value| value.as_str()) {
+        None => Ok(ParamCoercion::Off),
+        Some(Some("off")) => Ok(ParamCoercion::Off),
+        Some(Some("strict")) => Ok(ParamCoercion::Strict),
+        Some(Some("lenient")) => Ok(ParamCoercion::Lenient),
+        Some(_) => Err("'paramCoercion' must be 'off', 'strict' or 'lenient'".into()),
+    }
+}
+
+pub(crate) fn parse_base_path_match(
+    config: &Value,
+) -> Result<BasePathMatch, Box<dyn std::error::Error>> {
+    match config.get("basePathMatch").map(|value| value.as_str()) {
+        None => Ok(BasePathMatch::Segment),
+        Some(Some("segment")) => Ok(BasePathMatch::Segment),
+        Some(Some("prefix")) => Ok(BasePathMatch::Prefix),
+        Some(_) => Err("'basePathMatch' must be 'segment' or 'prefix'".into()),
+    }
+}
+
+pub(crate) fn parse_template_encoding(
+    config: &Value,
+) -> Result<TemplateEncoding, Box<dyn std::error::Error>> {
+    match config
+        .get("encodeTemplateHeader")
+        .map(|value| value.as_str())
+    {
+        None => Ok(TemplateEncoding::None),
+        Some(Some("none")) => Ok(TemplateEncoding::None),
+        Some(Some("hex")) => Ok(TemplateEncoding::Hex),
+        Some(Some("base64")) => Ok(TemplateEncoding::Base64),
+        Some(_) => Err("'encodeTemplateHeader' must be 'none', 'hex' or 'base64'".into()),
+    }
+}
+
+pub(crate) fn parse_bucketing(
+    config: &Value,
+) -> Result<Option<Bucketing>, Box<dyn std::error::Error>> {
+    let Some(bucketing_value) = config.get("bucketing") else {
+        return Ok(None);
+    };
+    let bucketing = bucketing_value
+        .as_object()
+        .ok_or("'bucketing' must be an object")?;
+    let name = |key: &str| -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match bucketing.get(key) {
+            None => Ok(None),
+            Some(value) => value
+                .as_str()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Some(name.to_string()))
+                .ok_or_else(|| format!("'bucketing.{}' must be a non-empty string", key).into()),
+        }
+    };
+    let source = match (name("header")?, name("cookie")?) {
+        (Some(header), None) => BucketSource::Header(header.to_ascii_lowercase()),
+        (None, Some(cookie)) => BucketSource::Cookie(cookie),
+        _ => return Err("'bucketing' must set exactly one of 'header' or 'cookie'".into()),
+    };
+    let buckets = parse_positive_integer(bucketing_value, "buckets")?.unwrap_or(100);
+    Ok(Some(Bucketing { source, buckets }))
+}
+
+/// How much optional work a request gets under `featureBudget`.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub(crate) enum FeatureLevel {
+    /// Every configured feature runs.
+    Full,
+    /// Shadow matching and diagnostic/derived headers are skipped.
+    Reduced,
+    /// Audit logging, filter metadata and baggage are skipped as well.
+    Minimal,
+}
+
+/// Load thresholds past which optional features are skipped. A request
+/// over a threshold is `Reduced`, over twice a threshold `Minimal`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FeatureBudget {
+    pub(crate) headers: Option<u64>,
+    pub(crate) path_length: Option<u64>,
+}
+
+impl FeatureBudget {
+    pub(crate) fn level(&self, nheaders: usize, path_length: usize) -> FeatureLevel {
+        let level = |value: usize, threshold: Option<u64>| match threshold {
+            Some(threshold) if value as u64 > threshold.saturating_mul(2) => FeatureLevel::Minimal,
+            Some(threshold) if value as u64 > threshold => FeatureLevel::Reduced,
+            _ => FeatureLevel::Full,
+        };
+        let by_headers = level(nheaders, self.headers);
+        let by_path = level(path_length, self.path_length);
+        if by_headers > by_path {
+            by_headers
+        } else {
+            by_path
+        }
+    }
+}
+
+pub(crate) fn parse_feature_budget(
+    config: &Value,
+) -> Result<Option<FeatureBudget>, Box<dyn std::error::Error>> {
+    let Some(budget) = config.get("featureBudget") else {
+        return Ok(None);
+    };
+    if !budget.is_object() {
+        return Err("'featureBudget' must be an object".into());
+    }
+    let budget = FeatureBudget {
+        headers: parse_positive_integer(budget, "headers")?,
+        path_length: parse_positive_integer(budget, "pathLength")?,
+    };
+    if budget.headers.is_none() && budget.path_length.is_none() {
+        return Err("'featureBudget' must set 'headers' or 'pathLength'".into());
+    }
+    Ok(Some(budget))
+}
+
+/// Reads the VM configuration's `tenant`, which namespaces the logs of
+/// VMs serving different logical gateways.
+pub(crate) fn parse_tenant(
+    vm_config: &Value,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match vm_config.get("tenant") {
+        None => Ok(None),
+        Some(value) => value
+            .as_str()
+            .filter(|tenant| {
+                !tenant.is_empty()
+                    && tenant
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte))
+            })
+            .map(|tenant| Some(tenant.to_string()))
+            .ok_or_else(|| "'tenant' must be a non-empty string of [A-Za-z0-9._-]".into()),
+    }
+}
+
+/// Parses a list of request headers to read a value from, such as
+/// `pathSource`, falling back to `default` when the key is absent.
+pub(crate) fn parse_header_sources(
+    config: &Value,
+    key: &str,
+    default: &[&str],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let Some(sources) = config.get(key) else {
+        return Ok(default.iter().map(|source| source.to_string()).collect());
+    };
+    let sources = sources
+        .as_array()
+        .filter(|sources| !sources.is_empty())
+        .ok_or_else(|| format!("'{}' must be a non-empty array of header names", key))?;
+    sources
+        .iter()
+        .map(|source| {
+            source
+                .as_str()
+                .map(str::trim)
+                .filter(|source| !source.is_empty())
+                .map(str::to_ascii_lowercase)
+                .ok_or_else(|| format!("'{}' entries must be non-empty strings", key).into())
+        })
+        .collect()
+}
+
+pub(crate) fn parse_path_conflict_policy(
+    config: &Value,
+) -> Result<PathConflictPolicy, Box<dyn std::error::Error>> {
+    match config.get("pathConflictPolicy").map(|value| value.as_str()) {
+        None => Ok(PathConflictPolicy::First),
+        Some(Some("first")) => Ok(PathConflictPolicy::First),
+        Some(Some("reject")) => Ok(PathConflictPolicy::Reject),
+        Some(Some("canonical")) => Ok(PathConflictPolicy::Canonical),
+        Some(_) => Err("'pathConflictPolicy' must be 'first', 'reject' or 'canonical'".into()),
+    }
+}
+
+pub(crate) fn parse_missing_method(
+    config: &Value,
+) -> Result<MissingMethod, Box<dyn std::error::Error>> {
+    match config
+        .get("missingMethodBehavior")
+        .map(|value| value.as_str())
+    {
+        None => Ok(MissingMethod::Any),
+        Some(Some("any")) => Ok(MissingMethod::Any),
+        Some(Some("reject")) => Ok(MissingMethod::Reject),
+        Some(Some("get")) => Ok(MissingMethod::Get),
+        Some(_) => Err("'missingMethodBehavior' must be 'any', 'reject' or 'get'".into()),
+    }
+}
+
+pub(crate) fn parse_routing_key(config: &Value) -> Result<RoutingKey, Box<dyn std::error::Error>> {
+    let invalid = "'routingKey' must be 'host' or 'header:<name>'";
+    match config.get("routingKey") {
+        None => Ok(RoutingKey::Host),
+        Some(value) => {
+            let value = value.as_str().ok_or(invalid)?.trim();
+            if value == "host" {
+                return Ok(RoutingKey::Host);
+            }
+            let name = value
+                .strip_prefix("header:")
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .ok_or(invalid)?;
+            Ok(RoutingKey::Header(name.to_ascii_lowercase()))
+        }
+    }
+}
+
+pub(crate) fn parse_host_list(
+    config: &Value,
+    key: &str,
+) -> Result<Option<HashSet<String>>, Box<dyn std::error::Error>> {
+    let hosts_value = config.get(key);
+    if hosts_value.is_none() {
+        return Ok(None);
+    }
+    let hosts = hosts_value
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("'{}' must be an array of hosts", key))?;
+
+    let mut parsed = HashSet::new();
+    for host in hosts {
+        let host = host
+            .as_str()
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| format!("'{}' entries must be non-empty strings", key))?;
+        parsed.insert(strip_port(&host.to_ascii_lowercase()).to_string());
+    }
+    Ok(Some(parsed))
+}
+
+/// The allowlist of `dynamicTargetHeaders`, lowercased, when
+/// `allowDynamicTargetHeader` is on, or `None` when it is off.
+pub(crate) fn parse_dynamic_target_headers(
+    config: &Value,
+) -> Result<Option<HashSet<String>>, Box<dyn std::error::Error>> {
+    let enabled = config
+        .get("allowDynamicTargetHeader")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(None);
+    }
+    let names = config
+        .get("dynamicTargetHeaders")
+        .and_then(Value::as_array)
+        .filter(|names| !names.is_empty())
+        .ok_or("'allowDynamicTargetHeader' needs a non-empty 'dynamicTargetHeaders' array")?;
+    let names = names
+        .iter()
+        .map(|name| {
+            name.as_str()
+                .filter(|name| {
+                    !name.is_empty()
+                        && name
+                            .bytes()
+                            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+                })
+                .map(str::to_ascii_lowercase)
+                .ok_or_else(|| format!("Invalid header name in 'dynamicTargetHeaders': {}", name))
+        })
+        .collect::<Result<HashSet<String>, String>>()?;
+    Ok(Some(names))
+}
+
+pub(crate) fn parse_prefixes(
+    service: &Value,
+) -> Result<Vec<PrefixSpec>, Box<dyn std::error::Error>> {
+    let catch_all = match service.get("catchAll") {
+        None => false,
+        Some(value) => value
+            .as_bool()
+            .ok_or("Invalid 'catchAll' in service configuration")?,
+    };
+    let prefixes = match service.get("prefixes") {
+        None => &Vec::new(),
+        Some(value) => value
+            .as_array()
+            .ok_or("Invalid 'prefixes' in service configuration")?,
+    };
+
+    let mut specs = Vec::new();
+    if catch_all {
+        // The catch-all segment does not match an empty remainder
+        for route_path in ["/", "/{*rest}"] {
+            specs.push(PrefixSpec {
+                route_path: route_path.to_string(),
+                template: "/*".to_string(),
+            });
+        }
+    }
+    for entry in prefixes {
+        let (prefix, template) = match entry {
+            Value::String(prefix) => (prefix.as_str(), None),
+            Value::Object(object) => {
+                let prefix = object
+                    .get("prefix")
+                    .and_then(Value::as_str)
+                    .ok_or("Missing 'prefix' in prefix configuration")?;
+                let template = match object.get("template") {
+                    Some(template) => Some(
+                        template
+                            .as_str()
+                            .ok_or("Prefix 'template' must be a string")?,
+                    ),
+                    None => None,
+                };
+                (prefix, template)
+            }
+            _ => return Err("Invalid entry in 'prefixes'".into()),
+        };
+        if !prefix.starts_with('/') {
+            return Err(format!("Prefix must start with '/': {}", prefix).into());
+        }
+        if prefix.contains('{') || prefix.contains('}') {
+            return Err(format!("Prefix cannot contain path parameters: {}", prefix).into());
         }
+
+        let normalized = normalize_path(prefix);
+        let base = normalized.trim_end_matches('/');
+        specs.push(PrefixSpec {
+            route_path: format!("{}/{{*rest}}", base),
+            template: template
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}/*", base)),
+        });
     }
     Ok(specs)
 }
 
+/// Reads `allowedSchemes`, the schemes accepted in absolute `servers.url`s.
+pub(crate) fn parse_allowed_schemes(
+    config: &Value,
+) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    let Some(schemes) = config.get("allowedSchemes") else {
+        return Ok(HashSet::from(["http".to_string(), "https".to_string()]));
+    };
+    let schemes = schemes
+        .as_array()
+        .ok_or("'allowedSchemes' must be an array of schemes")?;
+    schemes
+        .iter()
+        .map(|scheme| {
+            scheme
+                .as_str()
+                .map(str::trim)
+                .filter(|scheme| !scheme.is_empty())
+                .map(str::to_ascii_lowercase)
+                .ok_or_else(|| "'allowedSchemes' entries must be non-empty strings".into())
+        })
+        .collect()
+}
+
+/// Reads `extraMethods`, additional path item keys to register as
+/// method-specific routes (e.g. WebDAV's `propfind`).
+pub(crate) fn parse_extra_methods(
+    config: &Value,
+) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    let Some(methods) = config.get("extraMethods") else {
+        return Ok(HashSet::new());
+    };
+    let methods = methods
+        .as_array()
+        .ok_or("'extraMethods' must be an array of method names")?;
+    methods
+        .iter()
+        .map(|method| {
+            method
+                .as_str()
+                .filter(|method| {
+                    !method.is_empty()
+                        && method
+                            .bytes()
+                            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+                })
+                .map(str::to_ascii_lowercase)
+                .ok_or_else(|| format!("Invalid method in 'extraMethods': {}", method).into())
+        })
+        .collect()
+}
+
 pub(crate) fn parse_methods(
     path: &str,
     path_config: &Value,
+    extra_methods: &HashSet<String>,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let object = path_config
         .as_object()
@@ -52,7 +688,7 @@ pub(crate) fn parse_methods(
     let mut methods = Vec::new();
     for key in object.keys() {
         let lower = key.to_ascii_lowercase();
-        if is_http_method(&lower) {
+        if is_http_method(&lower) || extra_methods.contains(&lower) {
             methods.push(lower);
         }
     }
@@ -67,13 +703,524 @@ pub(crate) fn parse_methods(
     Ok(methods)
 }
 
+/// Reads a path's `x-method-aliases`, e.g. `{ "patch": "put" }`, as
+/// `(alias, method)` pairs. Each method must be one the path item declares,
+/// and no alias may be.
+pub(crate) fn parse_method_aliases(
+    path: &str,
+    path_config: &Value,
+    methods: &[String],
+    extra_methods: &HashSet<String>,
+) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let Some(value) = path_config.get("x-method-aliases") else {
+        return Ok(Vec::new());
+    };
+    let aliases = value
+        .as_object()
+        .ok_or_else(|| format!("'x-method-aliases' for path '{}' must be an object", path))?;
+    let mut parsed = Vec::new();
+    for (alias, method) in aliases {
+        let alias = alias.to_ascii_lowercase();
+        if !is_http_method(&alias) && !extra_methods.contains(&alias) {
+            return Err(format!(
+                "'x-method-aliases' for path '{}' has unknown method '{}'",
+                path, alias
+            )
+            .into());
+        }
+        if methods.contains(&alias) {
+            return Err(format!(
+                "'x-method-aliases' for path '{}' aliases '{}', which the path declares",
+                path, alias
+            )
+            .into());
+        }
+        let method = method
+            .as_str()
+            .map(str::to_ascii_lowercase)
+            .filter(|method| method != ANY_METHOD && methods.contains(method))
+            .ok_or_else(|| {
+                format!(
+                    "'x-method-aliases' for path '{}' must map '{}' to a method the path declares",
+                    path, alias
+                )
+            })?;
+        parsed.push((alias, method));
+    }
+    Ok(parsed)
+}
+
+pub(crate) fn parse_required_header(
+    path: &str,
+    path_config: &Value,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    match path_config.get("x-require-header") {
+        None => Ok(None),
+        Some(value) => {
+            let header = value
+                .as_str()
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .ok_or_else(|| format!("Invalid 'x-require-header' for path '{}'", path))?;
+            Ok(Some(header.to_ascii_lowercase()))
+        }
+    }
+}
+
+pub(crate) fn parse_timeout_hint(
+    path: &str,
+    path_config: &Value,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    match path_config.get("x-timeout-ms") {
+        None => Ok(None),
+        Some(value) => {
+            let timeout = value
+                .as_u64()
+                .filter(|timeout| *timeout > 0)
+                .ok_or_else(|| format!("Invalid 'x-timeout-ms' for path '{}'", path))?;
+            Ok(Some(timeout))
+        }
+    }
+}
+
+/// Reads the `integer` and `boolean` path parameters declared in a path
+/// item's `parameters`. Other parameters and schema types are skipped.
+pub(crate) fn parse_param_types(path_config: &Value) -> Vec<(String, ParamType)> {
+    let Some(parameters) = path_config.get("parameters").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    parameters
+        .iter()
+        .filter(|parameter| parameter.get("in").and_then(Value::as_str) == Some("path"))
+        .filter_map(|parameter| {
+            let name = parameter.get("name").and_then(Value::as_str)?;
+            let param_type = match parameter.pointer("/schema/type").and_then(Value::as_str)? {
+                "integer" => ParamType::Integer,
+                "boolean" => ParamType::Boolean,
+                _ => return None,
+            };
+            Some((name.to_string(), param_type))
+        })
+        .collect()
+}
+
+/// Reads a path's `x-extensions`, mapping extensions of the last segment
+/// (e.g. `.json`) to the services their requests are attributed to.
+pub(crate) fn parse_extensions(
+    path: &str,
+    path_config: &Value,
+) -> Result<ExtensionServices, Box<dyn std::error::Error>> {
+    let Some(value) = path_config.get("x-extensions") else {
+        return Ok(Vec::new());
+    };
+    let invalid = || {
+        format!(
+            "'x-extensions' for path '{}' must map extensions such as '.json' to service names",
+            path
+        )
+    };
+    let extensions = value
+        .as_object()
+        .filter(|map| !map.is_empty())
+        .ok_or_else(invalid)?;
+    extensions
+        .iter()
+        .map(|(extension, service)| {
+            let valid_extension = extension.len() > 1
+                && extension.starts_with('.')
+                && !extension[1..].contains(['.', '/']);
+            let service = service.as_str().filter(|service| !service.is_empty());
+            match (valid_extension, service) {
+                (true, Some(service)) => {
+                    Ok((extension.to_ascii_lowercase(), Rc::new(service.to_string())))
+                }
+                _ => Err(invalid().into()),
+            }
+        })
+        .collect()
+}
+
+/// Reads a path's `x-large-body-service` and the `x-large-body-threshold`
+/// in bytes it requires.
+pub(crate) fn parse_large_body(
+    path: &str,
+    path_config: &Value,
+) -> Result<Option<LargeBody>, Box<dyn std::error::Error>> {
+    let service = path_config.get("x-large-body-service");
+    let threshold = parse_positive_integer(path_config, "x-large-body-threshold")?;
+    match (service, threshold) {
+        (None, None) => Ok(None),
+        (Some(service), Some(threshold)) => {
+            let service = service
+                .as_str()
+                .filter(|service| !service.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "'x-large-body-service' for path '{}' must be a non-empty string",
+                        path
+                    )
+                })?;
+            Ok(Some(LargeBody {
+                threshold,
+                service: Rc::new(service.to_string()),
+            }))
+        }
+        _ => Err(format!(
+            "Path '{}' must set 'x-large-body-service' and 'x-large-body-threshold' together",
+            path
+        )
+        .into()),
+    }
+}
+
+/// Reads a path's `x-sunset` date (`YYYY-MM-DD`) and renders it as the
+/// HTTP-date for the `Sunset` header, at midnight GMT.
+pub(crate) fn parse_sunset(
+    path: &str,
+    path_config: &Value,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(value) = path_config.get("x-sunset") else {
+        return Ok(None);
+    };
+    let invalid = || {
+        format!(
+            "Invalid 'x-sunset' for path '{}', expected YYYY-MM-DD",
+            path
+        )
+    };
+    let date = value.as_str().ok_or_else(invalid)?;
+    let mut parts = date.split('-');
+    let (Some(year), Some(month), Some(day), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid().into());
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return Err(invalid().into());
+    }
+    let number = |part: &str| part.parse::<i64>().map_err(|_| invalid());
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid().into()),
+    };
+    if day < 1 || day > days_in_month {
+        return Err(invalid().into());
+    }
+
+    // Days since 1970-01-01 (a Thursday), per the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    Ok(Some(format!(
+        "{}, {:02} {} {} 00:00:00 GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year
+    )))
+}
+
+pub(crate) fn parse_websocket(
+    path: &str,
+    path_config: &Value,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match path_config.get("x-websocket") {
+        None => Ok(false),
+        Some(value) => value
+            .as_bool()
+            .ok_or_else(|| format!("Invalid 'x-websocket' for path '{}'", path).into()),
+    }
+}
+
+pub(crate) fn parse_preserve_trailing_slash(
+    path: &str,
+    path_config: &Value,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match path_config.get("x-preserve-trailing-slash") {
+        None => Ok(false),
+        Some(value) => value.as_bool().ok_or_else(|| {
+            format!("Invalid 'x-preserve-trailing-slash' for path '{}'", path).into()
+        }),
+    }
+}
+
+pub(crate) fn parse_operation<'a>(path_config: &'a Value, method: &str) -> Option<&'a Value> {
+    path_config
+        .as_object()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(method))
+        .map(|(_, operation)| operation)
+}
+
+pub(crate) fn parse_summary(operation: Option<&Value>) -> Option<String> {
+    operation?
+        .get("summary")
+        .and_then(Value::as_str)
+        .filter(|summary| !summary.is_empty())
+        .map(str::to_string)
+}
+
+/// First media type an operation produces: the first entry of Swagger 2
+/// `produces`, or else the first `content` type of its OpenAPI 3
+/// responses in status code order.
+pub(crate) fn parse_produces(operation: Option<&Value>) -> Option<String> {
+    let operation = operation?;
+    let declared = operation
+        .get("produces")
+        .and_then(Value::as_array)
+        .and_then(|produces| produces.first())
+        .and_then(Value::as_str);
+    let declared = declared.or_else(|| {
+        operation
+            .get("responses")?
+            .as_object()?
+            .values()
+            .find_map(|response| response.get("content")?.as_object()?.keys().next())
+            .map(String::as_str)
+    });
+    declared
+        .map(str::trim)
+        .filter(|media_type| !media_type.is_empty())
+        .map(str::to_string)
+}
+
+/// Scheme name of the first security requirement that applies to an
+/// operation: its own `security`, or else the service-wide one. An empty
+/// requirement list (`security: []`) marks the operation as unsecured.
+pub(crate) fn parse_security_scheme(operation: Option<&Value>, service: &Value) -> Option<String> {
+    let requirements = match operation?.get("security") {
+        Some(requirements) => requirements,
+        None => service.get("security")?,
+    };
+    requirements
+        .as_array()?
+        .first()?
+        .as_object()?
+        .keys()
+        .next()
+        .cloned()
+}
+
+/// A field of the audit log line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LogField {
+    Host,
+    Method,
+    Path,
+    Template,
+    Service,
+    Params,
+    Decision,
+}
+
+impl LogField {
+    pub(crate) const DEFAULT: [LogField; 6] = [
+        LogField::Host,
+        LogField::Method,
+        LogField::Path,
+        LogField::Template,
+        LogField::Service,
+        LogField::Decision,
+    ];
+}
+
+pub(crate) fn parse_log_fields(
+    config: &Value,
+) -> Result<Vec<LogField>, Box<dyn std::error::Error>> {
+    let invalid = "'logFields' must be an array of 'host', 'method', 'path', 'template', 'service', 'params' or 'decision'";
+    let Some(fields) = config.get("logFields") else {
+        return Ok(LogField::DEFAULT.to_vec());
+    };
+    let mut parsed = Vec::new();
+    for field in fields.as_array().ok_or(invalid)? {
+        let field = match field.as_str().ok_or(invalid)? {
+            "host" => LogField::Host,
+            "method" => LogField::Method,
+            "path" => LogField::Path,
+            "template" => LogField::Template,
+            "service" => LogField::Service,
+            "params" => LogField::Params,
+            "decision" => LogField::Decision,
+            _ => return Err(invalid.into()),
+        };
+        if !parsed.contains(&field) {
+            parsed.push(field);
+        }
+    }
+    Ok(parsed)
+}
+
+/// What happens when services declare the same exact (parameterless)
+/// template in the same route group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ExactOverlap {
+    /// The service declared first keeps the route.
+    First,
+    /// The service declared last takes the route over.
+    Last,
+    /// The configuration is rejected.
+    Error,
+}
+
+pub(crate) fn parse_exact_overlap(
+    config: &Value,
+) -> Result<ExactOverlap, Box<dyn std::error::Error>> {
+    match config.get("onExactOverlap").map(|value| value.as_str()) {
+        None => Ok(ExactOverlap::Error),
+        Some(Some("first")) => Ok(ExactOverlap::First),
+        Some(Some("last")) => Ok(ExactOverlap::Last),
+        Some(Some("error")) => Ok(ExactOverlap::Error),
+        Some(_) => Err("'onExactOverlap' must be 'first', 'last' or 'error'".into()),
+    }
+}
+
+/// What happens when a template differs from an already registered one
+/// only by its parameter names, e.g. `/users/{uid}` after `/users/{id}`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ParamNameConflict {
+    /// The first template keeps the route.
+    First,
+    /// The first template keeps the route, which also records the later
+    /// template's parameter names.
+    Merge,
+    /// The configuration is rejected.
+    Error,
+}
+
+pub(crate) fn parse_param_name_conflict(
+    config: &Value,
+) -> Result<ParamNameConflict, Box<dyn std::error::Error>> {
+    match config
+        .get("onParamNameConflict")
+        .map(|value| value.as_str())
+    {
+        None => Ok(ParamNameConflict::Error),
+        Some(Some("first")) => Ok(ParamNameConflict::First),
+        Some(Some("merge")) => Ok(ParamNameConflict::Merge),
+        Some(Some("error")) => Ok(ParamNameConflict::Error),
+        Some(_) => Err("'onParamNameConflict' must be 'first', 'merge' or 'error'".into()),
+    }
+}
+
+/// Names of a template's parameters in order, without catch-all `*`s.
+fn template_param_names(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name.trim_start_matches('*'))
+        .collect()
+}
+
+/// The template with each parameter name blanked out, so templates that
+/// only differ by parameter names have the same shape.
+fn template_shape(template: &str) -> String {
+    let mut shape = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        shape.push_str(&rest[..=start]);
+        if rest[start + 1..].starts_with('*') {
+            shape.push('*');
+        }
+        shape.push('}');
+        rest = &rest[end + 1..];
+    }
+    shape.push_str(rest);
+    shape
+}
+
+/// Inserts a template route, resolving an exact template already declared
+/// by another service according to `on_exact_overlap`, and a template that
+/// only differs from a registered one by parameter names according to
+/// `on_param_name_conflict`.
+pub(crate) fn insert_template_route(
+    router: &mut dyn PathMatcher,
+    path: &str,
+    route: Route,
+    on_exact_overlap: ExactOverlap,
+    on_param_name_conflict: ParamNameConflict,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if path.contains('{') && on_param_name_conflict != ParamNameConflict::Error {
+        // Templates cannot contain NUL, so the probe only reaches parameters
+        let probe = template_shape(path)
+            .replace("{*}", "\0")
+            .replace("{}", "\0");
+        let shape = template_shape(&route.template);
+        if let Some(existing) = router.at_mut(&probe) {
+            if existing.template != route.template && template_shape(&existing.template) == shape {
+                warn!(
+                    "[oef] Template '{}' of service '{}' only differs from '{}' by parameter names, keeping '{}'",
+                    route.template, route.service_name, existing.template, existing.template
+                );
+                if on_param_name_conflict == ParamNameConflict::Merge {
+                    let names = template_param_names(&existing.template)
+                        .into_iter()
+                        .zip(template_param_names(&route.template))
+                        .filter(|(name, other)| name != other)
+                        .map(|(name, other)| (name.to_string(), other.to_string()));
+                    existing.merged_param_names.extend(names);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    let overlapping = !path.contains('{')
+        && router.at(path).is_some_and(|existing| {
+            existing.value.template == route.template
+                && existing.value.service_name != route.service_name
+        });
+    if overlapping {
+        match on_exact_overlap {
+            ExactOverlap::First => {
+                warn!(
+                    "[oef] Exact route '{}' of service '{}' is already declared, ignoring it",
+                    path, route.service_name
+                );
+                return Ok(());
+            }
+            ExactOverlap::Last => {
+                warn!(
+                    "[oef] Exact route '{}' is taken over by service '{}'",
+                    path, route.service_name
+                );
+                router.remove(path);
+            }
+            ExactOverlap::Error => {}
+        }
+    }
+    insert_route(router, path, route)
+}
+
 pub(crate) fn insert_route(
-    router: &mut Router<(String, Rc<String>)>,
+    router: &mut dyn PathMatcher,
     path: &str,
-    service_name: Rc<String>,
+    route: Route,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("[oef] Inserting route: {} for service: {}", path, service_name);
-    if let Err(e) = router.insert(path, (path.to_string(), service_name)) {
+    debug!(
+        "[oef] Inserting route: {} for service: {}",
+        path, route.service_name
+    );
+    if let Err(e) = router.insert(path, route) {
         return Err(format!("Duplicate or conflicting route '{}': {}", path, e).into());
     }
     Ok(())
@@ -95,6 +1242,13 @@ pub(crate) fn strip_port(host: &str) -> &str {
     host
 }
 
+/// Drops the `user:pass@` userinfo some clients leave in the authority.
+pub(crate) fn strip_userinfo(authority: &str) -> &str {
+    authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host)
+}
+
 fn expand_server_urls(server: &Value) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let url = server
         .get("url")
@@ -147,7 +1301,10 @@ fn expand_server_urls(server: &Value) -> Result<Vec<String>, Box<dyn std::error:
     Ok(urls)
 }
 
-fn parse_server_url(url: &str) -> Result<ServerSpec, Box<dyn std::error::Error>> {
+fn parse_server_url(
+    url: &str,
+    allowed_schemes: &HashSet<String>,
+) -> Result<ServerSpec, Box<dyn std::error::Error>> {
     let without_fragment = url.split('#').next().unwrap_or("");
     let without_query = without_fragment.split('?').next().unwrap_or("");
     let trimmed = without_query.trim();
@@ -157,6 +1314,12 @@ fn parse_server_url(url: &str) -> Result<ServerSpec, Box<dyn std::error::Error>>
 
     let mut rest = trimmed;
     if let Some(idx) = trimmed.find("://") {
+        let scheme = trimmed[..idx].to_ascii_lowercase();
+        if !allowed_schemes.contains(&scheme) {
+            return Err(
+                format!("Server url '{}' has a disallowed scheme '{}'", url, scheme).into(),
+            );
+        }
         rest = &trimmed[idx + 3..];
     }
 
@@ -200,6 +1363,15 @@ fn normalize_base_path(path: &str) -> Result<String, Box<dyn std::error::Error>>
 fn is_http_method(method: &str) -> bool {
     matches!(
         method,
-        "get" | "post" | "put" | "delete" | "patch" | "options" | "head" | "trace"
+        "get"
+            | "post"
+            | "put"
+            | "delete"
+            | "patch"
+            | "options"
+            | "head"
+            | "trace"
+            | "query"
+            | ANY_METHOD
     )
 }
@@ -0,0 +1,399 @@
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::cell::Cell;
+
+/// Maximum size of a `baggage` header value per the W3C Baggage spec.
+const MAX_BAGGAGE_BYTES: usize = 8192;
+/// Maximum number of list members in a `baggage` header value.
+const MAX_BAGGAGE_MEMBERS: usize = 180;
+
+/// How disagreeing `pathSource` headers are handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PathConflictPolicy {
+    /// Use the first present source.
+    First,
+    /// Reject the request unless all present sources are identical.
+    Reject,
+    /// Reject the request unless all present sources normalize equal.
+    Canonical,
+}
+
+/// Slug of a template for log indexing: `/users/{id}` becomes `users__id`.
+/// Segments are joined by `__` and braces (and the `*` of catch-alls) are
+/// dropped; the root template `/` becomes `root`.
+pub(crate) fn route_slug(template: &str) -> String {
+    let slug = template
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.replace(['{', '}', '*'], ""))
+        .collect::<Vec<_>>()
+        .join("__");
+    if slug.is_empty() {
+        "root".to_string()
+    } else {
+        slug
+    }
+}
+
+/// The `x-oef-routing-key` value `<host><separator><service>`. `%` and the
+/// separator are percent-encoded in both parts, so the key splits back
+/// unambiguously.
+pub(crate) fn routing_key(host: &str, service: &str, separator: &str) -> String {
+    let escape = |value: &str| {
+        let value = value.replace('%', "%25");
+        if separator.is_empty() {
+            return value;
+        }
+        let encoded: String = separator
+            .bytes()
+            .map(|byte| format!("%{:02X}", byte))
+            .collect();
+        value.replace(separator, &encoded)
+    };
+    format!("{}{}{}", escape(host), separator, escape(service))
+}
+
+/// Name of the `emitParams` header for a path parameter: lowercased, with
+/// characters other than letters, digits and `-` replaced by `-`.
+pub(crate) fn param_header_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("x-oef-param-{}", name)
+}
+
+/// How a request without a `:method` pseudo-header is handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MissingMethod {
+    /// Match with the method `unknown`, so only any-method routes apply.
+    Any,
+    /// Reject the request.
+    Reject,
+    /// Match as a `GET` request.
+    Get,
+}
+
+/// Encoding applied to the `x-path-template` header value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TemplateEncoding {
+    None,
+    /// Uppercase hex of the template's bytes.
+    Hex,
+    /// Standard padded base64 of the template's bytes.
+    Base64,
+}
+
+impl TemplateEncoding {
+    pub(crate) fn encode(self, template: &str) -> String {
+        match self {
+            TemplateEncoding::None => template.to_string(),
+            TemplateEncoding::Hex => template
+                .bytes()
+                .map(|byte| format!("{:02X}", byte))
+                .collect(),
+            TemplateEncoding::Base64 => base64_encode(template.as_bytes()),
+        }
+    }
+}
+
+/// Notation used for a service's templates in emitted headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TemplateStyle {
+    /// `/users/{id}`, as written in the OpenAPI document.
+    OpenApi,
+    /// `/users/:id`
+    Colon,
+    /// `^/users/[^/]+$`
+    Regex,
+}
+
+impl TemplateStyle {
+    /// Renders `template` in this style, or `None` if it is unchanged.
+    pub(crate) fn render(self, template: &str) -> Option<String> {
+        if self == TemplateStyle::OpenApi {
+            return None;
+        }
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while !rest.is_empty() {
+            let literal_end = rest.find('{').unwrap_or(rest.len());
+            let (literal, tail) = rest.split_at(literal_end);
+            match self {
+                TemplateStyle::Regex if literal.ends_with("/*") => {
+                    rendered.push_str(&regex_escape(&literal[..literal.len() - 1]));
+                    rendered.push_str(".*");
+                }
+                TemplateStyle::Regex => rendered.push_str(&regex_escape(literal)),
+                _ => rendered.push_str(literal),
+            }
+            rest = tail;
+            if let Some(param_end) = rest.find('}') {
+                match self {
+                    TemplateStyle::Regex => rendered.push_str("[^/]+"),
+                    _ => {
+                        rendered.push(':');
+                        rendered.push_str(&rest[1..param_end]);
+                    }
+                }
+                rest = &rest[param_end + 1..];
+            } else if !rest.is_empty() {
+                rendered.push_str(rest);
+                rest = "";
+            }
+        }
+        match self {
+            TemplateStyle::Regex => Some(format!("^{}$", rendered)),
+            _ => Some(rendered),
+        }
+    }
+}
+
+fn regex_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub(crate) fn base64_encode(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+/// Decodes standard base64, either fully padded or without any padding.
+/// Whitespace is ignored so wrapped blobs decode too.
+pub(crate) fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = encoded
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    let engine = if digits.contains(&b'=') {
+        &STANDARD
+    } else {
+        &STANDARD_NO_PAD
+    };
+    engine.decode(digits).ok()
+}
+
+/// Substitutes captured values into `template`, keeping each parameter's
+/// name next to its value, e.g. `/users/42[id]` for `/users/{id}`.
+pub(crate) fn annotate_template(template: &str, params: &[(String, String)]) -> String {
+    let mut annotated = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        annotated.push_str(&rest[..start]);
+        let name = &rest[start + 1..end];
+        match params.iter().find(|(param, _)| param == name) {
+            Some((_, value)) => annotated.push_str(&format!("{}[{}]", value, name)),
+            None => annotated.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    annotated.push_str(rest);
+    annotated
+}
+
+/// Verifies a `<service>|<template>|<expiry>|<hex HMAC-SHA256>` route token,
+/// where the signature covers everything before it and `<expiry>` is in Unix
+/// seconds. Returns the service and template if the signature matches,
+/// compared in constant time, and the token has not expired by `now`.
+pub(crate) fn verify_route_token<'a>(
+    token: &'a str,
+    secret: &[u8],
+    now: u64,
+) -> Option<(&'a str, &'a str)> {
+    let (payload, signature) = token.trim().rsplit_once('|')?;
+    let (route, expiry) = payload.rsplit_once('|')?;
+    let (service, template) = route.split_once('|')?;
+    if service.is_empty() || !template.starts_with('/') || signature.len() % 2 != 0 {
+        return None;
+    }
+    let signature = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).ok()?;
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    // Checked once signed, so a forged expiry is never trusted
+    let expiry: u64 = expiry.parse().ok()?;
+    (now < expiry).then_some((service, template))
+}
+
+/// Replaces `{name}` placeholders in `template` with the matching value.
+/// Unknown placeholders are kept. With `json`, values are escaped for use
+/// inside a JSON string.
+pub(crate) fn render_placeholders(template: &str, values: &[(&str, &str)], json: bool) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder.find('}').and_then(|end| {
+            let name = &placeholder[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (end, *value))
+        });
+        match value {
+            Some((end, value)) => {
+                if json {
+                    let quoted = Value::String(value.to_string()).to_string();
+                    rendered.push_str(&quoted[1..quoted.len() - 1]);
+                } else {
+                    rendered.push_str(value);
+                }
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub(crate) fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Appends the matched route to a W3C `baggage` value, replacing any `oef.*`
+/// members from an earlier hop and keeping all other members in order.
+/// Returns `None` if the result would exceed the baggage size limits.
+pub(crate) fn append_baggage(
+    existing: Option<&str>,
+    service: &str,
+    template: &str,
+) -> Option<String> {
+    let mut members: Vec<String> = existing
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|member| !member.is_empty())
+        .filter(|member| {
+            let key = member.split(['=', ';']).next().unwrap_or_default().trim();
+            key != "oef.route" && key != "oef.template"
+        })
+        .map(str::to_string)
+        .collect();
+    members.push(format!("oef.route={}", percent_encode(service)));
+    members.push(format!("oef.template={}", percent_encode(template)));
+
+    let baggage = members.join(",");
+    if members.len() > MAX_BAGGAGE_MEMBERS || baggage.len() > MAX_BAGGAGE_BYTES {
+        return None;
+    }
+    Some(baggage)
+}
+
+/// Generates UUID v4 formatted request ids. Not cryptographically random:
+/// ids mix caller-provided entropy (e.g. the host clock) with a counter
+/// shared by all HTTP contexts, so ids stay unique within a VM.
+pub(crate) struct RequestIdGenerator {
+    counter: Cell<u64>,
+}
+
+impl RequestIdGenerator {
+    pub(crate) fn new() -> Self {
+        Self {
+            counter: Cell::new(0),
+        }
+    }
+
+    pub(crate) fn next(&self, entropy: u64) -> String {
+        let counter = self.counter.get();
+        self.counter.set(counter.wrapping_add(1));
+
+        let seed = entropy ^ counter.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let high = splitmix64(seed);
+        let low = splitmix64(high ^ counter);
+        // Version 4 and RFC 4122 variant bits
+        let high = (high & !0xF000) | 0x4000;
+        let low = (low & !(0b11 << 62)) | (0b10 << 62);
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xFFFF,
+            high & 0xFFFF,
+            low >> 48,
+            low & 0xFFFF_FFFF_FFFF
+        )
+    }
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Request attribute hashed into the `x-oef-bucket` value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum BucketSource {
+    Header(String),
+    Cookie(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Bucketing {
+    pub(crate) source: BucketSource,
+    pub(crate) buckets: u64,
+}
+
+impl Bucketing {
+    /// Reads the configured attribute through `get_header`, or `None` if
+    /// the request does not carry it.
+    pub(crate) fn attribute(&self, get_header: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+        match &self.source {
+            BucketSource::Header(name) => get_header(name),
+            BucketSource::Cookie(name) => get_header("cookie")?.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            }),
+        }
+    }
+
+    /// Stable bucket in `0..buckets` for the attribute and matched route.
+    pub(crate) fn bucket(&self, attribute: &str, service: &str, template: &str) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for part in [attribute, service, template] {
+            // The separator keeps ("ab", "c") and ("a", "bc") apart
+            for byte in part.bytes().chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+        hash % self.buckets
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
@@ -1,36 +1,232 @@
 mod config;
+mod headers;
+mod logging;
+mod matcher;
 mod router;
+mod sampler;
 
-use log::{debug, error, info};
-use matchit::Router;
+use log::{debug, error, info, warn};
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
+use std::time::{Duration, UNIX_EPOCH};
 
-use crate::config::{insert_route, parse_methods, parse_servers, strip_port};
-use crate::router::{normalize_path, RouteGroup, RouterSet};
+use crate::config::{
+    expand_normalization_profile, gunzip_config, insert_route, insert_template_route, is_gzip,
+    parse_allowed_schemes, parse_any_method_precedence, parse_base_path_exclusive,
+    parse_base_path_match, parse_bucketing, parse_dynamic_target_headers, parse_exact_overlap,
+    parse_extensions, parse_extra_methods, parse_feature_budget, parse_header_sources,
+    parse_host_list, parse_host_match_order, parse_large_body, parse_log_fields,
+    parse_match_engine, parse_match_precedence, parse_method_aliases, parse_methods,
+    parse_missing_method, parse_operation, parse_param_coercion, parse_param_name_conflict,
+    parse_param_types, parse_path_conflict_policy, parse_positive_integer, parse_prefixes,
+    parse_preserve_trailing_slash, parse_produces, parse_required_header, parse_routing_key,
+    parse_routing_value, parse_security_scheme, parse_servers, parse_service_param, parse_summary,
+    parse_sunset, parse_template_encoding, parse_template_style, parse_tenant, parse_tie_break,
+    parse_timeout_hint, parse_websocket, strip_port, strip_userinfo, unwrap_compressed_config,
+    ExactOverlap, FeatureBudget, FeatureLevel, LogField, ParamNameConflict, ServerSpec,
+};
+use crate::headers::{
+    annotate_template, append_baggage, param_header_name, percent_encode, render_placeholders,
+    route_slug, routing_key, verify_route_token, Bucketing, MissingMethod, PathConflictPolicy,
+    RequestIdGenerator, TemplateEncoding,
+};
+use crate::matcher::MatchEngine;
+use crate::router::{
+    matchit_template, normalize_path_with, segment_count, Match, NormalizeCache, NormalizeOptions,
+    Route, RouteGroup, RouterSet, RoutingKey, TemplateHits, TieBreak, ANY_METHOD, WEBSOCKET_METHOD,
+};
+use crate::sampler::Sampler;
 
 proxy_wasm::main! {{
-    proxy_wasm::set_log_level(LogLevel::Trace);
+    logging::init(log::LevelFilter::Trace);
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
         Box::new(OpenapiEndpointRoot::new())
     });
 }}
 
+const FAIL_CLOSED_STATUS: u32 = 503;
+const PATH_CONFLICT_STATUS: u32 = 400;
+const MISSING_METHOD_STATUS: u32 = 400;
+const UNMATCHED_STATUS: u32 = 404;
+const AUTO_OPTIONS_STATUS: u32 = 204;
+/// Name of the matched template under `emitAsTrailer`, as trailer
+/// consumers expect it; request headers keep `x-path-template`.
+const TEMPLATE_TRAILER: &str = "x-openapi-path";
+/// Plugin configurations larger than this are rejected unless the VM
+/// configuration sets `maxConfigBytes`.
+const DEFAULT_MAX_CONFIG_BYTES: usize = 4 * 1024 * 1024;
+/// How often the cache gauges are recorded with `emitCacheMetrics`.
+const CACHE_METRICS_PERIOD: Duration = Duration::from_secs(10);
+
+/// Gauge ids for the normalize cache fill level.
+struct CacheMetrics {
+    entries: u32,
+    capacity: u32,
+}
+
+/// Response sent for unmatched requests when `rejectUnmatched` is set.
+struct UnmatchedResponse {
+    /// Body template with `{method}`, `{path}` and `{host}` placeholders.
+    body: String,
+    content_type: String,
+}
+
+struct FilterSettings {
+    preserve_existing_headers: bool,
+    lowercase_service_header: bool,
+    debug_headers: bool,
+    emit_baggage: bool,
+    /// Whether matched request bodies get wrapped with their route.
+    prepend_route_to_body: bool,
+    emit_metadata: bool,
+    emit_service_on_response: bool,
+    template_encoding: TemplateEncoding,
+    /// Samples routing decisions for the info-level audit log, if enabled.
+    audit_sampler: Option<Sampler>,
+    /// Fields of the audit log line, in order.
+    log_fields: Vec<LogField>,
+    /// Decides which requests get routing headers when `emitSampleRate` is set.
+    emit_sampler: Option<Sampler>,
+    /// Generates `x-request-id` for requests without one, if enabled.
+    request_ids: Option<RequestIdGenerator>,
+    /// Header count above which optional header lookups are skipped.
+    max_headers: Option<usize>,
+    emit_as_trailer: bool,
+    bucketing: Option<Bucketing>,
+    param_annotated_path: bool,
+    emit_params: bool,
+    /// Upper bound on the `emitParams` headers of a request.
+    max_param_headers: Option<usize>,
+    /// Header carrying the matched template's slug.
+    slug_header: Option<String>,
+    /// Header names a request may pick with `x-oef-target-header`, when
+    /// `allowDynamicTargetHeader` is on.
+    dynamic_target_headers: Option<HashSet<String>>,
+    /// Separator of `x-oef-routing-key`, which is emitted when set.
+    routing_key_separator: Option<String>,
+    /// Request headers the path is read from, in order of preference.
+    path_sources: Vec<String>,
+    /// Request headers the host is read from, in order of preference.
+    host_sources: Vec<String>,
+    /// Host assumed for requests without one, normalized.
+    default_host: Option<String>,
+    path_conflict_policy: PathConflictPolicy,
+    missing_method: MissingMethod,
+    unmatched_response: Option<UnmatchedResponse>,
+    auto_options: bool,
+    emit_path_depth: bool,
+    /// Secret for `x-oef-route-token` signatures; tokens are ignored if unset.
+    route_token_secret: Option<String>,
+    feature_budget: Option<FeatureBudget>,
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        Self {
+            preserve_existing_headers: true,
+            lowercase_service_header: false,
+            debug_headers: false,
+            emit_baggage: false,
+            prepend_route_to_body: false,
+            emit_metadata: false,
+            emit_service_on_response: false,
+            template_encoding: TemplateEncoding::None,
+            audit_sampler: None,
+            log_fields: LogField::DEFAULT.to_vec(),
+            emit_sampler: None,
+            request_ids: None,
+            max_headers: None,
+            emit_as_trailer: false,
+            bucketing: None,
+            param_annotated_path: false,
+            emit_params: false,
+            max_param_headers: None,
+            slug_header: None,
+            dynamic_target_headers: None,
+            routing_key_separator: None,
+            path_sources: vec![":path".to_string()],
+            host_sources: vec![":authority".to_string(), "host".to_string()],
+            default_host: None,
+            path_conflict_policy: PathConflictPolicy::First,
+            missing_method: MissingMethod::Any,
+            unmatched_response: None,
+            auto_options: false,
+            emit_path_depth: false,
+            route_token_secret: None,
+            feature_budget: None,
+        }
+    }
+}
+
+/// Top-level options that shape how services are turned into route groups.
+struct RouteOptions {
+    routing_key: RoutingKey,
+    use_host_in_match: bool,
+    expose_summary: bool,
+    /// Whether routes keep their operation's media type for `x-oef-produces`.
+    emit_produces: bool,
+    /// Whether routes keep their operation's security scheme for
+    /// `x-oef-security-scheme`.
+    emit_security_scheme: bool,
+    extra_methods: HashSet<String>,
+    /// Schemes accepted in `servers.url`; relative URLs are always accepted.
+    allowed_schemes: HashSet<String>,
+    /// Whether a root catch-all that is the only route applying to all
+    /// hosts fails the configuration.
+    reject_global_catch_all: bool,
+    /// Whether such a catch-all is acknowledged, overriding the rejection.
+    allow_global_catch_all: bool,
+    on_exact_overlap: ExactOverlap,
+    on_param_name_conflict: ParamNameConflict,
+    match_engine: MatchEngine,
+    /// Whether templates starting with `//` keep it, like request paths.
+    preserve_leading_double_slash: bool,
+    /// Under `TieBreak::ServiceName`, services are registered in name order.
+    tie_break: TieBreak,
+    /// Whether routes get a slug for `slugHeader`.
+    emit_slug: bool,
+}
+
 struct OpenapiEndpointRoot {
     router_set: Rc<RouterSet>,
-    preserve_existing_headers: bool,
+    shadow_router_set: Option<Rc<RouterSet>>,
+    settings: Rc<FilterSettings>,
     config_error: Option<String>,
+    configured: bool,
+    fail_closed: bool,
+    enabled: bool,
+    /// Plugin configurations larger than this are rejected before parsing.
+    max_config_bytes: usize,
+    /// Tenant from the VM configuration, namespacing this VM's logs and
+    /// metrics.
+    tenant: Option<String>,
+    emit_cache_metrics: bool,
+    /// Defined once `emitCacheMetrics` is first enabled.
+    cache_metrics: Option<CacheMetrics>,
+    /// Hash of the last configuration applied, to skip identical re-pushes.
+    config_hash: Option<u64>,
 }
 
 impl OpenapiEndpointRoot {
     fn new() -> Self {
         Self {
             router_set: Rc::new(RouterSet::new()),
-            preserve_existing_headers: true,
+            shadow_router_set: None,
+            settings: Rc::new(FilterSettings::default()),
             config_error: None,
+            configured: false,
+            fail_closed: false,
+            enabled: true,
+            max_config_bytes: DEFAULT_MAX_CONFIG_BYTES,
+            tenant: None,
+            emit_cache_metrics: false,
+            cache_metrics: None,
+            config_hash: None,
         }
     }
 }
@@ -44,6 +240,9 @@ impl Context for OpenapiEndpointRoot {
 
 impl RootContext for OpenapiEndpointRoot {
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        if let Some(vm_config_bytes) = self.get_vm_configuration() {
+            self.load_vm_configuration(&vm_config_bytes);
+        }
         info!("[oef] openapi-endpoint-filter initialized");
         true
     }
@@ -54,88 +253,588 @@ impl RootContext for OpenapiEndpointRoot {
 
     fn on_configure(&mut self, _: usize) -> bool {
         debug!("[oef] Configuring openapi-endpoint-filter");
-        let config_bytes = match self.get_plugin_configuration() {
-            Some(bytes) => bytes,
+        match self.get_plugin_configuration() {
+            Some(config_bytes) => self.load_configuration(config_bytes),
             None => {
-                error!("[oef] (ERR_NO_CONFIG) No plugin configuration found. Bypassing filter.");
-                self.config_error = Some("ERR_NO_CONFIG".to_string());
-                return true;
+                error!("[oef] (ERR_NO_CONFIG) No plugin configuration found.");
+                self.set_config_error("ERR_NO_CONFIG");
+            }
+        }
+        if self.configured {
+            self.schedule_cache_metrics();
+        }
+        true
+    }
+
+    fn on_tick(&mut self) {
+        self.record_cache_metrics();
+    }
+
+    fn create_http_context(&self, _: u32) -> Option<Box<dyn HttpContext>> {
+        debug!("[oef] Creating HTTP context");
+        Some(Box::new(self.create_filter()))
+    }
+}
+
+impl OpenapiEndpointRoot {
+    fn create_filter(&self) -> OpenapiEndpointFilter {
+        OpenapiEndpointFilter {
+            router_set: Rc::clone(&self.router_set),
+            shadow_router_set: self.shadow_router_set.clone(),
+            settings: Rc::clone(&self.settings),
+            config_error: self.config_error.clone(),
+            fail_closed: self.fail_closed && !self.configured,
+            enabled: self.enabled,
+            response_service: None,
+            response_trailers: Vec::new(),
+            feature_level: FeatureLevel::Full,
+            response_sunset: None,
+            body_route: None,
+        }
+    }
+
+    /// Applies the VM configuration, which holds the limits that must be
+    /// known before the plugin configuration itself can be read.
+    fn load_vm_configuration(&mut self, vm_config_bytes: &[u8]) {
+        let vm_config = match serde_json::from_slice::<Value>(vm_config_bytes) {
+            Ok(vm_config) => vm_config,
+            Err(e) => {
+                warn!("[oef] Ignoring invalid VM configuration: {}", e);
+                return;
             }
         };
+        match parse_positive_integer(&vm_config, "maxConfigBytes") {
+            Ok(Some(max)) => self.max_config_bytes = max as usize,
+            Ok(None) => {}
+            Err(e) => warn!(
+                "[oef] Ignoring invalid maxConfigBytes, keeping {}: {}",
+                self.max_config_bytes, e
+            ),
+        }
+        match parse_tenant(&vm_config) {
+            Ok(Some(tenant)) => {
+                self.tenant = Some(tenant.clone());
+                logging::set_tenant(Some(tenant));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("[oef] Ignoring invalid tenant: {}", e),
+        }
+    }
 
+    fn load_configuration(&mut self, config_bytes: Vec<u8>) {
+        if config_bytes.len() > self.max_config_bytes {
+            error!(
+                "[oef] (ERR_CONFIG_SIZE) Configuration is {} bytes, exceeding maxConfigBytes ({})",
+                config_bytes.len(),
+                self.max_config_bytes
+            );
+            self.set_config_error("ERR_CONFIG_SIZE");
+            return;
+        }
+        let config_bytes = if is_gzip(&config_bytes) {
+            match gunzip_config(&config_bytes, self.max_config_bytes) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    error!("[oef] (ERR_GZIP) Failed to decompress configuration: {}", e);
+                    self.set_config_error("ERR_GZIP");
+                    return;
+                }
+            }
+        } else {
+            config_bytes
+        };
         let config_str = match String::from_utf8(config_bytes) {
             Ok(s) => s,
             Err(e) => {
-                error!(
-                    "[oef] (ERR_UTF8) Failed to convert bytes to UTF-8: {}. Bypassing filter.",
-                    e
-                );
-                self.config_error = Some("ERR_UTF8".to_string());
-                return true;
+                error!("[oef] (ERR_UTF8) Failed to convert bytes to UTF-8: {}", e);
+                self.set_config_error("ERR_UTF8");
+                return;
             }
         };
 
         let config: Value = match serde_json::from_str(&config_str) {
             Ok(v) => v,
             Err(e) => {
-                error!(
-                    "[oef] (ERR_JSON) Failed to parse JSON: {}. Bypassing filter.",
-                    e
-                );
-                self.config_error = Some("ERR_JSON".to_string());
-                return true;
+                error!("[oef] (ERR_JSON) Failed to parse JSON: {}", e);
+                self.set_config_error("ERR_JSON");
+                return;
             }
         };
+        let config = match unwrap_compressed_config(config, self.max_config_bytes) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("[oef] (ERR_GZIP) Failed to unwrap 'configGzipB64': {}", e);
+                self.set_config_error("ERR_GZIP");
+                return;
+            }
+        };
+
+        // Applied before validation on purpose: they decide how an invalid
+        // configuration is handled.
+        self.fail_closed = config
+            .get("failClosed")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        self.enabled = config
+            .get("enabled")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        if !self.enabled {
+            info!("[oef] Filter disabled by configuration, passing all requests through");
+        }
 
         match self.configure(&config) {
             Ok(_) => {
                 info!("[oef] ✅ Configuration successful");
+                self.configured = true;
                 self.config_error = None;
             }
             Err(e) => {
                 error!("[oef] ❌ (ERR_PARSE) Configuration failed: {}", e);
-                error!("[oef] ⚠️  All requests will bypass filter (no metrics collected)");
-                self.config_error = Some("ERR_PARSE".to_string());
+                self.set_config_error("ERR_PARSE");
             }
         }
-        true
     }
 
-    fn create_http_context(&self, _: u32) -> Option<Box<dyn HttpContext>> {
-        debug!("[oef] Creating HTTP context");
-        Some(Box::new(OpenapiEndpointFilter {
-            router_set: Rc::clone(&self.router_set),
-            preserve_existing_headers: self.preserve_existing_headers,
-            config_error: self.config_error.clone(),
-        }))
+    /// Starts or stops recording the cache gauges per `emitCacheMetrics`.
+    fn schedule_cache_metrics(&mut self) {
+        if !self.emit_cache_metrics {
+            // Gauges defined by an earlier configuration are reset to 0
+            self.record_cache_metrics();
+            self.set_tick_period(Duration::ZERO);
+            return;
+        }
+        if self.cache_metrics.is_none() {
+            let define = |name: &str| {
+                let name = metric_name(self.tenant.as_deref(), name);
+                proxy_wasm::hostcalls::define_metric(MetricType::Gauge, &name)
+            };
+            match (define("cache_entries"), define("cache_capacity")) {
+                (Ok(entries), Ok(capacity)) => {
+                    self.cache_metrics = Some(CacheMetrics { entries, capacity })
+                }
+                _ => {
+                    warn!("[oef] Failed to define cache metrics");
+                    return;
+                }
+            }
+        }
+        self.record_cache_metrics();
+        self.set_tick_period(CACHE_METRICS_PERIOD);
+    }
+
+    fn record_cache_metrics(&self) {
+        let Some(metrics) = &self.cache_metrics else {
+            return;
+        };
+        let (entries, capacity) = if self.emit_cache_metrics {
+            self.router_set.cache_fill()
+        } else {
+            (0, 0)
+        };
+        let _ = proxy_wasm::hostcalls::record_metric(metrics.entries, entries as u64);
+        let _ = proxy_wasm::hostcalls::record_metric(metrics.capacity, capacity as u64);
+    }
+
+    fn set_config_error(&mut self, code: &str) {
+        if self.fail_closed {
+            error!(
+                "[oef] ⚠️  All requests will be rejected with {} until a valid configuration loads",
+                FAIL_CLOSED_STATUS
+            );
+        } else {
+            error!("[oef] ⚠️  All requests will bypass filter (no metrics collected)");
+        }
+        self.configured = false;
+        self.config_error = Some(code.to_string());
     }
 }
 
 impl OpenapiEndpointRoot {
     fn configure(&mut self, config: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        // Control planes often re-push the same configuration; rebuilding
+        // would only throw away the normalize cache
+        let config_hash = config_hash(config);
+        if self.config_hash == Some(config_hash) {
+            info!("[oef] Configuration unchanged, keeping the current routes");
+            return Ok(());
+        }
+
         // === Phase 1: Parse and validate (no mutations to self) ===
 
         let preserve_existing_headers = config
             .get("preserveExistingHeaders")
             .and_then(Value::as_bool)
             .unwrap_or(true);
+        let lowercase_service_header = config
+            .get("lowercaseServiceHeader")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let debug_headers = config
+            .get("debugHeaders")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_metadata = config
+            .get("emitMetadata")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_baggage = config
+            .get("emitBaggage")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let prepend_route_to_body = config
+            .get("prependRouteToBody")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_service_on_response = config
+            .get("emitServiceOnResponse")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_as_trailer = config
+            .get("emitAsTrailer")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let template_encoding = parse_template_encoding(config)?;
+        let bucketing = parse_bucketing(config)?;
+        let log_fields = parse_log_fields(config)?;
+        let path_sources = parse_header_sources(config, "pathSource", &[":path"])?;
+        let host_sources = parse_header_sources(config, "hostSource", &[":authority", "host"])?;
+        let default_host = match config.get("defaultHost") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .as_str()
+                    .and_then(OpenapiEndpointFilter::normalize_host)
+                    .ok_or("'defaultHost' must be a non-empty host name")?,
+            ),
+        };
+        let path_conflict_policy = parse_path_conflict_policy(config)?;
+        let missing_method = parse_missing_method(config)?;
+        let route_token_secret = match config.get("routeTokenSecret") {
+            None => None,
+            Some(secret) => Some(
+                secret
+                    .as_str()
+                    .filter(|secret| !secret.is_empty())
+                    .ok_or("'routeTokenSecret' must be a non-empty string")?
+                    .to_string(),
+            ),
+        };
+        let feature_budget = parse_feature_budget(config)?;
+        let emit_path_depth = config
+            .get("emitPathDepth")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let auto_options = config
+            .get("autoOptions")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let reject_unmatched = config
+            .get("rejectUnmatched")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let string_option = |key: &str, default: &str| match config.get(key) {
+            None => Ok(default.to_string()),
+            Some(value) => value
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("'{}' must be a string", key)),
+        };
+        let unmatched_body = string_option("unmatchedBody", "")?;
+        let unmatched_content_type = string_option("unmatchedContentType", "text/plain")?;
+        let param_annotated_path = config
+            .get("paramAnnotatedPath")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let ensure_request_id = config
+            .get("ensureRequestId")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
         let use_host_in_match = config
             .get("useHostInMatch")
             .and_then(Value::as_bool)
             .unwrap_or(true);
+        let match_base_path_root = config
+            .get("matchBasePathRoot")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        let base_path_match = parse_base_path_match(config)?;
+        let expose_summary = config
+            .get("exposeSummary")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_produces = config
+            .get("emitProduces")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let emit_security_scheme = config
+            .get("emitSecurityScheme")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let max_path_segments = parse_positive_integer(config, "maxPathSegments")?;
+        let min_specificity = parse_positive_integer(config, "minSpecificity")?;
+        let prefer_longest_template = config
+            .get("preferLongestTemplate")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let normalization = expand_normalization_profile(config)?;
+        let decode_plus_in_path = normalization
+            .get("decodePlusInPath")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let strip_fragment = normalization
+            .get("stripFragment")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+        let resolve_dot_segments = normalization
+            .get("resolveDotSegments")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let uppercase_percent_encoding = normalization
+            .get("uppercasePercentEncoding")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let trim_path = normalization
+            .get("trimPath")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let reject_whitespace = normalization
+            .get("rejectWhitespacePath")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let preserve_leading_double_slash = normalization
+            .get("preserveLeadingDoubleSlash")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let miss_log_sample_rate = parse_positive_integer(config, "missLogSampleRate")?;
+        let normalize_cache_size = parse_positive_integer(config, "normalizeCacheSize")?;
+        let template_stats = config
+            .get("templateStats")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let max_headers = parse_positive_integer(config, "maxHeaders")?;
+        let audit_sample_rate = parse_positive_integer(config, "auditSampleRate")?;
+        let emit_sample_rate = parse_positive_integer(config, "emitSampleRate")?;
+        let match_precedence = parse_match_precedence(config)?;
+        let host_match_order = parse_host_match_order(config)?;
+        let any_method_precedence = parse_any_method_precedence(config)?;
+        let tie_break = parse_tie_break(config)?;
+        let param_coercion = parse_param_coercion(config)?;
+        let emit_params = config
+            .get("emitParams")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let slug_header = match config.get("slugHeader") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .as_str()
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .ok_or("'slugHeader' must be a non-empty header name")?
+                    .to_ascii_lowercase(),
+            ),
+        };
+        let dynamic_target_headers = parse_dynamic_target_headers(config)?;
+        let trusted_hosts = parse_host_list(config, "trustedHosts")?;
+        let denied_hosts = parse_host_list(config, "deniedHosts")?.unwrap_or_default();
+        let routing_key = parse_routing_key(config)?;
+        let extra_methods = parse_extra_methods(config)?;
+        let allowed_schemes = parse_allowed_schemes(config)?;
+        let reject_global_catch_all = config
+            .get("rejectGlobalCatchAll")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let allow_global_catch_all = config
+            .get("allowGlobalCatchAll")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let on_exact_overlap = parse_exact_overlap(config)?;
+        let on_param_name_conflict = parse_param_name_conflict(config)?;
+        let match_engine = parse_match_engine(config)?;
+        let max_param_headers = parse_positive_integer(config, "maxParamHeaders")?;
+        let emit_routing_key = config
+            .get("emitRoutingKey")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let routing_key_separator = match config.get("routingKeySeparator") {
+            None => "|",
+            Some(value) => value
+                .as_str()
+                .filter(|separator| !separator.is_empty())
+                .ok_or("'routingKeySeparator' must be a non-empty string")?,
+        };
+        let root_service = match config.get("rootService") {
+            None => None,
+            Some(value) => Some(Rc::new(
+                value
+                    .as_str()
+                    .filter(|service| !service.is_empty())
+                    .ok_or("'rootService' must be a non-empty service name")?
+                    .to_string(),
+            )),
+        };
+        let emit_cache_metrics = config
+            .get("emitCacheMetrics")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
 
         let services = config
             .get("services")
             .and_then(Value::as_array)
             .ok_or("Invalid or missing 'services' in configuration")?;
 
+        let allow_empty_services = config
+            .get("allowEmptyServices")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
         if services.is_empty() {
-            return Err("Services array cannot be empty".into());
+            if !allow_empty_services {
+                return Err("Services array cannot be empty".into());
+            }
+            warn!("[oef] 'services' is empty, every request will be unmatched");
         }
 
         // === Phase 2: Build new router (may fail, but self is untouched) ===
 
+        let normalize_options = NormalizeOptions {
+            max_segments: max_path_segments.map(|max| max as usize),
+            decode_plus: decode_plus_in_path,
+            strip_fragment,
+            resolve_dot_segments,
+            uppercase_percent_encoding,
+            preserve_leading_double_slash,
+            trim_path,
+            reject_whitespace,
+        };
+        let route_options = RouteOptions {
+            routing_key,
+            use_host_in_match,
+            expose_summary,
+            emit_produces,
+            emit_security_scheme,
+            extra_methods,
+            allowed_schemes,
+            reject_global_catch_all,
+            allow_global_catch_all,
+            on_exact_overlap,
+            on_param_name_conflict,
+            match_engine,
+            preserve_leading_double_slash,
+            tie_break,
+            emit_slug: slug_header.is_some(),
+        };
+        let by_key = Self::build_route_groups(services, &route_options)?;
+        let shadow_by_key = match config.get("shadowServices") {
+            None => None,
+            Some(shadow_services) => {
+                let shadow_services = shadow_services
+                    .as_array()
+                    .filter(|shadow_services| !shadow_services.is_empty())
+                    .ok_or("'shadowServices' must be a non-empty array")?;
+                Some(Self::build_route_groups(shadow_services, &route_options)?)
+            }
+        };
+
+        // === Phase 3: Apply all changes atomically ===
+        // All validations passed, now we can safely update self
+
+        let new_router_set = |by_key| RouterSet {
+            by_key,
+            routing_key: route_options.routing_key.clone(),
+            match_base_path_root,
+            base_path_match,
+            normalize_options: normalize_options.clone(),
+            normalize_cache: normalize_cache_size.map(|size| NormalizeCache::new(size as usize)),
+            template_hits: template_stats.then(TemplateHits::default),
+            miss_log_sampler: Sampler::new(miss_log_sample_rate.unwrap_or(1)),
+            match_precedence,
+            host_match_order,
+            any_method_precedence,
+            param_coercion,
+            trusted_hosts: trusted_hosts.clone(),
+            denied_hosts: denied_hosts.clone(),
+            min_specificity: min_specificity.map(|min| min as usize),
+            prefer_longest_template,
+            tie_break,
+            root_route: root_service.as_ref().map(|service_name| Route {
+                template: "/".to_string(),
+                service_name: Rc::clone(service_name),
+                ..Default::default()
+            }),
+        };
+        self.shadow_router_set = shadow_by_key.map(|by_key| Rc::new(new_router_set(by_key)));
+        self.router_set = Rc::new(new_router_set(by_key));
+        self.emit_cache_metrics = emit_cache_metrics;
+        self.settings = Rc::new(FilterSettings {
+            preserve_existing_headers,
+            lowercase_service_header,
+            debug_headers,
+            emit_baggage,
+            prepend_route_to_body,
+            emit_metadata,
+            emit_service_on_response,
+            template_encoding,
+            audit_sampler: audit_sample_rate.map(Sampler::new),
+            log_fields,
+            emit_sampler: emit_sample_rate.map(Sampler::new),
+            request_ids: ensure_request_id.then(RequestIdGenerator::new),
+            max_headers: max_headers.map(|max| max as usize),
+            emit_as_trailer,
+            bucketing,
+            param_annotated_path,
+            emit_params,
+            max_param_headers: max_param_headers.map(|max| max as usize),
+            slug_header,
+            dynamic_target_headers,
+            routing_key_separator: emit_routing_key.then(|| routing_key_separator.to_string()),
+            path_sources,
+            host_sources,
+            default_host,
+            path_conflict_policy,
+            missing_method,
+            unmatched_response: reject_unmatched.then_some(UnmatchedResponse {
+                body: unmatched_body,
+                content_type: unmatched_content_type,
+            }),
+            auto_options,
+            emit_path_depth,
+            route_token_secret,
+            feature_budget,
+        });
+        self.config_hash = Some(config_hash);
+
+        info!(
+            "[oef] ✅ Router configured successfully with {} services",
+            services.len()
+        );
+        Ok(())
+    }
+
+    /// Builds the route groups for `services`, keyed by routing key value and
+    /// ordered longest base path first.
+    fn build_route_groups(
+        services: &[Value],
+        options: &RouteOptions,
+    ) -> Result<HashMap<Option<String>, Vec<RouteGroup>>, Box<dyn std::error::Error>> {
         let mut groups: HashMap<(Option<String>, String), RouteGroup> = HashMap::new();
+        // Root catch-alls of the group that applies to every request, and
+        // whether that group has any more specific route
+        let mut global_catch_all = None;
+        let mut global_siblings = false;
+        // Slugs handed out so far, with the template each came from
+        let mut slugs: HashMap<String, String> = HashMap::new();
+        // Whatever resolves by declaration order (overlapping templates,
+        // slug collisions) then resolves by name instead
+        let mut services: Vec<&Value> = services.iter().collect();
+        if options.tie_break == TieBreak::ServiceName {
+            services.sort_by_key(|service| {
+                service
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            });
+        }
         for service in services {
             let service_name = service
                 .get("name")
@@ -146,15 +845,29 @@ impl OpenapiEndpointRoot {
                 return Err("Service name cannot be empty".into());
             }
 
-            let server_specs = parse_servers(service)?;
+            let server_specs = parse_servers(service, &options.allowed_schemes)?;
             let service_name = Rc::new(service_name.to_string());
 
-            let paths = service
-                .get("paths")
-                .and_then(Value::as_object)
-                .ok_or("Invalid or missing 'paths' in service configuration")?;
+            let service_param = parse_service_param(service)?;
+            let template_style = parse_template_style(service)?;
+            let base_path_exclusive = parse_base_path_exclusive(service)?;
+            let prefixes = parse_prefixes(service)?;
+            let routing_value = parse_routing_value(service)?;
+            let group_key = |server: &ServerSpec| match options.routing_key {
+                RoutingKey::Host if options.use_host_in_match => server.host.clone(),
+                RoutingKey::Host => None,
+                RoutingKey::Header(_) => routing_value.clone(),
+            };
+            let no_paths = serde_json::Map::new();
+            let paths = match service.get("paths") {
+                // A prefix-only service does not need to enumerate paths
+                None if !prefixes.is_empty() => &no_paths,
+                paths => paths
+                    .and_then(Value::as_object)
+                    .ok_or("Invalid or missing 'paths' in service configuration")?,
+            };
 
-            if paths.is_empty() {
+            if paths.is_empty() && prefixes.is_empty() {
                 return Err(format!("Service '{}' has no paths", service_name).into());
             }
 
@@ -176,74 +889,335 @@ impl OpenapiEndpointRoot {
                     return Err(format!("Path contains newline character: {}", path).into());
                 }
 
-                let methods = parse_methods(path, path_config)?;
-                let normalized_path = normalize_path(path);
+                let methods = parse_methods(path, path_config, &options.extra_methods)?;
+                let required_header = parse_required_header(path, path_config)?;
+                let websocket = parse_websocket(path, path_config)?;
+                let timeout_ms = parse_timeout_hint(path, path_config)?;
+                let sunset = parse_sunset(path, path_config)?;
+                let extensions = parse_extensions(path, path_config)?;
+                let large_body = parse_large_body(path, path_config)?;
+                let method_aliases =
+                    parse_method_aliases(path, path_config, &methods, &options.extra_methods)?;
+                let param_types = parse_param_types(path_config);
+                let preserve_trailing_slash = parse_preserve_trailing_slash(path, path_config)?
+                    && path.len() > 1
+                    && path.ends_with('/');
+                let normalized_path = normalize_path_with(
+                    path,
+                    &NormalizeOptions {
+                        preserve_leading_double_slash: options.preserve_leading_double_slash,
+                        ..Default::default()
+                    },
+                )
+                .unwrap_or_default();
+                let normalized_path = if preserve_trailing_slash {
+                    format!("{}/", normalized_path)
+                } else {
+                    normalized_path
+                };
+                let emitted_template = template_style.render(&normalized_path);
+                let (matchit_path, param_aliases) = matchit_template(&normalized_path);
+                let slug = options.emit_slug.then(|| route_slug(&normalized_path));
+                if let Some(slug) = &slug {
+                    match slugs.get(slug) {
+                        Some(template) if *template != normalized_path => warn!(
+                            "[oef] Slug '{}' of '{}' collides with '{}'",
+                            slug, normalized_path, template
+                        ),
+                        Some(_) => {}
+                        None => {
+                            slugs.insert(slug.clone(), normalized_path.clone());
+                        }
+                    }
+                }
 
                 for server in &server_specs {
-                    let host_key = if use_host_in_match {
-                        server.host.clone()
-                    } else {
-                        None
+                    if group_key(server).is_none() && server.base_path.is_empty() {
+                        if is_root_catch_all(&normalized_path) {
+                            global_catch_all = Some(Rc::clone(&service_name));
+                        } else {
+                            global_siblings = true;
+                        }
+                    }
+                    let group = Self::route_group(
+                        &mut groups,
+                        group_key(server),
+                        server,
+                        options.match_engine,
+                    );
+                    // A template taken over under `onExactOverlap` is listed once,
+                    // for the service that ends up with it
+                    if !group.templates.contains(&normalized_path) {
+                        group.templates.push(normalized_path.clone());
+                    }
+
+                    let new_route = |operation: Option<&Value>| Route {
+                        template: normalized_path.clone(),
+                        service_name: Rc::clone(&service_name),
+                        required_header: required_header.clone(),
+                        summary: parse_summary(operation.filter(|_| options.expose_summary)),
+                        produces: parse_produces(operation.filter(|_| options.emit_produces)),
+                        security_scheme: parse_security_scheme(
+                            operation.filter(|_| options.emit_security_scheme),
+                            service,
+                        ),
+                        service_param: service_param.clone(),
+                        timeout_ms,
+                        emitted_template: emitted_template.clone(),
+                        preserve_trailing_slash,
+                        depth: segment_count(&normalized_path),
+                        sunset: sunset.clone(),
+                        param_aliases: param_aliases.clone(),
+                        extensions: extensions.clone(),
+                        large_body: large_body.clone(),
+                        param_types: param_types.clone(),
+                        slug: slug.clone(),
+                        merged_param_names: Vec::new(),
+                        canonical_method: None,
+                        group_key: group_key(server),
                     };
-                    let key = (host_key, server.base_path.clone());
-                    let group = groups
-                        .entry(key)
-                        .or_insert_with(|| RouteGroup::new(server.base_path.clone()));
 
                     if methods.is_empty() {
-                        insert_route(
-                            &mut group.any_method,
-                            &normalized_path,
-                            Rc::clone(&service_name),
+                        insert_template_route(
+                            group.any_method.as_mut(),
+                            &matchit_path,
+                            new_route(None),
+                            options.on_exact_overlap,
+                            options.on_param_name_conflict,
                         )?;
                     } else {
                         for method in &methods {
+                            let router = if method == ANY_METHOD {
+                                &mut group.any_method
+                            } else {
+                                group
+                                    .methods
+                                    .entry(method.clone())
+                                    .or_insert_with(|| options.match_engine.matcher())
+                            };
+                            insert_template_route(
+                                router.as_mut(),
+                                &matchit_path,
+                                new_route(parse_operation(path_config, method)),
+                                options.on_exact_overlap,
+                                options.on_param_name_conflict,
+                            )?;
+                        }
+                        for (alias, method) in &method_aliases {
                             let router = group
                                 .methods
-                                .entry(method.clone())
-                                .or_insert_with(Router::new);
-                            insert_route(router, &normalized_path, Rc::clone(&service_name))?;
+                                .entry(alias.clone())
+                                .or_insert_with(|| options.match_engine.matcher());
+                            let mut route = new_route(parse_operation(path_config, method));
+                            route.canonical_method = Some(method.clone());
+                            insert_template_route(
+                                router.as_mut(),
+                                &matchit_path,
+                                route,
+                                options.on_exact_overlap,
+                                options.on_param_name_conflict,
+                            )?;
+                        }
+                    }
+                    if websocket {
+                        let router = group
+                            .methods
+                            .entry(WEBSOCKET_METHOD.to_string())
+                            .or_insert_with(|| options.match_engine.matcher());
+                        insert_template_route(
+                            router.as_mut(),
+                            &matchit_path,
+                            new_route(None),
+                            options.on_exact_overlap,
+                            options.on_param_name_conflict,
+                        )?;
+                    }
+                }
+            }
+
+            for prefix in &prefixes {
+                for server in &server_specs {
+                    if group_key(server).is_none() && server.base_path.is_empty() {
+                        if is_root_catch_all(&prefix.route_path) || prefix.route_path == "/" {
+                            global_catch_all = Some(Rc::clone(&service_name));
+                        } else {
+                            global_siblings = true;
                         }
                     }
+                    let group = Self::route_group(
+                        &mut groups,
+                        group_key(server),
+                        server,
+                        options.match_engine,
+                    );
+                    let route = Route {
+                        template: prefix.template.clone(),
+                        service_name: Rc::clone(&service_name),
+                        service_param: service_param.clone(),
+                        emitted_template: template_style.render(&prefix.template),
+                        depth: segment_count(&prefix.template),
+                        group_key: group_key(server),
+                        ..Default::default()
+                    };
+                    insert_route(group.prefixes.as_mut(), &prefix.route_path, route)?;
+                }
+            }
+
+            if base_path_exclusive {
+                for server in &server_specs {
+                    let group = Self::route_group(
+                        &mut groups,
+                        group_key(server),
+                        server,
+                        options.match_engine,
+                    );
+                    if let Some(owner) = &group.exclusive {
+                        return Err(format!(
+                            "Base path '{}' is already exclusive to service '{}'",
+                            group.base_path, owner.service_name
+                        )
+                        .into());
+                    }
+                    group.exclusive = Some(Route {
+                        template: "/*".to_string(),
+                        service_name: Rc::clone(&service_name),
+                        emitted_template: template_style.render("/*"),
+                        depth: segment_count("/*"),
+                        group_key: group_key(server),
+                        ..Default::default()
+                    });
                 }
             }
         }
 
-        // === Phase 3: Apply all changes atomically ===
-        // All validations passed, now we can safely update self
+        if let Some(service_name) = global_catch_all {
+            if !global_siblings
+                && options.reject_global_catch_all
+                && !options.allow_global_catch_all
+            {
+                return Err(format!(
+                    "Service '{}' has a root catch-all that would match every request; \
+                     add more specific routes or set 'allowGlobalCatchAll: true'",
+                    service_name
+                )
+                .into());
+            }
+        }
 
-        let mut by_host: HashMap<Option<String>, Vec<RouteGroup>> = HashMap::new();
-        for ((host, _base_path), group) in groups {
-            by_host.entry(host).or_default().push(group);
+        let mut by_key: HashMap<Option<String>, Vec<RouteGroup>> = HashMap::new();
+        for ((key, _base_path), group) in groups {
+            by_key.entry(key).or_default().push(group);
         }
-        for groups in by_host.values_mut() {
+        for groups in by_key.values_mut() {
             groups.sort_by_key(|group| std::cmp::Reverse(group.base_path.len()));
         }
+        Ok(by_key)
+    }
 
-        self.router_set = Rc::new(RouterSet {
-            by_host,
-        });
-        self.preserve_existing_headers = preserve_existing_headers;
+    fn route_group<'a>(
+        groups: &'a mut HashMap<(Option<String>, String), RouteGroup>,
+        key: Option<String>,
+        server: &ServerSpec,
+        match_engine: MatchEngine,
+    ) -> &'a mut RouteGroup {
+        groups
+            .entry((key, server.base_path.clone()))
+            .or_insert_with(|| RouteGroup::new(server.base_path.clone(), match_engine))
+    }
+}
 
-        info!(
-            "[oef] ✅ Router configured successfully with {} services",
-            services.len()
-        );
-        Ok(())
+/// Hash of a plugin configuration. Object keys are serialized in sorted
+/// order, so key order does not change it.
+fn config_hash(config: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    config.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Name of a metric, namespaced by the VM's tenant if it has one.
+fn metric_name(tenant: Option<&str>, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("oef_{}_{}", tenant, name),
+        None => format!("oef_{}", name),
     }
 }
 
+/// Whether `route_path` is a catch-all directly under the root, such as
+/// `/{*any}`, which matches every path.
+fn is_root_catch_all(route_path: &str) -> bool {
+    route_path.starts_with("/{*") && segment_count(route_path) == 1
+}
+
+/// Wraps a JSON request body as `{"route":{"service":..,"template":..},"body":..}`
+/// for `prependRouteToBody`. An empty body becomes `null`; any other body
+/// that is not JSON is left alone and `None` is returned.
+fn wrap_body_with_route(service: &str, template: &str, body: &[u8]) -> Option<Vec<u8>> {
+    let body: &[u8] = if body.iter().all(u8::is_ascii_whitespace) {
+        b"null"
+    } else {
+        serde_json::from_slice::<Value>(body).ok()?;
+        body
+    };
+    let route = serde_json::json!({ "service": service, "template": template });
+    let mut wrapped = format!(r#"{{"route":{},"body":"#, route).into_bytes();
+    wrapped.extend_from_slice(body);
+    wrapped.push(b'}');
+    Some(wrapped)
+}
+
 struct OpenapiEndpointFilter {
     router_set: Rc<RouterSet>,
-    preserve_existing_headers: bool,
+    /// Routes from `shadowServices`, matched only to log divergences.
+    shadow_router_set: Option<Rc<RouterSet>>,
+    settings: Rc<FilterSettings>,
     config_error: Option<String>,
+    fail_closed: bool,
+    /// When false the filter passes requests through untouched.
+    enabled: bool,
+    /// Service header value kept from the request phase for the response.
+    response_service: Option<String>,
+    /// Routing headers deferred to the response trailers.
+    response_trailers: Vec<(String, String)>,
+    /// Optional work allowed for the current request under `featureBudget`.
+    feature_level: FeatureLevel,
+    /// `Sunset` value of the matched route, set on the response.
+    response_sunset: Option<String>,
+    /// Route to wrap the request body with under `prependRouteToBody`.
+    body_route: Option<(String, String)>,
 }
 
 impl Context for OpenapiEndpointFilter {}
 
 impl HttpContext for OpenapiEndpointFilter {
-    fn on_http_request_headers(&mut self, _nheaders: usize, _end_of_stream: bool) -> Action {
+    fn on_http_request_headers(&mut self, nheaders: usize, end_of_stream: bool) -> Action {
+        if !self.enabled {
+            return Action::Continue;
+        }
+
+        if let Some(request_ids) = &self.settings.request_ids {
+            if self.get_http_request_header("x-request-id").is_none() {
+                let entropy = self
+                    .get_current_time()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_nanos() as u64)
+                    .unwrap_or_default();
+                let request_id = request_ids.next(entropy);
+                debug!("[oef] Generated x-request-id: {}", request_id);
+                self.set_http_request_header("x-request-id", Some(&request_id));
+            }
+        }
+
         if let Some(code) = &self.config_error {
+            if self.fail_closed {
+                debug!("[oef] ({}) Rejecting due to config error", code);
+                self.send_http_response(
+                    FAIL_CLOSED_STATUS,
+                    vec![("x-oef-config-error", code.as_str())],
+                    None,
+                );
+                return Action::Pause;
+            }
+
             debug!("[oef] ({}) Bypassing due to config error", code);
 
             // Set metric headers for monitoring config errors
@@ -255,751 +1229,7016 @@ impl HttpContext for OpenapiEndpointFilter {
         }
 
         debug!("[oef] Getting the path from header");
-        let path = self.get_http_request_header(":path").unwrap_or_default();
-        let method_header = self
-            .get_http_request_header(":method")
-            .unwrap_or("unknown".to_string());
+        let path = match self.resolve_path(&|name| self.get_http_request_header(name)) {
+            Some(path) => path,
+            None => {
+                self.send_http_response(
+                    PATH_CONFLICT_STATUS,
+                    vec![("x-oef-path-conflict", "true")],
+                    None,
+                );
+                return Action::Pause;
+            }
+        };
+        let method_header = match self.resolve_method(self.get_http_request_header(":method")) {
+            Some(method_header) => method_header,
+            None => {
+                self.send_http_response(
+                    MISSING_METHOD_STATUS,
+                    vec![("x-oef-missing-method", "true")],
+                    None,
+                );
+                return Action::Pause;
+            }
+        };
         let method = method_header.to_ascii_lowercase();
-        let host_header = self
-            .get_http_request_header(":authority")
-            .or_else(|| self.get_http_request_header("host"));
-        let host = host_header
-            .as_deref()
-            .and_then(OpenapiEndpointFilter::normalize_host);
+        let host = self.resolve_host(&|name| self.get_http_request_header(name));
 
-        let (path_template, service_name) = self
-            .get_path_template(host.as_deref(), &method, &path)
-            .map(|(matched_path, service_name)| (matched_path, service_name))
-            .unwrap_or(("unknown".to_string(), Rc::new("unknown".to_string())));
+        self.feature_level = self.budget_level(nheaders, path.len());
+        let degraded = self.exceeds_max_headers(nheaders);
+        // Routing always reads the headers it needs; over maxHeaders only the
+        // optional features below go without
+        let request_header = |name: &str| self.get_http_request_header(name);
+        let get_header = |name: &str| {
+            if degraded {
+                None
+            } else {
+                request_header(name)
+            }
+        };
 
-        if !self.preserve_existing_headers
-            || self.get_http_request_header("x-service-name").is_none()
-        {
-            self.set_http_request_header("x-service-name", Some(&service_name));
+        // Held locally so the match can outlive mutable borrows of self below
+        let router_set = Rc::clone(&self.router_set);
+        // Denied hosts get no route, neither matched nor from a route token
+        let denied = router_set.is_denied_host(host.as_deref());
+        let token_route = if denied {
+            debug!("[oef] Host {:?} is denied, treating as no match", host);
+            None
+        } else {
+            self.route_from_token(host.as_deref(), &get_header)
+        };
+        if self.settings.route_token_secret.is_some() {
+            // The token is for this filter, not the upstream
+            self.set_http_request_header("x-oef-route-token", None);
         }
-        if !self.preserve_existing_headers
-            || self.get_http_request_header("x-path-template").is_none()
+        let route = match &token_route {
+            Some(token_route) => Some(Match::whole(token_route, "")),
+            None if denied => None,
+            None => router_set.match_route(host.as_deref(), &method, &path, &request_header),
+        };
+
+        if let Some(line) =
+            self.shadow_divergence(host.as_deref(), &method, &path, &get_header, route.as_ref())
         {
-            self.set_http_request_header("x-path-template", Some(&path_template));
+            info!("{}", line);
         }
-        if !self.preserve_existing_headers
-            || self.get_http_request_header("x-api-endpoint").is_none()
-        {
-            self.set_http_request_header(
-                "x-api-endpoint",
-                Some(&if method == "unknown" && path_template == "unknown" {
-                    "unknown".to_string()
-                } else {
-                    format!("{} {}", method_header, path_template)
-                }),
-            );
+
+        if let Some(line) = self.audit_line(host.as_deref(), &method, &path, route.as_ref()) {
+            info!("{}", line);
         }
 
-        Action::Continue
-    }
-}
+        let allow = (!denied)
+            .then(|| {
+                self.auto_options_allow(
+                    host.as_deref(),
+                    &method,
+                    &path,
+                    &request_header,
+                    route.as_ref(),
+                )
+            })
+            .flatten();
+        if let Some(allow) = allow {
+            debug!("[oef] Answering OPTIONS {} with Allow: {}", path, allow);
+            self.send_http_response(AUTO_OPTIONS_STATUS, vec![("allow", allow.as_str())], None);
+            return Action::Pause;
+        }
 
-impl OpenapiEndpointFilter {
-    fn get_path_template(
-        &self,
-        host: Option<&str>,
-        method: &str,
-        path: &str,
-    ) -> Option<(String, Rc<String>)> {
-        self.router_set.match_route(host, method, path)
-    }
+        if route.is_none() {
+            if let Some(unmatched) = &self.settings.unmatched_response {
+                debug!("[oef] Rejecting unmatched request");
+                let body = self.unmatched_body(&method_header, host.as_deref(), &path);
+                self.send_http_response(
+                    UNMATCHED_STATUS,
+                    vec![("content-type", unmatched.content_type.as_str())],
+                    body.as_deref().map(str::as_bytes),
+                );
+                return Action::Pause;
+            }
+        }
 
-    fn normalize_host(host: &str) -> Option<String> {
-        let trimmed = host.trim();
-        if trimmed.is_empty() {
-            return None;
+        let mut headers = self.route_headers(&method_header, route.as_ref());
+        headers.extend(self.bucket_header(route.as_ref(), &get_header));
+        headers.extend(self.dynamic_target_header(&headers, route.is_some(), &get_header));
+        if route.is_none() && !denied {
+            headers.extend(self.miss_headers(host.as_deref(), &path, &get_header));
         }
-        let lower = trimmed.to_ascii_lowercase();
-        let host = strip_port(&lower);
-        if host.is_empty() {
-            None
-        } else {
-            Some(host.to_string())
+        let headers = self.sample_emission(headers);
+        self.retain_for_response(route.is_some(), &headers);
+        self.response_sunset = route
+            .as_ref()
+            .and_then(|matched| matched.route.sunset.clone());
+        let headers = self.defer_to_trailers(headers);
+        for (name, value) in headers {
+            if !self.settings.preserve_existing_headers
+                || self.get_http_request_header(&name).is_none()
+            {
+                self.set_http_request_header(&name, Some(&value));
+            }
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::router::normalize_path;
-    use serde_json::json;
+        for (path, value) in self.metadata_properties(route.as_ref()) {
+            self.set_property(path, Some(value.as_bytes()));
+        }
 
-    const TEST_CONFIG: &str = r#"{
-        "cacheSize": 5,
-        "services": [
-            {
-                "name": "dockebi",
-                "paths": {
-                    "/dockebi/v1/stuff": {},
-                    "/dockebi/v1/stuff/{id_}": {},
-                    "/dockebi/v1/stuff/{id_}/child/{child_id}/hello": {}
-                }
-            },
-            {
-                "name": "userservice",
-                "paths": {
-                    "/users": {},
-                    "/users/{id}": {},
-                    "/users/{id}/profile": {}
+        if self.settings.emit_baggage && !degraded && self.feature_level < FeatureLevel::Minimal {
+            if let Some(matched) = &route {
+                let existing = self.get_http_request_header("baggage");
+                match append_baggage(existing.as_deref(), matched.service, matched.template) {
+                    Some(baggage) => self.set_http_request_header("baggage", Some(&baggage)),
+                    None => debug!("[oef] Baggage size limit reached, not appending route"),
                 }
-            },
-            {
-                "name": "productservice",
-                "paths": {
-                    "/products": {},
-                    "/products/{product_id}": {},
-                    "/categories/{category_id}/products": {}
+            }
+        }
+
+        // Only JSON bodies are wrapped, and only requests that have one
+        let json_body = !end_of_stream
+            && self
+                .get_http_request_header("content-type")
+                .is_some_and(|content_type| content_type.to_ascii_lowercase().contains("json"));
+        self.body_route = self.body_route(route.as_ref()).filter(|_| json_body);
+        if self.body_route.is_some() {
+            // Held until the body is wrapped, so content-length can still be
+            // set to the wrapped size
+            return Action::Pause;
+        }
+
+        Action::Continue
+    }
+
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if self.body_route.is_none() {
+            return Action::Continue;
+        }
+        // Buffer every chunk until the whole body can be wrapped at once
+        if !end_of_stream {
+            return Action::Pause;
+        }
+        let Some((service, template)) = self.body_route.take() else {
+            return Action::Continue;
+        };
+        let body = self.get_http_request_body(0, body_size).unwrap_or_default();
+        match wrap_body_with_route(&service, &template, &body) {
+            Some(wrapped) => {
+                self.set_http_request_body(0, body_size, &wrapped);
+                if self.get_http_request_header("content-length").is_some() {
+                    let length = wrapped.len().to_string();
+                    self.set_http_request_header("content-length", Some(&length));
                 }
             }
-        ]
-    }"#;
+            None => debug!("[oef] Request body is not JSON, not prepending route"),
+        }
+        Action::Continue
+    }
 
-    #[test]
-    fn test_basic_path_and_service_matching() {
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx
-            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
-            .unwrap();
+    fn on_http_response_headers(&mut self, _nheaders: usize, end_of_stream: bool) -> Action {
+        if !self.enabled {
+            return Action::Continue;
+        }
+        let mut headers = self.response_headers();
+        // Only gRPC responses are sure to end with trailers
+        if !self.response_trailers.is_empty() && (end_of_stream || !self.expects_trailers()) {
+            debug!("[oef] Response has no trailers, emitting routing trailers as headers");
+            headers.append(&mut self.response_trailers);
+        }
+        for (name, value) in headers {
+            self.set_http_response_header(&name, Some(&value));
+        }
+        Action::Continue
+    }
 
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+    fn on_http_response_body(&mut self, _body_size: usize, end_of_stream: bool) -> Action {
+        if end_of_stream && !self.response_trailers.is_empty() {
+            // A gRPC response without trailers; too late for headers
+            debug!("[oef] Response ended without trailers, routing trailers not emitted");
+            self.response_trailers.clear();
+        }
+        Action::Continue
+    }
+
+    fn on_http_response_trailers(&mut self, _ntrailers: usize) -> Action {
+        for (name, value) in std::mem::take(&mut self.response_trailers) {
+            self.set_http_response_trailer(&name, Some(&value));
+        }
+        Action::Continue
+    }
+}
+
+impl OpenapiEndpointFilter {
+    #[cfg(test)]
+    fn get_path_template(
+        &self,
+        host: Option<&str>,
+        method: &str,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Match<'_>> {
+        self.router_set.match_route(host, method, path, get_header)
+    }
+
+    fn route_headers(&self, method_header: &str, matched: Option<&Match>) -> Vec<(String, String)> {
+        let route = matched.map(|matched| matched.route);
+        let (path_template, service_name) = matched
+            .map(|matched| {
+                let template = matched.route.emitted_template.as_deref();
+                (
+                    template.unwrap_or(matched.template),
+                    matched.service.as_str(),
+                )
+            })
+            .unwrap_or(("unknown", "unknown"));
+        // A request matched through an alias is reported as the declared method
+        let canonical_method = route
+            .and_then(|route| route.canonical_method.as_deref())
+            .map(str::to_ascii_uppercase);
+        let method_header = canonical_method.as_deref().unwrap_or(method_header);
+        let api_endpoint = if method_header == "unknown" && path_template == "unknown" {
+            "unknown".to_string()
+        } else {
+            format!("{} {}", method_header, path_template)
         };
 
-        let test_cases = vec![
-            (
-                "/dockebi/v1/stuff",
-                Some((
-                    "/dockebi/v1/stuff".to_string(),
-                    Rc::new("dockebi".to_string()),
-                )),
-            ),
-            (
-                "/dockebi/v1/stuff/123",
-                Some((
-                    "/dockebi/v1/stuff/{id_}".to_string(),
-                    Rc::new("dockebi".to_string()),
-                )),
-            ),
-            (
-                "/dockebi/v1/stuff/123/child/456/hello",
-                Some((
-                    "/dockebi/v1/stuff/{id_}/child/{child_id}/hello".to_string(),
-                    Rc::new("dockebi".to_string()),
-                )),
-            ),
-            (
-                "/dockebi/v1/stuff/123?key=value",
-                Some((
-                    "/dockebi/v1/stuff/{id_}".to_string(),
-                    Rc::new("dockebi".to_string()),
-                )),
-            ),
-            ("/dockebi/v1/other", None),
-            (
-                "/users",
-                Some(("/users".to_string(), Rc::new("userservice".to_string()))),
-            ),
-            (
-                "/users/42",
-                Some((
-                    "/users/{id}".to_string(),
-                    Rc::new("userservice".to_string()),
-                )),
-            ),
-            (
-                "/users/42/profile",
-                Some((
-                    "/users/{id}/profile".to_string(),
-                    Rc::new("userservice".to_string()),
-                )),
-            ),
+        let mut service_name = if self.settings.lowercase_service_header {
+            service_name.to_lowercase()
+        } else {
+            service_name.to_string()
+        };
+        if let Some(matched) = matched {
+            if let Some(param) = &matched.route.service_param {
+                match matched.params.iter().find(|(name, _)| name == param) {
+                    Some((_, value)) => {
+                        service_name.push('/');
+                        service_name.push_str(value);
+                    }
+                    None => debug!(
+                        "[oef] Service param '{}' not captured by {}",
+                        param, matched.template
+                    ),
+                }
+            }
+        }
+
+        let mut headers = vec![
+            ("x-service-name".to_string(), service_name),
             (
-                "/products",
-                Some((
-                    "/products".to_string(),
-                    Rc::new("productservice".to_string()),
-                )),
+                "x-path-template".to_string(),
+                self.settings.template_encoding.encode(path_template),
             ),
-            (
-                "/products/xyz123",
-                Some((
-                    "/products/{product_id}".to_string(),
-                    Rc::new("productservice".to_string()),
-                )),
+            ("x-api-endpoint".to_string(), api_endpoint),
+        ];
+        if let Some(summary) = route.and_then(|route| route.summary.as_deref()) {
+            headers.push(("x-openapi-summary".to_string(), percent_encode(summary)));
+        }
+        if let Some(produces) = route.and_then(|route| route.produces.as_deref()) {
+            headers.push(("x-oef-produces".to_string(), produces.to_string()));
+        }
+        if let Some(scheme) = route.and_then(|route| route.security_scheme.as_deref()) {
+            headers.push(("x-oef-security-scheme".to_string(), scheme.to_string()));
+        }
+        let full = self.feature_level < FeatureLevel::Reduced;
+        if self.settings.param_annotated_path && full {
+            if let Some(matched) = matched {
+                let annotated = annotate_template(matched.template, &matched.params);
+                headers.push((
+                    "x-oef-annotated-path".to_string(),
+                    format!("{}{}", matched.base_path, annotated),
+                ));
+            }
+        }
+        if let Some(header) = &self.settings.slug_header {
+            if let Some(slug) = route.and_then(|route| route.slug.as_ref()) {
+                headers.push((header.clone(), slug.clone()));
+            }
+        }
+        if let Some(separator) = &self.settings.routing_key_separator {
+            if let Some(matched) = matched {
+                let host = matched.route.group_key.as_deref().unwrap_or("*");
+                headers.push((
+                    "x-oef-routing-key".to_string(),
+                    routing_key(host, matched.service, separator),
+                ));
+            }
+        }
+        if self.settings.emit_params {
+            if let Some(matched) = matched {
+                let mut param_headers = Vec::new();
+                for (name, value) in &matched.params {
+                    param_headers.push((param_header_name(name), value.clone()));
+                    // Also under the names of templates merged into this one
+                    for (_, merged) in matched
+                        .route
+                        .merged_param_names
+                        .iter()
+                        .filter(|(primary, _)| primary == name)
+                    {
+                        param_headers.push((param_header_name(merged), value.clone()));
+                    }
+                }
+                if let Some(max) = self.settings.max_param_headers {
+                    if param_headers.len() > max {
+                        debug!(
+                            "[oef] Emitting {} of {} parameter headers for {} (maxParamHeaders)",
+                            max,
+                            param_headers.len(),
+                            matched.template
+                        );
+                        param_headers.truncate(max);
+                    }
+                }
+                headers.extend(param_headers);
+            }
+        }
+        if self.settings.emit_path_depth {
+            if let Some(route) = route {
+                headers.push(("x-oef-path-depth".to_string(), route.depth.to_string()));
+            }
+        }
+        if let Some(timeout_ms) = route.and_then(|route| route.timeout_ms) {
+            headers.push(("x-oef-timeout-ms".to_string(), timeout_ms.to_string()));
+        }
+        if matched.is_some_and(|matched| matched.websocket) {
+            headers.push(("x-oef-websocket".to_string(), "true".to_string()));
+        }
+        if self.settings.debug_headers && full {
+            if let Some(matched) = matched {
+                headers.push((
+                    "x-oef-param-count".to_string(),
+                    matched.params.len().to_string(),
+                ));
+                if let Some(hits) = &self.router_set.template_hits {
+                    headers.push((
+                        "x-oef-template-hits".to_string(),
+                        hits.count(matched.service, matched.template).to_string(),
+                    ));
+                }
+            }
+        }
+        headers
+    }
+
+    /// Reads the request host from the first configured source present, so
+    /// HTTP/2 `:authority` and HTTP/1.1 `host` route alike. Requests without
+    /// a usable host get `defaultHost`, if configured.
+    fn resolve_host(&self, get_header: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+        self.settings
+            .host_sources
+            .iter()
+            .find_map(|source| get_header(source))
+            .as_deref()
+            .and_then(OpenapiEndpointFilter::normalize_host)
+            .or_else(|| self.settings.default_host.clone())
+    }
+
+    /// Reads the request path from the configured sources. Returns `None` if
+    /// the sources disagree under a rejecting `pathConflictPolicy`.
+    fn resolve_path(&self, get_header: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+        let sources = &self.settings.path_sources;
+        let policy = self.settings.path_conflict_policy;
+        let mut paths = sources.iter().filter_map(|source| get_header(source));
+        let Some(path) = paths.next() else {
+            return Some(String::new());
+        };
+        if policy == PathConflictPolicy::First {
+            return Some(path);
+        }
+
+        let options = &self.router_set.normalize_options;
+        let canonical = |path: &str| normalize_path_with(path, options);
+        for other in paths {
+            let agree = match policy {
+                PathConflictPolicy::Canonical => canonical(&path) == canonical(&other),
+                _ => path == other,
+            };
+            if !agree {
+                warn!(
+                    "[oef] Path sources disagree ({:?} vs {:?}), rejecting request",
+                    path, other
+                );
+                return None;
+            }
+        }
+        Some(path)
+    }
+
+    /// The request method, or what `missingMethodBehavior` substitutes for
+    /// a missing `:method`. Returns `None` if the request is to be rejected.
+    fn resolve_method(&self, method: Option<String>) -> Option<String> {
+        if method.is_some() {
+            return method;
+        }
+        debug!("[oef] Request has no :method");
+        match self.settings.missing_method {
+            MissingMethod::Any => Some("unknown".to_string()),
+            MissingMethod::Reject => None,
+            MissingMethod::Get => Some("GET".to_string()),
+        }
+    }
+
+    /// The feature level for a request with `nheaders` headers and a path of
+    /// `path_length` bytes. Skipped features are logged.
+    fn budget_level(&self, nheaders: usize, path_length: usize) -> FeatureLevel {
+        let Some(budget) = &self.settings.feature_budget else {
+            return FeatureLevel::Full;
+        };
+        let level = budget.level(nheaders, path_length);
+        match level {
+            FeatureLevel::Full => {}
+            FeatureLevel::Reduced => debug!(
+                "[oef] Feature budget exceeded ({} headers, {} byte path), skipping shadow matching and diagnostic headers",
+                nheaders, path_length
             ),
-            (
-                "/categories/furniture/products",
-                Some((
-                    "/categories/{category_id}/products".to_string(),
-                    Rc::new("productservice".to_string()),
-                )),
+            FeatureLevel::Minimal => warn!(
+                "[oef] Feature budget exceeded twice over ({} headers, {} byte path), skipping all optional features",
+                nheaders, path_length
             ),
-            ("/unknownpath", None),
+        }
+        level
+    }
+
+    /// Whether the request has more headers than `maxHeaders`, in which case
+    /// optional features that read request headers (shadow matching,
+    /// bucketing, the dynamic target header, `x-oef-nearest` and baggage)
+    /// are skipped. Headers that routing depends on are still read.
+    fn exceeds_max_headers(&self, nheaders: usize) -> bool {
+        let exceeded = self.settings.max_headers.is_some_and(|max| nheaders > max);
+        if exceeded {
+            warn!(
+                "[oef] Request has {} headers (maxHeaders {:?}), skipping header-based features",
+                nheaders, self.settings.max_headers
+            );
+        }
+        exceeded
+    }
+
+    /// Matches the request against the shadow routes and describes the
+    /// difference from the primary match, if there is one.
+    fn shadow_divergence(
+        &self,
+        host: Option<&str>,
+        method: &str,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+        primary: Option<&Match>,
+    ) -> Option<String> {
+        if self.feature_level >= FeatureLevel::Reduced {
+            return None;
+        }
+        let shadow_router_set = self.shadow_router_set.as_ref()?;
+        let shadow = shadow_router_set.match_route(host, method, path, get_header);
+        let describe = |matched: Option<&Match>| {
+            matched
+                .map(|matched| format!("{} {}", matched.service, matched.template))
+                .unwrap_or_else(|| "unmatched".to_string())
+        };
+        let (primary, shadow) = (describe(primary), describe(shadow.as_ref()));
+        (primary != shadow).then(|| {
+            format!(
+                "[oef] shadow divergence for {} {}: primary={}, shadow={}",
+                method,
+                path.split('?').next().unwrap_or_default(),
+                primary,
+                shadow
+            )
+        })
+    }
+
+    /// The route named by a valid, unexpired `x-oef-route-token`, which
+    /// internal callers use to skip matching. Invalid tokens, and tokens
+    /// from hosts that are not trusted, are ignored.
+    fn route_from_token(
+        &self,
+        host: Option<&str>,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Route> {
+        let secret = self.settings.route_token_secret.as_ref()?;
+        let token = get_header("x-oef-route-token")?;
+        if !self.router_set.trusts_token_host(host) {
+            warn!("[oef] Ignoring x-oef-route-token from host {:?}", host);
+            return None;
+        }
+        let now = self
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        match verify_route_token(&token, secret.as_bytes(), now) {
+            Some((service, template)) => {
+                debug!("[oef] Route token accepted for {} {}", service, template);
+                Some(Route {
+                    template: template.to_string(),
+                    service_name: Rc::new(service.to_string()),
+                    depth: segment_count(template),
+                    ..Default::default()
+                })
+            }
+            None => {
+                warn!("[oef] Ignoring x-oef-route-token with an invalid signature or expired");
+                None
+            }
+        }
+    }
+
+    /// The `Allow` value to answer an `OPTIONS` request with when
+    /// `autoOptions` is on and no route took the request. It lists the
+    /// methods of every applicable route group that defines the path.
+    fn auto_options_allow(
+        &self,
+        host: Option<&str>,
+        method: &str,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+        matched: Option<&Match>,
+    ) -> Option<String> {
+        if !self.settings.auto_options || method != "options" || matched.is_some() {
+            return None;
+        }
+        let mut methods = self.router_set.allowed_methods(host, path, get_header);
+        if methods.is_empty() {
+            return None;
+        }
+        if !methods.iter().any(|method| method == "OPTIONS") {
+            methods.push("OPTIONS".to_string());
+            methods.sort();
+        }
+        Some(methods.join(", "))
+    }
+
+    /// The `unmatchedBody` with the request's method, path and host filled
+    /// in, or `None` if no body is configured.
+    fn unmatched_body(&self, method: &str, host: Option<&str>, path: &str) -> Option<String> {
+        let unmatched = self.settings.unmatched_response.as_ref()?;
+        if unmatched.body.is_empty() {
+            return None;
+        }
+        // The query string is left out, it may carry credentials
+        let path = path.split('?').next().unwrap_or_default();
+        let values = [
+            ("method", method),
+            ("path", path),
+            ("host", host.unwrap_or_default()),
         ];
+        let json = unmatched.content_type.to_ascii_lowercase().contains("json");
+        Some(render_placeholders(&unmatched.body, &values, json))
+    }
 
-        for (input_path, expected) in test_cases {
-            let result = http_ctx.get_path_template(None, "get", input_path);
-            assert_eq!(
-                result, expected,
-                "Path '{}' should match '{:?}' but got '{:?}'",
-                input_path, expected, result
+    /// Formats the audit log line for a routing decision, if it is sampled.
+    fn audit_line(
+        &self,
+        host: Option<&str>,
+        method: &str,
+        path: &str,
+        matched: Option<&Match>,
+    ) -> Option<String> {
+        if self.feature_level >= FeatureLevel::Minimal {
+            return None;
+        }
+        if !self.settings.audit_sampler.as_ref()?.sample() {
+            return None;
+        }
+        let (template, service) = matched
+            .map(|matched| (matched.template, matched.service.as_str()))
+            .unwrap_or(("-", "-"));
+        let mut line = "[oef] audit".to_string();
+        for field in &self.settings.log_fields {
+            let (name, value) = match field {
+                LogField::Host => ("host", host.unwrap_or("-").to_string()),
+                LogField::Method => ("method", method.to_string()),
+                // The query string is left out, it may carry credentials
+                LogField::Path => (
+                    "path",
+                    path.split('?').next().unwrap_or_default().to_string(),
+                ),
+                LogField::Template => ("template", template.to_string()),
+                LogField::Service => ("service", service.to_string()),
+                LogField::Params => {
+                    let params = matched
+                        .map(|matched| {
+                            matched
+                                .params
+                                .iter()
+                                .map(|(name, value)| format!("{}:{}", name, value))
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        })
+                        .filter(|params| !params.is_empty());
+                    ("params", params.unwrap_or_else(|| "-".to_string()))
+                }
+                LogField::Decision => (
+                    "decision",
+                    if matched.is_some() {
+                        "matched"
+                    } else {
+                        "unmatched"
+                    }
+                    .to_string(),
+                ),
+            };
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        Some(line)
+    }
+
+    /// Drops the routing headers of requests not sampled by `emitSampleRate`.
+    fn sample_emission(&self, headers: Vec<(String, String)>) -> Vec<(String, String)> {
+        match &self.settings.emit_sampler {
+            Some(sampler) if !sampler.sample() => {
+                debug!("[oef] Request not sampled for header emission");
+                Vec::new()
+            }
+            _ => headers,
+        }
+    }
+
+    /// Service and template to wrap the request body with, if
+    /// `prependRouteToBody` is on and the request matched.
+    fn body_route(&self, matched: Option<&Match>) -> Option<(String, String)> {
+        if !self.settings.prepend_route_to_body {
+            return None;
+        }
+        matched.map(|matched| (matched.service.to_string(), matched.template.to_string()))
+    }
+
+    fn retain_for_response(&mut self, matched: bool, headers: &[(String, String)]) {
+        if !matched || !self.settings.emit_service_on_response {
+            return;
+        }
+        self.response_service = headers
+            .iter()
+            .find(|(name, _)| name == "x-service-name")
+            .map(|(_, value)| value.clone());
+    }
+
+    /// The `x-oef-bucket` header for a match, if bucketing is configured and
+    /// the request carries the bucketing attribute.
+    fn bucket_header(
+        &self,
+        matched: Option<&Match>,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<(String, String)> {
+        let bucketing = self.settings.bucketing.as_ref()?;
+        let matched = matched.filter(|_| self.feature_level < FeatureLevel::Reduced)?;
+        let attribute = bucketing.attribute(get_header)?;
+        let bucket = bucketing.bucket(&attribute, matched.service, matched.template);
+        Some(("x-oef-bucket".to_string(), bucket.to_string()))
+    }
+
+    /// Filter state properties to set for the match with `emitMetadata`.
+    fn metadata_properties(&self, matched: Option<&Match>) -> Vec<(Vec<&'static str>, String)> {
+        match matched {
+            Some(matched)
+                if self.settings.emit_metadata && self.feature_level < FeatureLevel::Minimal =>
+            {
+                vec![
+                    (vec!["oef.service"], matched.service.to_string()),
+                    (vec!["oef.template"], matched.template.to_string()),
+                ]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// With `emitAsTrailer`, moves the service and template headers out of
+    /// `headers` to be set as response trailers; returns the rest.
+    fn defer_to_trailers(&mut self, headers: Vec<(String, String)>) -> Vec<(String, String)> {
+        if !self.settings.emit_as_trailer {
+            return headers;
+        }
+        let (trailers, headers): (Vec<_>, _) = headers
+            .into_iter()
+            .partition(|(name, _)| name == "x-service-name" || name == "x-path-template");
+        self.response_trailers = trailers
+            .into_iter()
+            .map(|(name, value)| match name.as_str() {
+                "x-path-template" => (TEMPLATE_TRAILER.to_string(), value),
+                _ => (name, value),
+            })
+            .collect();
+        headers
+    }
+
+    /// Whether the response is gRPC, whose trailers carry its status, so
+    /// routing trailers can wait for them.
+    fn expects_trailers(&self) -> bool {
+        self.get_http_response_header("content-type")
+            .is_some_and(|content_type| {
+                content_type
+                    .to_ascii_lowercase()
+                    .starts_with("application/grpc")
+            })
+    }
+
+    fn response_headers(&self) -> Vec<(String, String)> {
+        let service = self
+            .response_service
+            .iter()
+            .map(|service| ("x-service-name".to_string(), service.clone()));
+        let sunset = self
+            .response_sunset
+            .iter()
+            .map(|sunset| ("sunset".to_string(), sunset.clone()));
+        service.chain(sunset).collect()
+    }
+
+    /// The header named by the request's `x-oef-target-header`, carrying the
+    /// `x-api-endpoint` value of a match, if `allowDynamicTargetHeader` is on
+    /// and the name is in `dynamicTargetHeaders`.
+    fn dynamic_target_header(
+        &self,
+        headers: &[(String, String)],
+        matched: bool,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<(String, String)> {
+        let allowed = self.settings.dynamic_target_headers.as_ref()?;
+        if !matched {
+            return None;
+        }
+        let requested = get_header("x-oef-target-header")?;
+        let name = requested.trim().to_ascii_lowercase();
+        if !allowed.contains(&name) {
+            debug!(
+                "[oef] Ignoring x-oef-target-header {:?}, not in dynamicTargetHeaders",
+                requested
             );
+            return None;
         }
+        let (_, endpoint) = headers.iter().find(|(name, _)| name == "x-api-endpoint")?;
+        Some((name, endpoint.clone()))
+    }
+
+    fn miss_headers(
+        &self,
+        host: Option<&str>,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Vec<(String, String)> {
+        if !self.settings.debug_headers || self.feature_level >= FeatureLevel::Reduced {
+            return Vec::new();
+        }
+        self.router_set
+            .nearest_template(host, path, get_header)
+            .map(|template| vec![("x-oef-nearest".to_string(), template.to_string())])
+            .unwrap_or_default()
+    }
+
+    fn normalize_host(host: &str) -> Option<String> {
+        let trimmed = host.trim();
+        if trimmed.is_empty() {
+            return None;
+        }
+        let lower = trimmed.to_ascii_lowercase();
+        let host = strip_port(strip_userinfo(&lower));
+        if host.is_empty() {
+            None
+        } else {
+            Some(host.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::normalize_path;
+    use serde_json::json;
+
+    fn no_headers(_: &str) -> Option<String> {
+        None
+    }
+
+    fn endpoint<'a>(matched: Option<Match<'a>>) -> Option<(&'a str, &'a str)> {
+        matched.map(|matched| (matched.template, matched.service.as_str()))
+    }
+
+    /// Stand-ins for the proxy-wasm host calls the filter makes, so that its
+    /// `HttpContext` callbacks can run in tests. Each test thread has its own
+    /// exchange of request and response.
+    mod host {
+        use proxy_wasm::types::{BufferType, MapType, MetricType, Status};
+        use std::cell::RefCell;
+
+        /// Status, headers and body sent with `send_http_response`.
+        pub(super) type LocalResponse = (u32, Vec<(String, String)>, Vec<u8>);
+
+        #[derive(Default)]
+        pub(super) struct Exchange {
+            pub(super) request_headers: Vec<(String, String)>,
+            pub(super) response_headers: Vec<(String, String)>,
+            pub(super) response_trailers: Vec<(String, String)>,
+            pub(super) request_body: Vec<u8>,
+            pub(super) local_response: Option<LocalResponse>,
+            pub(super) time_nanos: u64,
+            /// Defined metrics by id, with their last recorded value.
+            pub(super) metrics: Vec<(String, u64)>,
+        }
+
+        thread_local! {
+            static EXCHANGE: RefCell<Exchange> = RefCell::new(Exchange::default());
+        }
+
+        /// Starts a new exchange with these request headers.
+        pub(super) fn reset(request_headers: &[(&str, &str)]) {
+            EXCHANGE.with(|exchange| {
+                *exchange.borrow_mut() = Exchange {
+                    request_headers: request_headers
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect(),
+                    ..Default::default()
+                }
+            });
+        }
+
+        pub(super) fn with<R>(f: impl FnOnce(&mut Exchange) -> R) -> R {
+            EXCHANGE.with(|exchange| f(&mut exchange.borrow_mut()))
+        }
+
+        pub(super) fn request_header(name: &str) -> Option<String> {
+            with(|exchange| find(&exchange.request_headers, name))
+        }
+
+        pub(super) fn response_header(name: &str) -> Option<String> {
+            with(|exchange| find(&exchange.response_headers, name))
+        }
+
+        /// The last value recorded for the metric defined as `name`.
+        pub(super) fn metric(name: &str) -> Option<u64> {
+            with(|exchange| {
+                exchange
+                    .metrics
+                    .iter()
+                    .find(|(defined, _)| defined == name)
+                    .map(|(_, value)| *value)
+            })
+        }
+
+        fn find(map: &[(String, String)], name: &str) -> Option<String> {
+            map.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        }
+
+        fn map(exchange: &mut Exchange, map_type: MapType) -> &mut Vec<(String, String)> {
+            match map_type {
+                MapType::HttpRequestHeaders => &mut exchange.request_headers,
+                MapType::HttpResponseHeaders => &mut exchange.response_headers,
+                MapType::HttpResponseTrailers => &mut exchange.response_trailers,
+                _ => panic!("unexpected map type {:?}", map_type),
+            }
+        }
+
+        unsafe fn text(data: *const u8, size: usize) -> String {
+            String::from_utf8(std::slice::from_raw_parts(data, size).to_vec()).unwrap()
+        }
+
+        /// Hands `bytes` to the SDK, which frees them as a `Vec`.
+        unsafe fn give(bytes: Vec<u8>, data: *mut *mut u8, size: *mut usize) {
+            let bytes = bytes.into_boxed_slice();
+            *size = bytes.len();
+            *data = Box::into_raw(bytes) as *mut u8;
+        }
+
+        /// Parses a header map as serialized by the SDK.
+        unsafe fn deserialize_map(data: *const u8, size: usize) -> Vec<(String, String)> {
+            let bytes = std::slice::from_raw_parts(data, size);
+            let number = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            let count = number(0) as usize;
+            let mut offset = 4 + count * 8;
+            let mut pairs = Vec::new();
+            for i in 0..count {
+                let key_size = number(4 + i * 8) as usize;
+                let value_size = number(8 + i * 8) as usize;
+                let key = &bytes[offset..offset + key_size];
+                offset += key_size + 1;
+                let value = &bytes[offset..offset + value_size];
+                offset += value_size + 1;
+                pairs.push((
+                    String::from_utf8(key.to_vec()).unwrap(),
+                    String::from_utf8(value.to_vec()).unwrap(),
+                ));
+            }
+            pairs
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_get_header_map_value(
+            map_type: MapType,
+            key_data: *const u8,
+            key_size: usize,
+            return_value_data: *mut *mut u8,
+            return_value_size: *mut usize,
+        ) -> Status {
+            let key = text(key_data, key_size);
+            match with(|exchange| find(map(exchange, map_type), &key)) {
+                Some(value) => {
+                    give(value.into_bytes(), return_value_data, return_value_size);
+                    Status::Ok
+                }
+                None => Status::NotFound,
+            }
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_replace_header_map_value(
+            map_type: MapType,
+            key_data: *const u8,
+            key_size: usize,
+            value_data: *const u8,
+            value_size: usize,
+        ) -> Status {
+            let (key, value) = (text(key_data, key_size), text(value_data, value_size));
+            with(|exchange| {
+                let map = map(exchange, map_type);
+                map.retain(|(name, _)| *name != key);
+                map.push((key, value));
+            });
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_add_header_map_value(
+            map_type: MapType,
+            key_data: *const u8,
+            key_size: usize,
+            value_data: *const u8,
+            value_size: usize,
+        ) -> Status {
+            let (key, value) = (text(key_data, key_size), text(value_data, value_size));
+            with(|exchange| map(exchange, map_type).push((key, value)));
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_remove_header_map_value(
+            map_type: MapType,
+            key_data: *const u8,
+            key_size: usize,
+        ) -> Status {
+            let key = text(key_data, key_size);
+            with(|exchange| map(exchange, map_type).retain(|(name, _)| *name != key));
+            Status::Ok
+        }
+
+        #[no_mangle]
+        #[allow(clippy::too_many_arguments)]
+        unsafe extern "C" fn proxy_send_local_response(
+            status_code: u32,
+            _status_code_details_data: *const u8,
+            _status_code_details_size: usize,
+            body_data: *const u8,
+            body_size: usize,
+            headers_data: *const u8,
+            headers_size: usize,
+            _grpc_status: i32,
+        ) -> Status {
+            let body = if body_data.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(body_data, body_size).to_vec()
+            };
+            let headers = deserialize_map(headers_data, headers_size);
+            with(|exchange| exchange.local_response = Some((status_code, headers, body)));
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_get_buffer_bytes(
+            buffer_type: BufferType,
+            start: usize,
+            max_size: usize,
+            return_buffer_data: *mut *mut u8,
+            return_buffer_size: *mut usize,
+        ) -> Status {
+            assert_eq!(buffer_type, BufferType::HttpRequestBody);
+            let body = with(|exchange| exchange.request_body.clone());
+            let end = body.len().min(start.saturating_add(max_size));
+            give(
+                body[start.min(end)..end].to_vec(),
+                return_buffer_data,
+                return_buffer_size,
+            );
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_set_buffer_bytes(
+            buffer_type: BufferType,
+            start: usize,
+            size: usize,
+            buffer_data: *const u8,
+            buffer_size: usize,
+        ) -> Status {
+            assert_eq!(buffer_type, BufferType::HttpRequestBody);
+            let data = std::slice::from_raw_parts(buffer_data, buffer_size).to_vec();
+            with(|exchange| {
+                let end = exchange.request_body.len().min(start + size);
+                exchange.request_body.splice(start..end, data);
+            });
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_set_property(
+            _path_data: *const u8,
+            _path_size: usize,
+            _value_data: *const u8,
+            _value_size: usize,
+        ) -> Status {
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_define_metric(
+            _metric_type: MetricType,
+            name_data: *const u8,
+            name_size: usize,
+            return_id: *mut u32,
+        ) -> Status {
+            let name = text(name_data, name_size);
+            *return_id = with(|exchange| {
+                exchange.metrics.push((name, 0));
+                exchange.metrics.len() as u32 - 1
+            });
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+            with(
+                |exchange| match exchange.metrics.get_mut(metric_id as usize) {
+                    Some(metric) => {
+                        metric.1 = value;
+                        Status::Ok
+                    }
+                    None => Status::NotFound,
+                },
+            )
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_set_tick_period_milliseconds(_period: u32) -> Status {
+            Status::Ok
+        }
+
+        #[no_mangle]
+        unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+            *return_time = with(|exchange| exchange.time_nanos);
+            Status::Ok
+        }
+    }
+
+    /// Runs the request header phase of a filter for `config` on a request
+    /// with these headers, announced as `nheaders` headers.
+    fn run_request_headers(
+        config: &Value,
+        headers: &[(&str, &str)],
+        nheaders: usize,
+    ) -> (OpenapiEndpointFilter, Action) {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(config).unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        host::reset(headers);
+        let action = http_ctx.on_http_request_headers(nheaders, false);
+        (http_ctx, action)
+    }
+
+    /// Average time per call of `f` over `iterations` calls, for the
+    /// `#[ignore]`d timing tests (`cargo test --release -- --ignored`).
+    fn time_per_call(iterations: u32, mut f: impl FnMut()) -> std::time::Duration {
+        let start = std::time::Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        start.elapsed() / iterations
+    }
+
+    const TEST_CONFIG: &str = r#"{
+        "cacheSize": 5,
+        "services": [
+            {
+                "name": "dockebi",
+                "paths": {
+                    "/dockebi/v1/stuff": {},
+                    "/dockebi/v1/stuff/{id_}": {},
+                    "/dockebi/v1/stuff/{id_}/child/{child_id}/hello": {}
+                }
+            },
+            {
+                "name": "userservice",
+                "paths": {
+                    "/users": {},
+                    "/users/{id}": {},
+                    "/users/{id}/profile": {}
+                }
+            },
+            {
+                "name": "productservice",
+                "paths": {
+                    "/products": {},
+                    "/products/{product_id}": {},
+                    "/categories/{category_id}/products": {}
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_basic_path_and_service_matching() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        let test_cases = vec![
+            ("/dockebi/v1/stuff", Some(("/dockebi/v1/stuff", "dockebi"))),
+            (
+                "/dockebi/v1/stuff/123",
+                Some(("/dockebi/v1/stuff/{id_}", "dockebi")),
+            ),
+            (
+                "/dockebi/v1/stuff/123/child/456/hello",
+                Some(("/dockebi/v1/stuff/{id_}/child/{child_id}/hello", "dockebi")),
+            ),
+            (
+                "/dockebi/v1/stuff/123?key=value",
+                Some(("/dockebi/v1/stuff/{id_}", "dockebi")),
+            ),
+            ("/dockebi/v1/other", None),
+            ("/users", Some(("/users", "userservice"))),
+            ("/users/42", Some(("/users/{id}", "userservice"))),
+            (
+                "/users/42/profile",
+                Some(("/users/{id}/profile", "userservice")),
+            ),
+            ("/products", Some(("/products", "productservice"))),
+            (
+                "/products/xyz123",
+                Some(("/products/{product_id}", "productservice")),
+            ),
+            (
+                "/categories/furniture/products",
+                Some(("/categories/{category_id}/products", "productservice")),
+            ),
+            ("/unknownpath", None),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let result = endpoint(http_ctx.get_path_template(None, "get", input_path, &no_headers));
+            assert_eq!(
+                result, expected,
+                "Path '{}' should match '{:?}' but got '{:?}'",
+                input_path, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_path_normalization() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        let test_cases = vec![
+            (
+                "/users/42?sortBy=name&order=asc",
+                Some(("/users/{id}", "userservice")),
+            ),
+            (
+                "/products?category=electronics&inStock=true",
+                Some(("/products", "productservice")),
+            ),
+            (
+                "/categories/books/products?featured=true&limit=10",
+                Some(("/categories/{category_id}/products", "productservice")),
+            ),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let result = endpoint(http_ctx.get_path_template(None, "get", input_path, &no_headers));
+            assert_eq!(
+                result, expected,
+                "Path with query params '{}' should match '{:?}' but got '{:?}'",
+                input_path, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_path_normalization() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/": {},
+                        "/api//v1/data": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users", &no_headers)),
+            Some(("/users", "userservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/v1/data", &no_headers)),
+            Some(("/api/v1/data", "userservice"))
+        );
+    }
+
+    #[test]
+    fn test_advanced_path_normalization() {
+        let test_cases = vec![
+            // Trailing slash
+            ("/users/", "/users"),
+            ("/products/", "/products"),
+            // Root path should remain as /
+            ("/", "/"),
+            // Fragment removal
+            ("/users#section", "/users"),
+            ("/products#top", "/products"),
+            // Duplicate slashes
+            ("/users//profile", "/users/profile"),
+            ("//users", "/users"),
+            ("/users///profile///", "/users/profile"),
+            ("///users///profile///", "/users/profile"),
+            // Combined: query + fragment + trailing slash
+            ("/users/?query=1#section", "/users"),
+            ("/products?a=1&b=2#top", "/products"),
+            // Combined: duplicate slashes + query + fragment
+            ("//users//profile//?key=value#section", "/users/profile"),
+            // Complex real-world cases
+            (
+                "/api//v1///users/{id}/?format=json#details",
+                "/api/v1/users/{id}",
+            ),
+            (
+                "/dockebi/v1/stuff/{id_}//child/{child_id}/hello/?test=1",
+                "/dockebi/v1/stuff/{id_}/child/{child_id}/hello",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let result = normalize_path(input);
+            assert_eq!(
+                result, expected,
+                "normalize_path('{}') should return '{}' but got '{}'",
+                input, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalized_path_matching() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        // Test that normalized paths match correctly
+        let test_cases = vec![
+            // Trailing slash should match
+            ("/users/", Some(("/users", "userservice"))),
+            // Fragment should be ignored
+            ("/users#section", Some(("/users", "userservice"))),
+            // Duplicate slashes should match
+            ("/users//42", Some(("/users/{id}", "userservice"))),
+            // Combined normalization
+            (
+                "/users/42//profile/?query=1#section",
+                Some(("/users/{id}/profile", "userservice")),
+            ),
+            // Root path
+            ("/", None),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let result = endpoint(http_ctx.get_path_template(None, "get", input_path, &no_headers));
+            assert_eq!(
+                result, expected,
+                "Path '{}' should match '{:?}' but got '{:?}'",
+                input_path, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_complex_path_patterns() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "complexapi",
+                    "paths": {
+                        "/api/v1/resources/{resource_id}/subresources/{subresource_id}/items/{item_id}": {},
+                        "/api/v1/users/{user_id}/orders/{order_id}/items/{item_id}/tracking": {},
+                        "/{tenant_id}/dashboard": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        let test_cases = vec![
+            (
+                "/api/v1/resources/r123/subresources/sub456/items/i789",
+                Some((
+                    "/api/v1/resources/{resource_id}/subresources/{subresource_id}/items/{item_id}",
+                    "complexapi",
+                )),
+            ),
+            (
+                "/api/v1/users/u123/orders/o456/items/i789/tracking",
+                Some((
+                    "/api/v1/users/{user_id}/orders/{order_id}/items/{item_id}/tracking",
+                    "complexapi",
+                )),
+            ),
+            (
+                "/tenant1/dashboard",
+                Some(("/{tenant_id}/dashboard", "complexapi")),
+            ),
+            ("/api/v1/resources/r123/something_else", None),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let result = endpoint(http_ctx.get_path_template(None, "get", input_path, &no_headers));
+            assert_eq!(
+                result, expected,
+                "Complex path '{}' should match '{:?}' but got '{:?}'",
+                input_path, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_servers_and_method_matching() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [
+                        {
+                            "url": "https://{env}.example.com/v1",
+                            "variables": {
+                                "env": {
+                                    "default": "api",
+                                    "enum": ["api", "staging"]
+                                }
+                            }
+                        }
+                    ],
+                    "paths": {
+                        "/users": {
+                            "get": {}
+                        },
+                        "/admin": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("api.example.com"),
+                "get",
+                "/v1/users",
+                &no_headers
+            )),
+            Some(("/users", "userservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("staging.example.com"),
+                "post",
+                "/v1/admin",
+                &no_headers
+            )),
+            Some(("/admin", "userservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("api.example.com"),
+                "post",
+                "/v1/users",
+                &no_headers
+            )),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("api.example.com"),
+                "get",
+                "/users",
+                &no_headers
+            )),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("other.example.com"),
+                "get",
+                "/v1/users",
+                &no_headers
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_host_matching_disabled() {
+        let config = json!({
+            "useHostInMatch": false,
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [
+                        { "url": "https://api.example.com/v1" }
+                    ],
+                    "paths": {
+                        "/users": {
+                            "get": {}
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("other.example.com"),
+                "get",
+                "/v1/users",
+                &no_headers
+            )),
+            Some(("/users", "userservice"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_configurations() {
+        let test_cases = vec![
+            // Missing services array
+            (
+                json!({
+                    "cacheSize": 10
+                }),
+                "Invalid or missing 'services' in configuration",
+            ),
+            // Service without name
+            (
+                json!({
+                    "services": [
+                        {
+                            "paths": {
+                                "/test": {}
+                            }
+                        }
+                    ]
+                }),
+                "Missing 'name' in service configuration",
+            ),
+            // Service without paths
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test"
+                        }
+                    ]
+                }),
+                "Invalid or missing 'paths' in service configuration",
+            ),
+            // Invalid paths type
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test",
+                            "paths": "invalid"
+                        }
+                    ]
+                }),
+                "Invalid or missing 'paths' in service configuration",
+            ),
+            // Empty services array
+            (
+                json!({
+                    "services": []
+                }),
+                "Services array cannot be empty",
+            ),
+            // Empty service name
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "",
+                            "paths": {
+                                "/test": {}
+                            }
+                        }
+                    ]
+                }),
+                "Service name cannot be empty",
+            ),
+            // Empty paths object
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test",
+                            "paths": {}
+                        }
+                    ]
+                }),
+                "has no paths",
+            ),
+            // Path not starting with /
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test",
+                            "paths": {
+                                "test": {}
+                            }
+                        }
+                    ]
+                }),
+                "must start with '/'",
+            ),
+            // Path too long
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test",
+                            "paths": {
+                                format!("/{}", "x".repeat(1024)): {}
+                            }
+                        }
+                    ]
+                }),
+                "too long",
+            ),
+            // Path with space
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "test",
+                            "paths": {
+                                "/test path": {}
+                            }
+                        }
+                    ]
+                }),
+                "contains space",
+            ),
+            // Duplicate paths
+            (
+                json!({
+                    "services": [
+                        {
+                            "name": "service1",
+                            "paths": {
+                                "/test": {}
+                            }
+                        },
+                        {
+                            "name": "service2",
+                            "paths": {
+                                "/test": {}
+                            }
+                        }
+                    ]
+                }),
+                "Duplicate or conflicting route",
+            ),
+        ];
+
+        for (config, expected_error) in test_cases {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let result = root_ctx.configure(&config);
+
+            assert!(result.is_err(), "Configuration should fail: {:?}", config);
+            let error = result.err().unwrap();
+            assert!(
+                error.to_string().contains(expected_error),
+                "Error message should contain '{}', but got '{}'",
+                expected_error,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_multiple_service_overlapping_routes() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "service1",
+                    "paths": {
+                        "/api/v1/shared": {},
+                        "/api/v1/service1/specific": {}
+                    }
+                },
+                {
+                    "name": "service2",
+                    "paths": {
+                        "/api/v1/shared/{id}": {},
+                        "/api/v1/service2/specific": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+
+        // For exact path matches, the first service should win
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/v1/shared", &no_headers)),
+            Some(("/api/v1/shared", "service1"))
+        );
+
+        // For parameterized paths, the match should work correctly
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/v1/shared/123", &no_headers)),
+            Some(("/api/v1/shared/{id}", "service2"))
+        );
+
+        // Service-specific paths should go to the correct service
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                None,
+                "get",
+                "/api/v1/service1/specific",
+                &no_headers
+            )),
+            Some(("/api/v1/service1/specific", "service1"))
+        );
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                None,
+                "get",
+                "/api/v1/service2/specific",
+                &no_headers
+            )),
+            Some(("/api/v1/service2/specific", "service2"))
+        );
+    }
+
+    #[test]
+    fn test_required_header_matching() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "privateservice",
+                    "paths": {
+                        "/reports/{id}": {
+                            "get": {},
+                            "x-require-header": "Authorization"
+                        },
+                        "/admin": {
+                            "x-require-header": "authorization"
+                        }
+                    }
+                },
+                {
+                    "name": "publicservice",
+                    "paths": {
+                        "/reports/{id}": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let http_ctx = root_ctx.create_filter();
+        let with_authorization = |name: &str| {
+            if name == "authorization" {
+                Some("Bearer token".to_string())
+            } else {
+                None
+            }
+        };
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/reports/1", &with_authorization)),
+            Some(("/reports/{id}", "privateservice"))
+        );
+        // Without the header, the route is skipped and matching continues
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/reports/1", &no_headers)),
+            Some(("/reports/{id}", "publicservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "post", "/admin", &with_authorization)),
+            Some(("/admin", "privateservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "post", "/admin", &no_headers)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_required_header() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "test",
+                    "paths": {
+                        "/test": {
+                            "x-require-header": ""
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx.configure(&config).err().unwrap();
+        assert!(error.to_string().contains("Invalid 'x-require-header'"));
+    }
+
+    #[test]
+    fn test_base_path_root_matching() {
+        let config = |match_base_path_root: bool| {
+            json!({
+                "matchBasePathRoot": match_base_path_root,
+                "services": [
+                    {
+                        "name": "apiservice",
+                        "servers": [
+                            { "url": "/api/v1" }
+                        ],
+                        "paths": {
+                            "/": {},
+                            "/users": {}
+                        }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        for path in ["/api/v1", "/api/v1/", "/api/v1?query=1"] {
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", path, &no_headers)),
+                Some(("/", "apiservice")),
+                "Path '{}' should match the root path item",
+                path
+            );
+        }
+
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        for path in ["/api/v1", "/api/v1/", "/api/v1?query=1"] {
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", path, &no_headers)),
+                None,
+                "Path '{}' should not match the root path item",
+                path
+            );
+        }
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/v1/users", &no_headers)),
+            Some(("/users", "apiservice"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_fail_open() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.load_configuration(br#"{ "services": [] }"#.to_vec());
+
+        assert!(!root_ctx.configured);
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_PARSE"));
+        assert!(!http_ctx.fail_closed);
+
+        root_ctx.load_configuration(b"not json".to_vec());
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_JSON"));
+        assert!(!http_ctx.fail_closed);
+    }
+
+    #[test]
+    fn test_invalid_config_fail_closed() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.load_configuration(br#"{ "failClosed": true, "services": [] }"#.to_vec());
+
+        assert!(!root_ctx.configured);
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_PARSE"));
+        assert!(http_ctx.fail_closed);
+
+        // A later unparsable config keeps the last known failure policy
+        root_ctx.load_configuration(b"not json".to_vec());
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_JSON"));
+        assert!(http_ctx.fail_closed);
+
+        // Loading a valid config stops rejecting requests
+        root_ctx.load_configuration(
+            TEST_CONFIG
+                .replace("\"cacheSize\": 5", "\"failClosed\": true")
+                .into_bytes(),
+        );
+        assert!(root_ctx.configured);
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error, None);
+        assert!(!http_ctx.fail_closed);
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users", &no_headers)),
+            Some(("/users", "userservice"))
+        );
+    }
+
+    #[test]
+    fn test_operation_summary_header() {
+        let config = json!({
+            "exposeSummary": true,
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {
+                            "get": { "summary": "Get a user by ID" },
+                            "delete": {}
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let route = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", route.as_ref());
+        assert!(headers.contains(&(
+            "x-openapi-summary".to_string(),
+            "Get%20a%20user%20by%20ID".to_string()
+        )));
+
+        let route = http_ctx
+            .router_set
+            .match_route(None, "delete", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("DELETE", route.as_ref());
+        assert!(headers.iter().all(|(name, _)| name != "x-openapi-summary"));
+        assert!(headers.contains(&(
+            "x-api-endpoint".to_string(),
+            "DELETE /users/{id}".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_operation_summary_disabled_by_default() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {
+                            "get": { "summary": "Get a user by ID" }
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let route = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", route.as_ref());
+        assert!(headers.iter().all(|(name, _)| name != "x-openapi-summary"));
+    }
+
+    #[test]
+    fn test_lowercase_service_header() {
+        let config = |lowercase_service_header: bool| {
+            json!({
+                "lowercaseServiceHeader": lowercase_service_header,
+                "services": [
+                    {
+                        "name": "UserService",
+                        "paths": {
+                            "/users/{id}": {}
+                        }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let route = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        assert_eq!(
+            route.as_ref().map(|matched| matched.service.as_str()),
+            Some("UserService")
+        );
+        let headers = http_ctx.route_headers("GET", route.as_ref());
+        assert!(headers.contains(&("x-service-name".to_string(), "userservice".to_string())));
+
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let route = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", route.as_ref());
+        assert!(headers.contains(&("x-service-name".to_string(), "UserService".to_string())));
+    }
+
+    #[test]
+    fn test_prefix_routes() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "legacyservice",
+                    "prefixes": ["/legacy/", "/legacy/v2"]
+                },
+                {
+                    "name": "reportservice",
+                    "prefixes": [
+                        { "prefix": "/reports", "template": "/reports/**" }
+                    ],
+                    "paths": {
+                        "/reports/{id}/summary": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let test_cases = vec![
+            (
+                "/legacy/anything/deep",
+                Some(("/legacy/*", "legacyservice")),
+            ),
+            ("/legacy/x?query=1", Some(("/legacy/*", "legacyservice"))),
+            ("/legacy/v2/items", Some(("/legacy/v2/*", "legacyservice"))),
+            ("/legacyx", None),
+            ("/legacyx/anything", None),
+            ("/reports/2024/q1", Some(("/reports/**", "reportservice"))),
+            // More specific templates win over the prefix
+            (
+                "/reports/42/summary",
+                Some(("/reports/{id}/summary", "reportservice")),
+            ),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let result = endpoint(http_ctx.get_path_template(None, "get", input_path, &no_headers));
+            assert_eq!(
+                result, expected,
+                "Path '{}' should match '{:?}' but got '{:?}'",
+                input_path, expected, result
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_prefixes() {
+        let test_cases = vec![
+            (json!(["legacy"]), "Prefix must start with '/'"),
+            (json!(["/legacy/{id}"]), "cannot contain path parameters"),
+            (json!([42]), "Invalid entry in 'prefixes'"),
+            (json!("/legacy"), "Invalid 'prefixes'"),
+        ];
+
+        for (prefixes, expected_error) in test_cases {
+            let config = json!({
+                "services": [
+                    {
+                        "name": "legacyservice",
+                        "prefixes": prefixes
+                    }
+                ]
+            });
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx.configure(&config).err().unwrap();
+            assert!(
+                error.to_string().contains(expected_error),
+                "Error message should contain '{}', but got '{}'",
+                expected_error,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_path_segments() {
+        let config = json!({
+            "maxPathSegments": 3,
+            "services": [
+                {
+                    "name": "dockebi",
+                    "paths": {
+                        "/dockebi/v1/stuff": {},
+                        "/dockebi/v1/stuff/{id_}": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        // At the limit
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/dockebi/v1/stuff", &no_headers)),
+            Some(("/dockebi/v1/stuff", "dockebi"))
+        );
+        // Empty segments do not count
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "//dockebi//v1/stuff/", &no_headers)),
+            Some(("/dockebi/v1/stuff", "dockebi"))
+        );
+        // Above the limit
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/dockebi/v1/stuff/123", &no_headers)),
+            None
+        );
+
+        let options = NormalizeOptions {
+            max_segments: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_path_with("/a/b/c", &options),
+            Some("/a/b/c".to_string())
+        );
+        assert_eq!(normalize_path_with("/a/b/c/d", &options), None);
+        assert_eq!(normalize_path_with("/", &options), Some("/".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_max_path_segments() {
+        for value in [json!(0), json!(-1), json!("abc")] {
+            let config = json!({
+                "maxPathSegments": value,
+                "services": [
+                    {
+                        "name": "test",
+                        "paths": {
+                            "/test": {}
+                        }
+                    }
+                ]
+            });
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx.configure(&config).err().unwrap();
+            assert!(error
+                .to_string()
+                .contains("'maxPathSegments' must be a positive integer"));
+        }
+    }
+
+    #[test]
+    fn test_param_count_debug_header() {
+        let config = json!({
+            "debugHeaders": true,
+            "services": [
+                {
+                    "name": "dockebi",
+                    "paths": {
+                        "/dockebi/v1/stuff": {},
+                        "/dockebi/v1/stuff/{id_}": {},
+                        "/dockebi/v1/stuff/{id_}/child/{child_id}/hello": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let test_cases = vec![
+            ("/dockebi/v1/stuff", Some("0")),
+            ("/dockebi/v1/stuff/123", Some("1")),
+            ("/dockebi/v1/stuff/123/child/456/hello", Some("2")),
+            ("/unknownpath", None),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let matched = http_ctx
+                .router_set
+                .match_route(None, "get", input_path, &no_headers);
+            let headers = http_ctx.route_headers("GET", matched.as_ref());
+            let param_count = headers
+                .iter()
+                .find(|(name, _)| name == "x-oef-param-count")
+                .map(|(_, value)| value.as_str());
+            assert_eq!(
+                param_count, expected,
+                "Path '{}' should have param count {:?}",
+                input_path, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_headers_disabled_by_default() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.iter().all(|(name, _)| !name.starts_with("x-oef-")));
+    }
+
+    #[test]
+    fn test_miss_log_sampling() {
+        let sampler = Sampler::new(3);
+        let sampled: Vec<bool> = (0..7).map(|_| sampler.sample()).collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false, true]);
+        assert_eq!(sampler.count(), 7);
+
+        let config = json!({
+            "missLogSampleRate": 100,
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        // The miss counter is shared by all contexts; matches are not counted
+        for _ in 0..5 {
+            let http_ctx = root_ctx.create_filter();
+            endpoint(http_ctx.get_path_template(None, "get", "/unknown", &no_headers));
+            endpoint(http_ctx.get_path_template(None, "get", "/users", &no_headers));
+        }
+        assert_eq!(root_ctx.router_set.miss_log_sampler.count(), 5);
+    }
+
+    #[test]
+    fn test_websocket_upgrade_matching() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "chatservice",
+                    "paths": {
+                        "/chat/{room}": {
+                            "get": {},
+                            "x-websocket": true
+                        }
+                    }
+                },
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {
+                            "get": {}
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let upgrade = |name: &str| {
+            if name == "upgrade" {
+                Some("WebSocket".to_string())
+            } else {
+                None
+            }
+        };
+
+        // Upgrade request on a websocket path
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/chat/lobby", &upgrade);
+        assert!(matched.as_ref().is_some_and(|matched| matched.websocket));
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.contains(&("x-oef-websocket".to_string(), "true".to_string())));
+        assert!(headers.contains(&("x-path-template".to_string(), "/chat/{room}".to_string())));
+
+        // Plain GET on the same path
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/chat/lobby", &no_headers);
+        assert!(matched.as_ref().is_some_and(|matched| !matched.websocket));
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.iter().all(|(name, _)| name != "x-oef-websocket"));
+
+        // Upgrade request on a path without websocket support falls back to the method
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &upgrade);
+        assert!(matched.as_ref().is_some_and(|matched| !matched.websocket));
+        assert_eq!(
+            matched.map(|matched| matched.template.to_string()),
+            Some("/users/{id}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_service_param_in_service_header() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "x-service-param": "id",
+                    "paths": {
+                        "/users/{id}": {},
+                        "/users": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.contains(&("x-service-name".to_string(), "userservice/42".to_string())));
+        assert!(headers.contains(&("x-path-template".to_string(), "/users/{id}".to_string())));
+
+        // Routes that do not capture the param emit the plain service name
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/users", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.contains(&("x-service-name".to_string(), "userservice".to_string())));
+    }
+
+    #[test]
+    fn test_match_precedence() {
+        // The longer base path group is tried first, but only has an any-method route
+        let config = |match_precedence: &str| {
+            json!({
+                "matchPrecedence": match_precedence,
+                "services": [
+                    {
+                        "name": "catchallservice",
+                        "servers": [
+                            { "url": "/api" }
+                        ],
+                        "paths": {
+                            "/users/{id}": {}
+                        }
+                    },
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/api/users/{id}": {
+                                "get": {}
+                            }
+                        }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config("group-first")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+            Some(("/users/{id}", "catchallservice"))
+        );
+
+        root_ctx.configure(&config("method-first")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+            Some(("/api/users/{id}", "userservice"))
+        );
+        // Without a method-specific match, any-method routes still apply
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "post", "/api/users/42", &no_headers)),
+            Some(("/users/{id}", "catchallservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx.configure(&config("random")).err().unwrap();
+        assert!(error.to_string().contains("'matchPrecedence' must be"));
+    }
+
+    #[test]
+    fn test_append_baggage() {
+        assert_eq!(
+            append_baggage(None, "userservice", "/users/{id}"),
+            Some("oef.route=userservice,oef.template=%2Fusers%2F%7Bid%7D".to_string())
+        );
+        assert_eq!(
+            append_baggage(
+                Some("userId=alice, isProduction=false;ttl=60"),
+                "userservice",
+                "/users"
+            ),
+            Some(
+                "userId=alice,isProduction=false;ttl=60,oef.route=userservice,oef.template=%2Fusers"
+                    .to_string()
+            )
+        );
+        // Entries from an earlier hop are replaced rather than duplicated
+        assert_eq!(
+            append_baggage(
+                Some("oef.route=old,userId=alice,oef.template=%2Fold"),
+                "userservice",
+                "/users"
+            ),
+            Some("userId=alice,oef.route=userservice,oef.template=%2Fusers".to_string())
+        );
+        // Size limits are respected
+        let large = format!("big={}", "x".repeat(8180));
+        assert_eq!(append_baggage(Some(&large), "userservice", "/users"), None);
+        let many = (0..179)
+            .map(|i| format!("k{}=v", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(append_baggage(Some(&many), "userservice", "/users"), None);
+    }
+
+    #[test]
+    fn test_decode_plus_in_path() {
+        let config = |decode_plus_in_path: bool| {
+            json!({
+                "decodePlusInPath": decode_plus_in_path,
+                "services": [
+                    {
+                        "name": "searchservice",
+                        "paths": {
+                            "/a+b": {},
+                            "/a%20c": {},
+                            "/search/{term}": {}
+                        }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/a+b", &no_headers)),
+            Some(("/a+b", "searchservice"))
+        );
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/search/a+b?q=c+d", &no_headers)
+            .unwrap();
+        assert_eq!(
+            matched.params,
+            vec![("term".to_string(), "a+b".to_string())]
+        );
+
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/a+b", &no_headers)),
+            None
+        );
+        // A decoded `+` matches the `%20` a template spells a space with
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/a+c", &no_headers)),
+            Some(("/a%20c", "searchservice"))
+        );
+        let matched = http_ctx
+            .router_set
+            .match_route(None, "get", "/search/a+b?q=c+d", &no_headers)
+            .unwrap();
+        assert_eq!(
+            matched.params,
+            vec![("term".to_string(), "a%20b".to_string())]
+        );
+
+        let options = NormalizeOptions {
+            decode_plus: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_path_with("/a+b?x=1+2", &options),
+            Some("/a%20b".to_string())
+        );
+        assert_eq!(normalize_path("/a+b"), "/a+b");
+    }
+
+    #[test]
+    fn test_trusted_hosts() {
+        let config = json!({
+            "trustedHosts": ["API.example.com:443"],
+            "services": [
+                {
+                    "name": "tenantservice",
+                    "servers": [
+                        { "url": "https://api.example.com" },
+                        { "url": "https://evil.example.com" }
+                    ],
+                    "paths": {
+                        "/users/{id}": {}
+                    }
+                },
+                {
+                    "name": "publicservice",
+                    "paths": {
+                        "/users/{id}": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("api.example.com"),
+                "get",
+                "/users/42",
+                &no_headers
+            )),
+            Some(("/users/{id}", "tenantservice"))
+        );
+        // An untrusted host cannot steer routing to its host-specific group
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("evil.example.com"),
+                "get",
+                "/users/42",
+                &no_headers
+            )),
+            Some(("/users/{id}", "publicservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "publicservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({ "trustedHosts": "api.example.com", "services": [] }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("'trustedHosts' must be an array"));
+    }
+
+    #[test]
+    fn test_nearest_template_debug_header() {
+        let config = json!({
+            "debugHeaders": true,
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [{ "url": "/api" }],
+                    "paths": {
+                        "/users/{id}": {},
+                        "/users/{id}/posts/{post_id}": {},
+                        "/orders": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42/xtra", &no_headers)),
+            None
+        );
+        assert_eq!(
+            http_ctx.miss_headers(None, "/api/users/42/xtra", &no_headers),
+            vec![("x-oef-nearest".to_string(), "/users/{id}".to_string())]
+        );
+        assert_eq!(
+            http_ctx.miss_headers(None, "/api/users/42/posts/7/xtra", &no_headers),
+            vec![(
+                "x-oef-nearest".to_string(),
+                "/users/{id}/posts/{post_id}".to_string()
+            )]
+        );
+        // Nothing in common, or outside every base path
+        assert!(http_ctx
+            .miss_headers(None, "/api/products/1", &no_headers)
+            .is_empty());
+        assert!(http_ctx
+            .miss_headers(None, "/other/users/42/xtra", &no_headers)
+            .is_empty());
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx
+            .miss_headers(None, "/users/42/xtra", &no_headers)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_service_header_on_response() {
+        let config = json!({
+            "emitServiceOnResponse": true,
+            "lowercaseServiceHeader": true,
+            "services": [
+                { "name": "UserService", "paths": { "/users/{id}": {} } }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        let mut http_ctx = root_ctx.create_filter();
+        let router_set = Rc::clone(&http_ctx.router_set);
+        let matched = router_set.match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        http_ctx.retain_for_response(matched.is_some(), &headers);
+        assert_eq!(
+            http_ctx.response_headers(),
+            vec![("x-service-name".to_string(), "userservice".to_string())]
+        );
+
+        // Unmatched requests leave the response untouched
+        let mut http_ctx = root_ctx.create_filter();
+        let matched = router_set.match_route(None, "get", "/orders", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        http_ctx.retain_for_response(matched.is_some(), &headers);
+        assert!(http_ctx.response_headers().is_empty());
+
+        // Disabled by default
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{ "name": "UserService", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        let router_set = Rc::clone(&http_ctx.router_set);
+        let matched = router_set.match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        http_ctx.retain_for_response(matched.is_some(), &headers);
+        assert!(http_ctx.response_headers().is_empty());
+    }
+
+    #[test]
+    fn test_min_specificity() {
+        let config = json!({
+            "minSpecificity": 2,
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/api/users/{id}": {},
+                        "/users/{id}": {}
+                    }
+                },
+                {
+                    "name": "catchall",
+                    "paths": {
+                        "/{anything}": {},
+                        "/{a}/{b}/{c}": {}
+                    },
+                    "prefixes": ["/static"]
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+            Some(("/api/users/{id}", "userservice"))
+        );
+        // Templates with fewer than two static segments are rejected
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42", &no_headers)),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/health", &no_headers)),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/x/y/z", &no_headers)),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/static/app.js", &no_headers)),
+            None
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({ "minSpecificity": 0, "services": [] }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("'minSpecificity' must be a positive integer"));
+    }
+
+    #[test]
+    fn test_match_fields() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [{ "url": "https://api.example.com/v1" }],
+                    "paths": {
+                        "/users/{id}/posts/{post_id}": { "get": {} },
+                        "/health": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx
+            .get_path_template(
+                Some("api.example.com"),
+                "get",
+                "/v1/users/42/posts/7",
+                &no_headers,
+            )
+            .unwrap();
+        assert_eq!(matched.template, "/users/{id}/posts/{post_id}");
+        assert_eq!(matched.service.as_str(), "userservice");
+        assert_eq!(matched.method, Some("get"));
+        assert_eq!(matched.base_path, "/v1");
+        assert_eq!(
+            matched.params,
+            vec![
+                ("id".to_string(), "42".to_string()),
+                ("post_id".to_string(), "7".to_string())
+            ]
+        );
+        assert!(!matched.websocket);
+
+        let matched = http_ctx
+            .get_path_template(Some("api.example.com"), "post", "/v1/health", &no_headers)
+            .unwrap();
+        assert_eq!(matched.template, "/health");
+        assert_eq!(matched.method, None);
+        assert!(matched.params.is_empty());
+    }
+
+    #[test]
+    fn test_strip_fragment() {
+        let config = |strip_fragment: bool| {
+            json!({
+                "stripFragment": strip_fragment,
+                "services": [
+                    { "name": "docservice", "paths": { "/docs/{name}": {} } }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx
+            .get_path_template(None, "get", "/docs/intro#usage", &no_headers)
+            .unwrap();
+        assert_eq!(
+            matched.params,
+            vec![("name".to_string(), "intro".to_string())]
+        );
+        // An encoded hash is part of the path, not a fragment
+        let matched = http_ctx
+            .get_path_template(None, "get", "/docs/c%23", &no_headers)
+            .unwrap();
+        assert_eq!(
+            matched.params,
+            vec![("name".to_string(), "c%23".to_string())]
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx
+            .get_path_template(None, "get", "/docs/c#sharp?x=1", &no_headers)
+            .unwrap();
+        assert_eq!(
+            matched.params,
+            vec![("name".to_string(), "c#sharp".to_string())]
+        );
+        assert_eq!(
+            normalize_path_with(
+                "/docs/a#b/c",
+                &NormalizeOptions {
+                    strip_fragment: false,
+                    ..Default::default()
+                }
+            ),
+            Some("/docs/a#b/c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_timeout_hint_header() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "reportservice",
+                    "paths": {
+                        "/reports/{id}/export": { "x-timeout-ms": 30000, "get": {} },
+                        "/reports/{id}": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx.get_path_template(None, "get", "/reports/1/export", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.contains(&("x-oef-timeout-ms".to_string(), "30000".to_string())));
+
+        let matched = http_ctx.get_path_template(None, "get", "/reports/1", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert!(headers.iter().all(|(name, _)| name != "x-oef-timeout-ms"));
+
+        for invalid in [json!(0), json!(-5), json!("30000"), json!(1.5)] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx
+                .configure(&json!({
+                    "services": [{
+                        "name": "reportservice",
+                        "paths": { "/reports": { "x-timeout-ms": invalid } }
+                    }]
+                }))
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains("Invalid 'x-timeout-ms' for path '/reports'"));
+        }
+    }
+
+    #[test]
+    fn test_encoded_template_header() {
+        let config = |encoding: &str| {
+            json!({
+                "encodeTemplateHeader": encoding,
+                "services": [
+                    { "name": "userservice", "paths": { "/users/{id}": {} } }
+                ]
+            })
+        };
+        let template_header = |encoding: &str| {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.configure(&config(encoding)).unwrap();
+            let http_ctx = root_ctx.create_filter();
+            let matched = http_ctx.get_path_template(None, "get", "/users/42", &no_headers);
+            let headers = http_ctx.route_headers("GET", matched.as_ref());
+            headers
+                .into_iter()
+                .find(|(name, _)| name == "x-path-template")
+                .map(|(_, value)| value)
+        };
+
+        assert_eq!(template_header("none"), Some("/users/{id}".to_string()));
+        assert_eq!(
+            template_header("hex"),
+            Some("2F75736572732F7B69647D".to_string())
+        );
+        assert_eq!(
+            template_header("base64"),
+            Some("L3VzZXJzL3tpZH0=".to_string())
+        );
+
+        assert_eq!(TemplateEncoding::Base64.encode("/a"), "L2E=");
+        assert_eq!(TemplateEncoding::Base64.encode("/ab"), "L2Fi");
+        assert_eq!(TemplateEncoding::Base64.encode(""), "");
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx.configure(&config("rot13")).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("'encodeTemplateHeader' must be 'none', 'hex' or 'base64'"));
+    }
+
+    #[test]
+    fn test_encoded_query_delimiter_is_path_character() {
+        let config = json!({
+            "decodePlusInPath": true,
+            "services": [
+                { "name": "searchservice", "paths": { "/search/{term}": {} } }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx
+            .get_path_template(None, "get", "/search/a+b%3Fq%3Dx?page=2", &no_headers)
+            .unwrap();
+        assert_eq!(matched.template, "/search/{term}");
+        assert_eq!(
+            matched.params,
+            vec![("term".to_string(), "a%20b%3Fq%3Dx".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_enabled_kill_switch() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.load_configuration(
+            TEST_CONFIG
+                .replace("\"cacheSize\": 5", "\"enabled\": false")
+                .into_bytes(),
+        );
+        assert!(root_ctx.configured);
+        // Disabled contexts return before reading or setting any header
+        let http_ctx = root_ctx.create_filter();
+        assert!(!http_ctx.enabled);
+
+        // Also honored when the rest of the configuration is invalid
+        root_ctx.load_configuration(
+            br#"{ "enabled": false, "failClosed": true, "services": [] }"#.to_vec(),
+        );
+        assert!(!root_ctx.configured);
+        let http_ctx = root_ctx.create_filter();
+        assert!(!http_ctx.enabled);
+
+        // Re-enabled on the next configuration
+        root_ctx.load_configuration(TEST_CONFIG.as_bytes().to_vec());
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx.enabled);
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users", &no_headers)),
+            Some(("/users", "userservice"))
+        );
+    }
+
+    #[test]
+    fn test_audit_log_sampling() {
+        let config = json!({
+            "auditSampleRate": 3,
+            "services": [
+                { "name": "userservice", "paths": { "/users/{id}": {} } }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+
+        // The audit counter is shared by all contexts and counts every request
+        let lines: Vec<Option<String>> = (0..7)
+            .map(|i| {
+                let http_ctx = root_ctx.create_filter();
+                let path = if i % 2 == 0 {
+                    "/users/42?token=secret"
+                } else {
+                    "/orders"
+                };
+                let matched =
+                    http_ctx.get_path_template(Some("api.example.com"), "get", path, &no_headers);
+                http_ctx.audit_line(Some("api.example.com"), "get", path, matched.as_ref())
+            })
+            .collect();
+        let sampled: Vec<bool> = lines.iter().map(Option::is_some).collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false, true]);
+        assert_eq!(root_ctx.router_set.miss_log_sampler.count(), 3);
+        assert_eq!(
+            lines[0].as_deref(),
+            Some("[oef] audit host=api.example.com method=get path=/users/42 template=/users/{id} service=userservice decision=matched")
+        );
+        assert_eq!(
+            lines[3].as_deref(),
+            Some("[oef] audit host=api.example.com method=get path=/orders template=- service=- decision=unmatched")
+        );
+
+        // Disabled by default
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.audit_line(None, "get", "/users", None), None);
+    }
+
+    #[test]
+    fn test_base_path_match_modes() {
+        let config = |mode: &str| {
+            json!({
+                "basePathMatch": mode,
+                "services": [
+                    {
+                        "name": "apiservice",
+                        "servers": [{ "url": "/api" }],
+                        "paths": {
+                            "/users": {},
+                            "/v1/users": {}
+                        }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config("segment")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users", &no_headers)),
+            Some(("/users", "apiservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/apiv1/users", &no_headers)),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/apiusers", &no_headers)),
+            None
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config("prefix")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users", &no_headers)),
+            Some(("/users", "apiservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/apiv1/users", &no_headers)),
+            Some(("/v1/users", "apiservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/apiusers", &no_headers)),
+            Some(("/users", "apiservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/ap/users", &no_headers)),
+            None
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx.configure(&config("exact")).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("'basePathMatch' must be 'segment' or 'prefix'"));
+    }
+
+    #[test]
+    fn test_request_id_generation() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "ensureRequestId": true,
+                "services": [{ "name": "userservice", "paths": { "/users": {} } }]
+            }))
+            .unwrap();
+        let request_ids = root_ctx.settings.request_ids.as_ref().unwrap();
+
+        // Same entropy still yields distinct ids thanks to the shared counter
+        let first = request_ids.next(1_700_000_000_000_000_000);
+        let second = request_ids.next(1_700_000_000_000_000_000);
+        assert_ne!(first, second);
+        for id in [&first, &second] {
+            let groups: Vec<&str> = id.split('-').collect();
+            assert_eq!(
+                groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+                vec![8, 4, 4, 4, 12]
+            );
+            assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+            assert!(groups[2].starts_with('4'));
+            assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+        }
+
+        // Disabled by default, so existing ids are never touched
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        assert!(root_ctx.settings.request_ids.is_none());
+    }
+
+    #[test]
+    fn test_service_template_style() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "templateStyle": "colon",
+                    "paths": { "/users/{id}": {} }
+                },
+                {
+                    "name": "fileservice",
+                    "templateStyle": "regex",
+                    "paths": { "/files/{name}.json": {} },
+                    "prefixes": ["/static"]
+                },
+                {
+                    "name": "orderservice",
+                    "paths": { "/orders/{id}": {} }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let emitted = |path: &str| {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers);
+            // Matching itself still reports the OpenAPI template
+            assert!(matched.as_ref().unwrap().template.starts_with('/'));
+            let headers = http_ctx.route_headers("GET", matched.as_ref());
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(header, _)| header == name)
+                    .map(|(_, value)| value.clone())
+                    .unwrap()
+            };
+            (header("x-path-template"), header("x-api-endpoint"))
+        };
+
+        assert_eq!(
+            emitted("/users/42"),
+            ("/users/:id".to_string(), "GET /users/:id".to_string())
+        );
+        assert_eq!(
+            emitted("/files/report.json").0,
+            "^/files/[^/]+\\.json$".to_string()
+        );
+        assert_eq!(emitted("/static/app.js").0, "^/static/.*$".to_string());
+        assert_eq!(emitted("/orders/7").0, "/orders/{id}".to_string());
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "userservice",
+                    "templateStyle": "express",
+                    "paths": { "/users/{id}": {} }
+                }]
+            }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("Service 'templateStyle' must be 'openapi', 'colon' or 'regex'"));
+    }
+
+    #[test]
+    fn test_max_headers_degradation() {
+        let config = json!({
+            "maxHeaders": 100,
+            "services": [
+                {
+                    "name": "adminservice",
+                    "paths": { "/users/{id}": { "get": {}, "x-require-header": "x-admin" } }
+                },
+                {
+                    "name": "userservice",
+                    "paths": { "/users/{id}": {} }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        assert!(!http_ctx.exceeds_max_headers(100));
+        assert!(http_ctx.exceeds_max_headers(5000));
+
+        // Routing inputs are still read, only optional features are skipped
+        let config = json!({
+            "maxHeaders": 100,
+            "emitBaggage": true,
+            "services": config["services"]
+        });
+        let request = [
+            (":path", "/users/42"),
+            (":method", "GET"),
+            ("x-admin", "1"),
+            ("baggage", "k=v"),
+        ];
+        for (nheaders, baggage) in [
+            (
+                4,
+                "k=v,oef.route=adminservice,oef.template=%2Fusers%2F%7Bid%7D",
+            ),
+            (5000, "k=v"),
+        ] {
+            let (_, action) = run_request_headers(&config, &request, nheaders);
+            assert_eq!(action, Action::Continue);
+            assert_eq!(
+                host::request_header("x-service-name").as_deref(),
+                Some("adminservice")
+            );
+            assert_eq!(host::request_header("baggage").as_deref(), Some(baggage));
+        }
+
+        // Unlimited by default
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        assert!(!root_ctx.create_filter().exceeds_max_headers(usize::MAX));
+    }
+
+    #[test]
+    fn test_routing_headers_as_trailers() {
+        let config = json!({
+            "emitAsTrailer": true,
+            "services": [
+                { "name": "userservice", "paths": { "/users/{id}": {} } }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        let router_set = Rc::clone(&http_ctx.router_set);
+
+        let matched = router_set.match_route(None, "get", "/users/42", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        let headers = http_ctx.defer_to_trailers(headers);
+        assert_eq!(
+            headers,
+            vec![("x-api-endpoint".to_string(), "GET /users/{id}".to_string())]
+        );
+        assert_eq!(
+            http_ctx.response_trailers,
+            vec![
+                ("x-service-name".to_string(), "userservice".to_string()),
+                ("x-openapi-path".to_string(), "/users/{id}".to_string())
+            ]
+        );
+
+        // Disabled by default: everything stays a request header
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        let router_set = Rc::clone(&http_ctx.router_set);
+        let matched = router_set.match_route(None, "get", "/users", &no_headers);
+        let headers = http_ctx.route_headers("GET", matched.as_ref());
+        assert_eq!(http_ctx.defer_to_trailers(headers.clone()), headers);
+        assert!(http_ctx.response_trailers.is_empty());
+    }
+
+    #[test]
+    fn test_trailers_without_response_trailers() {
+        let config = json!({
+            "emitAsTrailer": true,
+            "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+        });
+        let request = [(":path", "/users/42"), (":method", "GET")];
+        let respond = |content_type: &str| {
+            host::with(|exchange| {
+                exchange.response_headers =
+                    vec![("content-type".to_string(), content_type.to_string())]
+            })
+        };
+        let trailers = || host::with(|exchange| exchange.response_trailers.clone());
+        let routing = vec![
+            ("x-service-name".to_string(), "userservice".to_string()),
+            ("x-openapi-path".to_string(), "/users/{id}".to_string()),
+        ];
+
+        // gRPC responses end with trailers, so the routing ones wait for them
+        let (mut http_ctx, _) = run_request_headers(&config, &request, 2);
+        assert_eq!(host::request_header("x-service-name"), None);
+        respond("application/grpc+proto");
+        http_ctx.on_http_response_headers(1, false);
+        http_ctx.on_http_response_body(10, false);
+        http_ctx.on_http_response_trailers(1);
+        assert_eq!(host::response_header("x-service-name"), None);
+        assert_eq!(trailers(), routing);
+
+        // Other responses, and any without a body, carry them as headers
+        for (content_type, end_of_stream) in
+            [("application/json", false), ("application/grpc", true)]
+        {
+            let (mut http_ctx, _) = run_request_headers(&config, &request, 2);
+            respond(content_type);
+            http_ctx.on_http_response_headers(1, end_of_stream);
+            http_ctx.on_http_response_body(10, true);
+            for (name, value) in &routing {
+                assert_eq!(host::response_header(name).as_ref(), Some(value));
+            }
+            assert!(trailers().is_empty());
+        }
+
+        // A gRPC body that ends without trailers leaves no place for them
+        let (mut http_ctx, _) = run_request_headers(&config, &request, 2);
+        respond("application/grpc");
+        http_ctx.on_http_response_headers(1, false);
+        http_ctx.on_http_response_body(10, true);
+        assert!(http_ctx.response_trailers.is_empty());
+        assert_eq!(host::response_header("x-service-name"), None);
+        assert!(trailers().is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_method_token() {
+        let config = json!({
+            "exposeSummary": true,
+            "services": [
+                {
+                    "name": "proxyservice",
+                    "paths": {
+                        "/proxy/{target}": {
+                            "*": { "summary": "Proxy any method" },
+                            "delete": { "summary": "Forbidden delete" }
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        for method in ["get", "post", "purge", "propfind"] {
+            let matched = http_ctx
+                .get_path_template(None, method, "/proxy/a", &no_headers)
+                .unwrap();
+            assert_eq!(matched.template, "/proxy/{target}");
+            assert_eq!(matched.method, None);
+            assert_eq!(matched.route.summary.as_deref(), Some("Proxy any method"));
+        }
+        // An explicit method still takes precedence over "*"
+        let matched = http_ctx
+            .get_path_template(None, "delete", "/proxy/a", &no_headers)
+            .unwrap();
+        assert_eq!(matched.method, Some("delete"));
+        assert_eq!(matched.route.summary.as_deref(), Some("Forbidden delete"));
+    }
+
+    #[test]
+    fn test_prefer_longest_template() {
+        let config = |prefer_longest: bool| {
+            json!({
+                "preferLongestTemplate": prefer_longest,
+                "services": [
+                    {
+                        "name": "tenantservice",
+                        "servers": [{ "url": "/api" }],
+                        "paths": { "/{tenant}/{resource}": {} }
+                    },
+                    {
+                        "name": "userservice",
+                        "paths": { "/api/users/{id}": {} }
+                    }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        // The longer base path is tried first
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+            Some(("/{tenant}/{resource}", "tenantservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+            Some(("/api/users/{id}", "userservice"))
+        );
+        // With equal lengths the usual precedence still decides
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/a/b", &no_headers)),
+            Some(("/{tenant}/{resource}", "tenantservice"))
+        );
+    }
+
+    #[test]
+    fn test_shadow_services() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": { "/users/{id}": {}, "/orders": {} }
+                }
+            ],
+            "shadowServices": [
+                {
+                    "name": "userservice-v2",
+                    "paths": { "/users/{user_id}": {} }
+                },
+                {
+                    "name": "orderservice",
+                    "paths": { "/orders": {} }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        // The primary result is what gets used
+        let matched = http_ctx.get_path_template(None, "get", "/users/42", &no_headers);
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "userservice"))
+        );
+        assert_eq!(
+            http_ctx.shadow_divergence(None, "get", "/users/42?x=1", &no_headers, matched.as_ref()),
+            Some(
+                "[oef] shadow divergence for get /users/42: primary=userservice /users/{id}, shadow=userservice-v2 /users/{user_id}"
+                    .to_string()
+            )
+        );
+
+        let matched = http_ctx.get_path_template(None, "get", "/health", &no_headers);
+        assert_eq!(
+            http_ctx.shadow_divergence(None, "get", "/health", &no_headers, matched.as_ref()),
+            None
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{ "name": "orderservice", "paths": { "/orders": {} } }],
+                "shadowServices": [{ "name": "orderservice", "paths": { "/orders": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx.get_path_template(None, "get", "/orders", &no_headers);
+        assert_eq!(
+            http_ctx.shadow_divergence(None, "get", "/orders", &no_headers, matched.as_ref()),
+            None
+        );
+
+        // Invalid shadow services fail the whole configuration
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({
+                "services": [{ "name": "orderservice", "paths": { "/orders": {} } }],
+                "shadowServices": []
+            }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("'shadowServices' must be a non-empty array"));
+        assert!(root_ctx.shadow_router_set.is_none());
+    }
+
+    #[test]
+    fn test_resolve_dot_segments() {
+        let options = NormalizeOptions {
+            resolve_dot_segments: true,
+            ..Default::default()
+        };
+        let cases = [
+            ("/a/../b", "/b"),
+            ("/./a", "/a"),
+            ("/../a", "/a"),
+            ("/../../users/42", "/users/42"),
+            ("/users/42/./profile/..", "/users/42"),
+            ("/a/..", "/"),
+            ("/a/..b/.c", "/a/..b/.c"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                normalize_path_with(input, &options),
+                Some(expected.to_string()),
+                "{}",
+                input
+            );
+        }
+        // Off by default
+        assert_eq!(normalize_path("/a/../b"), "/a/../b");
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "resolveDotSegments": true,
+                "services": [
+                    { "name": "publicservice", "paths": { "/public/{file}": {} } },
+                    { "name": "adminservice", "paths": { "/admin/{file}": {} } }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/public/../admin/keys", &no_headers)),
+            Some(("/admin/{file}", "adminservice"))
+        );
+    }
+
+    #[test]
+    fn test_metadata_properties() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "emitMetadata": true,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx.get_path_template(None, "get", "/users/42", &no_headers);
+        assert_eq!(
+            http_ctx.metadata_properties(matched.as_ref()),
+            vec![
+                (vec!["oef.service"], "userservice".to_string()),
+                (vec!["oef.template"], "/users/{id}".to_string())
+            ]
+        );
+        assert!(http_ctx.metadata_properties(None).is_empty());
+
+        // Off by default
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx.get_path_template(None, "get", "/users", &no_headers);
+        assert!(http_ctx.metadata_properties(matched.as_ref()).is_empty());
+    }
+
+    #[test]
+    fn test_service_catch_all() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "tenantservice",
+                    "servers": [{ "url": "https://tenant.example.com" }],
+                    "paths": { "/users/{id}": {} }
+                },
+                {
+                    "name": "tenantfallback",
+                    "servers": [{ "url": "https://tenant.example.com" }],
+                    "catchAll": true
+                },
+                {
+                    "name": "publicservice",
+                    "paths": { "/health": {} }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let tenant = Some("tenant.example.com");
+
+        // Specific routes in the same group still win
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(tenant, "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "tenantservice"))
+        );
+        for path in ["/", "/users", "/anything/else/entirely", "/health"] {
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(tenant, "post", path, &no_headers)),
+                Some(("/*", "tenantfallback")),
+                "{}",
+                path
+            );
+        }
+        // Other hosts are not affected
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                Some("other.example.com"),
+                "get",
+                "/users/42",
+                &no_headers
+            )),
+            None
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/health", &no_headers)),
+            Some(("/health", "publicservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({
+                "services": [{ "name": "fallback", "catchAll": "yes" }]
+            }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("Invalid 'catchAll' in service configuration"));
+    }
+
+    #[test]
+    fn test_bucket_header() {
+        let config = |bucketing: Value| {
+            json!({
+                "bucketing": bucketing,
+                "services": [
+                    { "name": "userservice", "paths": { "/users/{id}": {}, "/orders": {} } }
+                ]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&config(json!({ "header": "X-User-Id" })))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let bucket_for = |user: &str, path: &str| {
+            let user = user.to_string();
+            let get_header = move |name: &str| (name == "x-user-id").then(|| user.clone());
+            let matched = http_ctx.get_path_template(None, "get", path, &get_header);
+            http_ctx
+                .bucket_header(matched.as_ref(), &get_header)
+                .map(|(_, bucket)| bucket.parse::<u64>().unwrap())
+        };
+
+        // Deterministic per user and route, and independent of path params
+        assert_eq!(
+            bucket_for("alice", "/users/1"),
+            bucket_for("alice", "/users/2")
+        );
+        assert!(bucket_for("alice", "/users/1").unwrap() < 100);
+
+        // Roughly uniform over many users
+        let mut counts = [0u32; 100];
+        for user in 0..10_000 {
+            let bucket = bucket_for(&format!("user-{}", user), "/orders").unwrap();
+            counts[bucket as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| (50..=150).contains(count)));
+
+        // No attribute or no match, no bucket
+        let matched = http_ctx.get_path_template(None, "get", "/orders", &no_headers);
+        assert_eq!(http_ctx.bucket_header(matched.as_ref(), &no_headers), None);
+        assert_eq!(
+            http_ctx.bucket_header(None, &|_| Some("alice".to_string())),
+            None
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&config(json!({ "cookie": "uid", "buckets": 10 })))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let with_cookie =
+            |name: &str| (name == "cookie").then(|| "theme=dark; uid=alice".to_string());
+        let matched = http_ctx.get_path_template(None, "get", "/orders", &with_cookie);
+        let bucket = http_ctx
+            .bucket_header(matched.as_ref(), &with_cookie)
+            .unwrap();
+        let expected =
+            root_ctx
+                .settings
+                .bucketing
+                .as_ref()
+                .unwrap()
+                .bucket("alice", "userservice", "/orders");
+        assert_eq!(bucket, ("x-oef-bucket".to_string(), expected.to_string()));
+        assert!(expected < 10);
+
+        for invalid in [
+            json!({}),
+            json!({ "header": "x-user-id", "cookie": "uid" }),
+            json!({ "header": "" }),
+            json!({ "header": "x-user-id", "buckets": 0 }),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            assert!(root_ctx.configure(&config(invalid)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_base_path_exclusive() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "billingservice",
+                    "servers": [{ "url": "/api/billing" }],
+                    "basePathExclusive": true,
+                    "paths": { "/invoices/{id}": {} }
+                },
+                {
+                    "name": "legacyservice",
+                    "paths": { "/api/billing/reports": {}, "/api/users": {} }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(
+                None,
+                "get",
+                "/api/billing/invoices/7",
+                &no_headers
+            )),
+            Some(("/invoices/{id}", "billingservice"))
+        );
+        // Inner misses stay with the exclusive service instead of falling through
+        let matched = http_ctx
+            .get_path_template(None, "get", "/api/billing/reports", &no_headers)
+            .unwrap();
+        assert_eq!(
+            (
+                matched.template,
+                matched.service.as_str(),
+                matched.base_path
+            ),
+            ("/*", "billingservice", "/api/billing")
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/billing", &no_headers)),
+            Some(("/*", "billingservice"))
+        );
+        // Outside the base path nothing changes
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/api/users", &no_headers)),
+            Some(("/api/users", "legacyservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&json!({
+                "services": [
+                    {
+                        "name": "a",
+                        "servers": [{ "url": "/api" }],
+                        "basePathExclusive": true,
+                        "paths": { "/a": {} }
+                    },
+                    {
+                        "name": "b",
+                        "servers": [{ "url": "/api" }],
+                        "basePathExclusive": true,
+                        "paths": { "/b": {} }
+                    }
+                ]
+            }))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("Base path '/api' is already exclusive to service 'a'"));
+    }
+
+    #[test]
+    fn test_numeric_string_options() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "maxPathSegments": "2",
+                "missLogSampleRate": " 256 ",
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "userservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42/x", &no_headers)),
+            None
+        );
+
+        for invalid in ["abc", "", "-1", "0", "1.5"] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx
+                .configure(&json!({
+                    "missLogSampleRate": invalid,
+                    "services": [{ "name": "userservice", "paths": { "/users": {} } }]
+                }))
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains("'missLogSampleRate' must be a positive integer"));
+        }
+    }
+
+    #[test]
+    fn test_param_annotated_path_header() {
+        let config = json!({
+            "paramAnnotatedPath": true,
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [{ "url": "/api" }],
+                    "paths": {
+                        "/users/{id}/posts/{post_id}": {},
+                        "/files/{name}.pdf": {},
+                        "/users": {}
+                    }
+                }
+            ]
+        });
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let annotated = |path: &str| {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers);
+            http_ctx
+                .route_headers("GET", matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-annotated-path")
+                .map(|(_, value)| value)
+        };
+
+        assert_eq!(
+            annotated("/api/users/42/posts/7?x=1"),
+            Some("/api/users/42[id]/posts/7[post_id]".to_string())
+        );
+        assert_eq!(
+            annotated("/api/files/report.pdf"),
+            Some("/api/files/report[name].pdf".to_string())
+        );
+        assert_eq!(annotated("/api/users"), Some("/api/users".to_string()));
+        assert_eq!(annotated("/unknown"), None);
+    }
+
+    #[test]
+    fn test_path_sources_and_conflict_policy() {
+        let filter = |policy: &str| {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx
+                .configure(&json!({
+                    "pathSource": ["X-Envoy-Original-Path", ":path"],
+                    "pathConflictPolicy": policy,
+                    "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+                }))
+                .unwrap();
+            root_ctx.create_filter()
+        };
+        let headers = |original: Option<&'static str>, path: &'static str| {
+            move |name: &str| match name {
+                "x-envoy-original-path" => original.map(str::to_string),
+                ":path" => Some(path.to_string()),
+                _ => None,
+            }
+        };
+
+        let agreeing = headers(Some("/users/42"), "/users/42");
+        let equivalent = headers(Some("/users//42?a=1"), "/users/42");
+        let disagreeing = headers(Some("/users/42"), "/admin/42");
+        let single = headers(None, "/users/42");
+
+        let http_ctx = filter("first");
+        assert_eq!(
+            http_ctx.resolve_path(&agreeing).as_deref(),
+            Some("/users/42")
+        );
+        assert_eq!(
+            http_ctx.resolve_path(&disagreeing).as_deref(),
+            Some("/users/42")
+        );
+        assert_eq!(http_ctx.resolve_path(&single).as_deref(), Some("/users/42"));
+
+        let http_ctx = filter("reject");
+        assert_eq!(
+            http_ctx.resolve_path(&agreeing).as_deref(),
+            Some("/users/42")
+        );
+        assert_eq!(http_ctx.resolve_path(&equivalent), None);
+        assert_eq!(http_ctx.resolve_path(&disagreeing), None);
+        assert_eq!(http_ctx.resolve_path(&single).as_deref(), Some("/users/42"));
+
+        let http_ctx = filter("canonical");
+        assert_eq!(
+            http_ctx.resolve_path(&agreeing).as_deref(),
+            Some("/users/42")
+        );
+        assert_eq!(
+            http_ctx.resolve_path(&equivalent).as_deref(),
+            Some("/users//42?a=1")
+        );
+        assert_eq!(http_ctx.resolve_path(&disagreeing), None);
+
+        // The default reads :path only
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            http_ctx.resolve_path(&disagreeing).as_deref(),
+            Some("/admin/42")
+        );
+        assert_eq!(http_ctx.resolve_path(&no_headers).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_routing_key_header() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "routingKey": "header:X-Tenant",
+                "services": [
+                    {
+                        "name": "tenant-a-users",
+                        "routingValue": "Tenant-A",
+                        "servers": [{ "url": "https://ignored.example.com" }],
+                        "paths": { "/users/{id}": {} }
+                    },
+                    {
+                        "name": "tenant-b-users",
+                        "routingValue": "tenant-b",
+                        "paths": { "/users/{id}": {} }
+                    },
+                    { "name": "shared", "paths": { "/users/{id}": {}, "/health": {} } }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let tenant =
+            |value: &'static str| move |name: &str| (name == "x-tenant").then(|| value.to_string());
+
+        let service = |host: Option<&str>, get_header: &dyn Fn(&str) -> Option<String>| {
+            http_ctx
+                .get_path_template(host, "get", "/users/42", get_header)
+                .map(|m| m.service.to_string())
+        };
+        assert_eq!(
+            service(None, &tenant("tenant-a")).as_deref(),
+            Some("tenant-a-users")
+        );
+        assert_eq!(
+            service(None, &tenant(" TENANT-B ")).as_deref(),
+            Some("tenant-b-users")
+        );
+        // Unknown or missing tenants fall back to groups without a routingValue
+        assert_eq!(
+            service(None, &tenant("tenant-c")).as_deref(),
+            Some("shared")
+        );
+        assert_eq!(service(None, &no_headers).as_deref(), Some("shared"));
+        // The host no longer selects groups
+        assert_eq!(
+            service(Some("ignored.example.com"), &no_headers).as_deref(),
+            Some("shared")
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/health", &tenant("tenant-a"))),
+            Some(("/health", "shared"))
+        );
+    }
+
+    #[test]
+    fn test_routing_key_header_over_max_headers() {
+        let config = json!({
+            "maxHeaders": 10,
+            "routingKey": "header:x-tenant",
+            "services": [
+                { "name": "tenant-a-users", "routingValue": "tenant-a", "paths": { "/users/{id}": {} } },
+                { "name": "shared", "paths": { "/users/{id}": {} } }
+            ]
+        });
+        let request = [
+            (":path", "/users/42"),
+            (":method", "GET"),
+            ("x-tenant", "tenant-a"),
+        ];
+
+        // The routing key is a routing input, so it is read however many headers there are
+        for nheaders in [3, 5000] {
+            let (_, action) = run_request_headers(&config, &request, nheaders);
+            assert_eq!(action, Action::Continue);
+            assert_eq!(
+                host::request_header("x-service-name").as_deref(),
+                Some("tenant-a-users")
+            );
+        }
+    }
+
+    #[test]
+    fn test_routing_key_validation() {
+        for routing_key in [json!("header:"), json!("tenant"), json!(1)] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let result = root_ctx.configure(&json!({
+                "routingKey": routing_key,
+                "services": [{ "name": "userservice", "paths": { "/users": {} } }]
+            }));
+            assert!(result.is_err(), "{} should be rejected", routing_key);
+        }
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let result = root_ctx.configure(&json!({
+            "routingKey": "header:x-tenant",
+            "services": [{ "name": "userservice", "routingValue": "", "paths": { "/users": {} } }]
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_query_method() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "searchservice",
+                    "paths": { "/search": { "QUERY": {} }, "/items": { "get": {} } }
+                }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let route = http_ctx.get_path_template(None, "query", "/search", &no_headers);
+        assert_eq!(route.as_ref().and_then(|m| m.method), Some("query"));
+        let headers = http_ctx.route_headers("QUERY", route.as_ref());
+        assert!(headers.contains(&("x-api-endpoint".to_string(), "QUERY /search".to_string())));
+        assert!(http_ctx
+            .get_path_template(None, "post", "/search", &no_headers)
+            .is_none());
+        assert!(http_ctx
+            .get_path_template(None, "query", "/items", &no_headers)
+            .is_none());
+    }
+
+    #[test]
+    fn test_extra_methods() {
+        let config = |extra_methods: Value| {
+            json!({
+                "extraMethods": extra_methods,
+                "services": [{
+                    "name": "davservice",
+                    "paths": { "/files/{name}": { "propfind": {}, "get": {} } }
+                }]
+            })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(json!(["PROPFIND"]))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "propfind", "/files/a", &no_headers)),
+            Some(("/files/{name}", "davservice"))
+        );
+        assert!(http_ctx
+            .get_path_template(None, "post", "/files/a", &no_headers)
+            .is_none());
+
+        // Without extraMethods the unknown key is ignored like any extension field
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(json!([]))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx
+            .get_path_template(None, "propfind", "/files/a", &no_headers)
+            .is_none());
+
+        for invalid in [
+            json!("propfind"),
+            json!([""]),
+            json!(["prop find"]),
+            json!([1]),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            assert!(root_ctx.configure(&config(invalid)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_unmatched_body_placeholders() {
+        let filter = |config: Value| {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.configure(&config).unwrap();
+            root_ctx.create_filter()
+        };
+        let services = json!([{ "name": "userservice", "paths": { "/users/{id}": {} } }]);
+
+        let http_ctx = filter(json!({
+            "rejectUnmatched": true,
+            "unmatchedBody": "no route for {method} {host}{path} {unknown}",
+            "services": services
+        }));
+        assert_eq!(
+            http_ctx.unmatched_body("GET", Some("api.example.com"), "/nope?token=secret"),
+            Some("no route for GET api.example.com/nope {unknown}".to_string())
+        );
+        assert_eq!(
+            http_ctx.unmatched_body("GET", None, "/nope"),
+            Some("no route for GET /nope {unknown}".to_string())
+        );
+
+        // Values are escaped for JSON content types
+        let http_ctx = filter(json!({
+            "rejectUnmatched": true,
+            "unmatchedBody": "{\"error\":\"not found\",\"path\":\"{path}\"}",
+            "unmatchedContentType": "application/json",
+            "services": services
+        }));
+        let body = http_ctx.unmatched_body("GET", None, "/a\"b\\c").unwrap();
+        assert_eq!(body, "{\"error\":\"not found\",\"path\":\"/a\\\"b\\\\c\"}");
+        let parsed: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["path"], "/a\"b\\c");
+
+        // No body without a template, and nothing without rejectUnmatched
+        let http_ctx = filter(json!({ "rejectUnmatched": true, "services": services }));
+        assert_eq!(http_ctx.unmatched_body("GET", None, "/nope"), None);
+        let http_ctx = filter(json!({ "unmatchedBody": "{path}", "services": services }));
+        assert_eq!(http_ctx.unmatched_body("GET", None, "/nope"), None);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let result = root_ctx.configure(&json!({ "unmatchedBody": 1, "services": services }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        use crate::headers::{base64_decode, base64_encode};
+
+        for (bytes, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"\xff\xfe\x00", "//4A"),
+        ] {
+            assert_eq!(base64_encode(bytes), encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(bytes));
+        }
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+
+        // Padding may be left out, and wrapped lines are joined
+        assert_eq!(base64_decode("Zg").as_deref(), Some(&b"f"[..]));
+        assert_eq!(
+            base64_decode("Zm9v\nZm8=\n").as_deref(),
+            Some(&b"foofo"[..])
+        );
+        for invalid in [
+            "Zg=", "Zg===", "Z===", "Zg==Zg==", "Zm9=v", "Zm9v=", "Zm*v", "Z", "Zh==",
+        ] {
+            assert_eq!(base64_decode(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_gzip_configuration() {
+        use crate::headers::{base64_decode, base64_encode};
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(TEST_CONFIG.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let wrapped = json!({ "configGzipB64": base64_encode(&gzipped) }).to_string();
+        assert_eq!(
+            base64_decode(&base64_encode(&gzipped)),
+            Some(gzipped.clone())
+        );
+
+        for config_bytes in [gzipped.clone(), wrapped.into_bytes()] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.load_configuration(config_bytes);
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(http_ctx.config_error, None);
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/dockebi/v1/stuff", &no_headers)),
+                Some(("/dockebi/v1/stuff", "dockebi"))
+            );
+        }
+
+        let truncated = gzipped[..gzipped.len() / 2].to_vec();
+        for config_bytes in [
+            truncated,
+            br#"{ "configGzipB64": "not base64!" }"#.to_vec(),
+            br#"{ "configGzipB64": "aGVsbG8=" }"#.to_vec(),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.load_configuration(config_bytes);
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_GZIP"));
+        }
+
+        // The decompressed size may be four times maxConfigBytes
+        let padded = format!("{}{}", TEST_CONFIG, " ".repeat(20 * 1024 * 1024));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(padded.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        for (vm_config, error) in [
+            (&b"{}"[..], Some("ERR_GZIP")),
+            (br#"{ "maxConfigBytes": 8388608 }"#, None),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.load_vm_configuration(vm_config);
+            root_ctx.load_configuration(gzipped.clone());
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(http_ctx.config_error.as_deref(), error);
+        }
+    }
+
+    #[test]
+    fn test_preserve_trailing_slash() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "itemservice",
+                    "paths": {
+                        "/items/": { "get": {}, "x-preserve-trailing-slash": true },
+                        "/items": { "get": {} },
+                        "/items/{id}": { "get": {} },
+                        "/collections/{id}/": { "x-preserve-trailing-slash": true },
+                        "/users/": { "get": {} }
+                    }
+                }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let template = |path: &str| {
+            http_ctx
+                .get_path_template(None, "get", path, &no_headers)
+                .map(|m| m.template.to_string())
+        };
+
+        // The preserved route and its slash-less sibling coexist
+        assert_eq!(template("/items/").as_deref(), Some("/items/"));
+        assert_eq!(template("/items/?page=2").as_deref(), Some("/items/"));
+        assert_eq!(template("/items").as_deref(), Some("/items"));
+        // Other routes keep the usual normalization
+        assert_eq!(template("/items/42/").as_deref(), Some("/items/{id}"));
+        assert_eq!(template("/users/").as_deref(), Some("/users"));
+        assert_eq!(template("/users").as_deref(), Some("/users"));
+        // A preserved route alone matches only with the slash
+        assert_eq!(
+            template("/collections/7/").as_deref(),
+            Some("/collections/{id}/")
+        );
+        assert_eq!(template("/collections/7"), None);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let result = root_ctx.configure(&json!({
+            "services": [{
+                "name": "itemservice",
+                "paths": { "/items/": { "x-preserve-trailing-slash": "yes" } }
+            }]
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_max_config_bytes() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        assert_eq!(root_ctx.max_config_bytes, DEFAULT_MAX_CONFIG_BYTES);
+        root_ctx.load_vm_configuration(br#"{ "maxConfigBytes": 64 }"#);
+        assert_eq!(root_ctx.max_config_bytes, 64);
+
+        root_ctx.load_configuration(TEST_CONFIG.as_bytes().to_vec());
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error.as_deref(), Some("ERR_CONFIG_SIZE"));
+
+        let small = br#"{ "services": [{ "name": "a", "paths": { "/a": {} } }] }"#;
+        assert!(small.len() <= 64);
+        root_ctx.load_configuration(small.to_vec());
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(http_ctx.config_error, None);
+
+        // An invalid VM configuration keeps the current limit
+        for vm_config in [&b"not json"[..], br#"{ "maxConfigBytes": 0 }"#, b""] {
+            root_ctx.load_vm_configuration(vm_config);
+            assert_eq!(root_ctx.max_config_bytes, 64);
+        }
+    }
+
+    #[test]
+    fn test_emit_sample_rate() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "emitSampleRate": 3,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let emitted: Vec<bool> = (0..7)
+            .map(|_| {
+                let route = http_ctx.get_path_template(None, "get", "/users/42", &no_headers);
+                assert!(route.is_some(), "matching is not sampled");
+                let headers = http_ctx.route_headers("GET", route.as_ref());
+                !http_ctx.sample_emission(headers).is_empty()
+            })
+            .collect();
+        assert_eq!(emitted, [true, false, false, true, false, false, true]);
+
+        // Without emitSampleRate every request gets its headers
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        for _ in 0..3 {
+            let headers = http_ctx.route_headers("GET", None);
+            assert!(!http_ctx.sample_emission(headers).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_allowed_schemes() {
+        let configure = |allowed_schemes: Option<Value>, url: &str| {
+            let mut config = json!({
+                "services": [{
+                    "name": "userservice",
+                    "servers": [{ "url": url }],
+                    "paths": { "/users": {} }
+                }]
+            });
+            if let Some(allowed_schemes) = allowed_schemes {
+                config["allowedSchemes"] = allowed_schemes;
+            }
+            OpenapiEndpointRoot::new().configure(&config)
+        };
+
+        assert!(configure(None, "https://api.example.com/v1").is_ok());
+        assert!(configure(None, "HTTP://api.example.com/v1").is_ok());
+        assert!(configure(None, "/v1").is_ok());
+        let error = configure(None, "ftp://api.example.com/v1").unwrap_err();
+        assert!(error.to_string().contains("disallowed scheme 'ftp'"));
+
+        assert!(configure(Some(json!(["grpc"])), "grpc://api.example.com").is_ok());
+        assert!(configure(Some(json!(["grpc"])), "https://api.example.com").is_err());
+        assert!(configure(Some(json!([])), "/v1").is_ok());
+        assert!(configure(Some(json!("https")), "/v1").is_err());
+    }
+
+    #[test]
+    fn test_auto_options_allow() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "autoOptions": true,
+                "services": [
+                    {
+                        "name": "readservice",
+                        "paths": { "/users/{id}": { "get": {}, "delete": {} } }
+                    },
+                    {
+                        "name": "writeservice",
+                        "servers": [{ "url": "https://api.example.com" }],
+                        "paths": { "/users/{id}": { "put": {}, "get": {} } }
+                    },
+                    {
+                        "name": "otherservice",
+                        "servers": [{ "url": "https://other.example.com" }],
+                        "paths": { "/users/{id}": { "post": {} } }
+                    },
+                    { "name": "anyservice", "paths": { "/health": {} } }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let allow = |host: Option<&str>, method: &str, path: &str| {
+            let route = http_ctx.get_path_template(host, method, path, &no_headers);
+            http_ctx.auto_options_allow(host, method, path, &no_headers, route.as_ref())
+        };
+
+        // Methods are merged across the host group and the host-less group
+        assert_eq!(
+            allow(Some("api.example.com"), "options", "/users/42").as_deref(),
+            Some("DELETE, GET, OPTIONS, PUT")
+        );
+        assert_eq!(
+            allow(None, "options", "/users/42").as_deref(),
+            Some("DELETE, GET, OPTIONS")
+        );
+        // Non-OPTIONS requests, unknown paths and any-method routes are left alone
+        assert_eq!(allow(Some("api.example.com"), "get", "/users/42"), None);
+        assert_eq!(allow(None, "options", "/unknown"), None);
+        assert_eq!(allow(None, "options", "/health"), None);
+
+        // Allow lists exactly the methods that would match this request
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "autoOptions": true,
+                "services": [
+                    {
+                        "name": "adminservice",
+                        "paths": { "/users/{id}": { "delete": {}, "x-require-header": "x-admin" } }
+                    },
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{id}": { "get": {} },
+                            "/report": { "get": {}, "x-extensions": { ".json": "jsonservice" } }
+                        }
+                    }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let admin = |name: &str| (name == "x-admin").then(|| "1".to_string());
+        let allow = |path: &str, get_header: &dyn Fn(&str) -> Option<String>| {
+            http_ctx.auto_options_allow(None, "options", path, get_header, None)
+        };
+        assert_eq!(
+            allow("/users/42", &no_headers).as_deref(),
+            Some("GET, OPTIONS")
+        );
+        assert_eq!(
+            allow("/users/42", &admin).as_deref(),
+            Some("DELETE, GET, OPTIONS")
+        );
+        assert_eq!(
+            allow("/report.json", &no_headers).as_deref(),
+            Some("GET, OPTIONS")
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            http_ctx.auto_options_allow(None, "options", "/dockebi/v1/stuff", &no_headers, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_allow_empty_services() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let result = root_ctx.configure(&json!({ "services": [] }));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Services array cannot be empty"
+        );
+        let result = root_ctx.configure(&json!({ "allowEmptyServices": false, "services": [] }));
+        assert!(result.is_err());
+
+        root_ctx
+            .configure(&json!({ "allowEmptyServices": true, "services": [] }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx
+            .get_path_template(None, "get", "/users/42", &no_headers)
+            .is_none());
+    }
+
+    #[test]
+    fn test_normalize_cache() {
+        let options = NormalizeOptions {
+            max_segments: Some(3),
+            ..Default::default()
+        };
+        let cache = NormalizeCache::new(2);
+        let inputs = [
+            "/users/",
+            "//users//42?a=1",
+            "/users/42#top",
+            "/a/b/c/d",
+            "/users//42?b=2",
+            "/",
+        ];
+        // Twice, so later rounds are served from the cache where possible
+        for _ in 0..2 {
+            for input in inputs {
+                assert_eq!(
+                    cache.normalize(input, &options),
+                    normalize_path_with(input, &options),
+                    "{}",
+                    input
+                );
+                assert!(cache.len() <= 2);
+            }
+        }
+        // The query string is not part of the key
+        let cache = NormalizeCache::new(8);
+        cache.normalize("/users/42?a=1", &options);
+        cache.normalize("/users/42?a=2", &options);
+        assert_eq!(cache.len(), 1);
+        // Very long paths are not cached
+        let long = format!("/{}", "x".repeat(2048));
+        assert_eq!(cache.normalize(&long, &options), Some(long.clone()));
+        assert_eq!(cache.len(), 1);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "normalizeCacheSize": 1,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        for path in ["/users/42/", "/users//7", "/users/42/", "/nope"] {
+            let expected = (path != "/nope").then_some(("/users/{id}", "userservice"));
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", path, &no_headers)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_normalize_cache() {
+        let options = NormalizeOptions::default();
+        let paths: Vec<String> = (0..32)
+            .map(|i| {
+                format!(
+                    "/api//v1/./tenants/{}/users/../users/%7e{}/orders//{}/",
+                    i, i, i
+                )
+            })
+            .collect();
+        // Every path repeats, as with a few hot endpoints
+        let cache = NormalizeCache::new(paths.len());
+        let run = |normalize: &dyn Fn(&str) -> Option<String>| {
+            time_per_call(10_000, || {
+                for path in &paths {
+                    std::hint::black_box(normalize(std::hint::black_box(path)));
+                }
+            })
+        };
+        let uncached = run(&|path| normalize_path_with(path, &options));
+        let cached = run(&|path| cache.normalize(path, &options));
+        println!(
+            "normalize {} paths: uncached {:?}, cached {:?}",
+            paths.len(),
+            uncached,
+            cached
+        );
+        assert!(cached < uncached);
+    }
+
+    #[test]
+    fn test_path_depth_header() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "emitPathDepth": true,
+                "services": [{
+                    "name": "userservice",
+                    "servers": [{ "url": "/api/v1" }],
+                    "paths": { "/a/b/{c}": {}, "/": {} },
+                    "prefixes": ["/static/"]
+                }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let depth = |path: &str| {
+            let route = http_ctx.get_path_template(None, "get", path, &no_headers);
+            http_ctx
+                .route_headers("GET", route.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-path-depth")
+                .map(|(_, value)| value)
+        };
+
+        // The template is counted, not the request path or the base path
+        assert_eq!(depth("/api/v1/a/b/42").as_deref(), Some("3"));
+        assert_eq!(depth("/api/v1/").as_deref(), Some("0"));
+        assert_eq!(depth("/api/v1/static/css/site.css").as_deref(), Some("2"));
+        assert_eq!(depth("/unknown"), None);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let route = http_ctx.get_path_template(None, "get", "/dockebi/v1/stuff", &no_headers);
+        assert!(!http_ctx
+            .route_headers("GET", route.as_ref())
+            .iter()
+            .any(|(name, _)| name == "x-oef-path-depth"));
+    }
+
+    #[test]
+    fn test_route_token() {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let sign = |secret: &str, payload: &str| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(payload.as_bytes());
+            let signature: String = mac
+                .finalize()
+                .into_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            format!("{}|{}", payload, signature)
+        };
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "routeTokenSecret": "s3cret",
+                "trustedHosts": ["api.example.com"],
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let with_token =
+            |token: String| move |name: &str| (name == "x-oef-route-token").then(|| token.clone());
+        let host = Some("api.example.com");
+        // 2000 seconds after the epoch
+        host::with(|exchange| exchange.time_nanos = 2_000_000_000_000);
+
+        // A valid token names the route directly, bypassing matching
+        let valid = with_token(sign("s3cret", "billing|/invoices/{id}|3000"));
+        let route = http_ctx.route_from_token(host, &valid).unwrap();
+        let headers = http_ctx.route_headers("GET", Some(&Match::whole(&route, "")));
+        assert!(headers.contains(&("x-service-name".to_string(), "billing".to_string())));
+        assert!(headers.contains(&(
+            "x-api-endpoint".to_string(),
+            "GET /invoices/{id}".to_string()
+        )));
+
+        // Invalid, expired and absent tokens leave the request to normal matching
+        for token in [
+            sign("other", "billing|/invoices/{id}|3000"),
+            sign("s3cret", "billing|/invoices/{id}|3000").replace("billing", "admin"),
+            sign("s3cret", "billing|/invoices/{id}|3000").replace("3000", "9000"),
+            sign("s3cret", "billing|/invoices/{id}|2000"),
+            sign("s3cret", "billing|/invoices/{id}|soon"),
+            sign("s3cret", "billing|/invoices/{id}"),
+            "billing|/invoices/{id}|3000|zz".to_string(),
+            String::new(),
+        ] {
+            assert!(http_ctx
+                .route_from_token(host, &with_token(token))
+                .is_none());
+        }
+        assert!(http_ctx.route_from_token(host, &no_headers).is_none());
+
+        // Hosts that are not trusted cannot use tokens
+        for host in [Some("evil.example.com"), None] {
+            assert!(http_ctx.route_from_token(host, &valid).is_none());
+        }
+
+        // The token is read from the request, then removed from it
+        let config = json!({
+            "routeTokenSecret": "s3cret",
+            "maxHeaders": 10,
+            "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+        });
+        let token = sign("s3cret", "billing|/invoices/{id}|3000");
+        let request = [
+            (":path", "/users/42"),
+            (":method", "GET"),
+            ("x-oef-route-token", token.as_str()),
+        ];
+        for (nheaders, service) in [(3, "billing"), (5000, "userservice")] {
+            run_request_headers(&config, &request, nheaders);
+            // Over maxHeaders the token is not honored
+            assert_eq!(
+                host::request_header("x-service-name").as_deref(),
+                Some(service)
+            );
+            assert_eq!(host::request_header("x-oef-route-token"), None);
+        }
+
+        // Without a secret tokens are never trusted
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx.route_from_token(None, &valid).is_none());
+    }
+
+    #[test]
+    fn test_global_catch_all_safety() {
+        let configure = |config: Value| OpenapiEndpointRoot::new().configure(&config);
+
+        for service in [
+            json!({ "name": "everything", "paths": { "/{*any}": {} } }),
+            json!({ "name": "everything", "prefixes": ["/"] }),
+            json!({ "name": "everything", "catchAll": true }),
+        ] {
+            let error = configure(json!({
+                "rejectGlobalCatchAll": true,
+                "services": [service.clone()]
+            }))
+            .unwrap_err();
+            assert!(
+                error.to_string().contains("allowGlobalCatchAll"),
+                "{}",
+                error
+            );
+            // Unchecked by default, or acknowledged, it loads and matches everything
+            for config in [
+                json!({ "services": [service.clone()] }),
+                json!({
+                    "rejectGlobalCatchAll": true,
+                    "allowGlobalCatchAll": true,
+                    "services": [service]
+                }),
+            ] {
+                let mut root_ctx = OpenapiEndpointRoot::new();
+                root_ctx.configure(&config).unwrap();
+                let http_ctx = root_ctx.create_filter();
+                assert_eq!(
+                    http_ctx
+                        .get_path_template(None, "get", "/any/path", &no_headers)
+                        .map(|m| m.service.to_string())
+                        .as_deref(),
+                    Some("everything")
+                );
+            }
+        }
+
+        // A more specific sibling, a base path or a host makes it safe
+        assert!(configure(json!({
+            "rejectGlobalCatchAll": true,
+            "services": [
+                { "name": "everything", "paths": { "/{*any}": {} } },
+                { "name": "userservice", "paths": { "/users/{id}": {} } }
+            ]
+        }))
+        .is_ok());
+        assert!(configure(json!({
+            "rejectGlobalCatchAll": true,
+            "services": [{
+                "name": "everything",
+                "servers": [{ "url": "/api" }],
+                "paths": { "/{*any}": {} }
+            }]
+        }))
+        .is_ok());
+        assert!(configure(json!({
+            "rejectGlobalCatchAll": true,
+            "services": [{
+                "name": "everything",
+                "servers": [{ "url": "https://api.example.com" }],
+                "catchAll": true
+            }]
+        }))
+        .is_ok());
+    }
+
+    #[test]
+    fn test_feature_budget() {
+        let budget = FeatureBudget {
+            headers: Some(10),
+            path_length: Some(100),
+        };
+        assert_eq!(budget.level(10, 100), FeatureLevel::Full);
+        assert_eq!(budget.level(11, 100), FeatureLevel::Reduced);
+        assert_eq!(budget.level(10, 101), FeatureLevel::Reduced);
+        assert_eq!(budget.level(20, 200), FeatureLevel::Reduced);
+        assert_eq!(budget.level(21, 50), FeatureLevel::Minimal);
+        assert_eq!(budget.level(5, 201), FeatureLevel::Minimal);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "featureBudget": { "headers": 10, "pathLength": "100" },
+                "debugHeaders": true,
+                "paramAnnotatedPath": true,
+                "emitMetadata": true,
+                "auditSampleRate": 1,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }],
+                "shadowServices": [{ "name": "shadow", "paths": { "/users/{id}": {} } }]
+            }))
+            .unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        let mut features = |nheaders: usize| {
+            http_ctx.feature_level = http_ctx.budget_level(nheaders, "/users/42".len());
+            let route = http_ctx.get_path_template(None, "get", "/users/42", &no_headers);
+            let headers = http_ctx.route_headers("GET", route.as_ref());
+            let has = |name: &str| headers.iter().any(|(header, _)| header == name);
+            (
+                has("x-service-name"),
+                has("x-oef-annotated-path") && has("x-oef-param-count"),
+                http_ctx
+                    .shadow_divergence(None, "get", "/users/42", &no_headers, route.as_ref())
+                    .is_some(),
+                http_ctx
+                    .audit_line(None, "get", "/users/42", route.as_ref())
+                    .is_some(),
+                !http_ctx.metadata_properties(route.as_ref()).is_empty(),
+            )
+        };
+
+        // (core headers, derived headers, shadow, audit, metadata)
+        assert_eq!(features(5), (true, true, true, true, true));
+        assert_eq!(features(15), (true, false, false, true, true));
+        assert_eq!(features(25), (true, false, false, false, false));
+
+        for budget in [json!(10), json!({}), json!({ "headers": 0 })] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let result = root_ctx.configure(&json!({
+                "featureBudget": budget,
+                "services": [{ "name": "userservice", "paths": { "/users": {} } }]
+            }));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_sunset_header() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "userservice",
+                    "paths": {
+                        "/v1/users/{id}": { "get": {}, "x-sunset": "2025-12-31" },
+                        "/v1/leap": { "x-sunset": "2024-02-29" },
+                        "/v2/users/{id}": { "get": {} }
+                    }
+                }]
+            }))
+            .unwrap();
+        let router_set = Rc::clone(&root_ctx.router_set);
+        let sunset = |path: &str| {
+            let mut http_ctx = root_ctx.create_filter();
+            let matched = router_set.match_route(None, "get", path, &no_headers);
+            http_ctx.response_sunset = matched
+                .as_ref()
+                .and_then(|matched| matched.route.sunset.clone());
+            http_ctx
+                .response_headers()
+                .into_iter()
+                .find(|(name, _)| name == "sunset")
+                .map(|(_, value)| value)
+        };
+
+        assert_eq!(
+            sunset("/v1/users/42").as_deref(),
+            Some("Wed, 31 Dec 2025 00:00:00 GMT")
+        );
+        assert_eq!(
+            sunset("/v1/leap").as_deref(),
+            Some("Thu, 29 Feb 2024 00:00:00 GMT")
+        );
+        assert_eq!(sunset("/v2/users/42"), None);
+        assert_eq!(sunset("/unknown"), None);
+
+        for date in [
+            json!("2025-13-01"),
+            json!("2025-02-29"),
+            json!("2025-1-01"),
+            json!("31/12/2025"),
+            json!("2025-12-31T00:00:00Z"),
+            json!(20251231),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let result = root_ctx.configure(&json!({
+                "services": [{
+                    "name": "userservice",
+                    "paths": { "/v1/users": { "x-sunset": date } }
+                }]
+            }));
+            assert!(result.is_err(), "{} should be rejected", date);
+        }
+    }
+
+    #[test]
+    fn test_vm_tenant_log_prefix() {
+        use crate::logging::namespace_log_line;
+
+        let line = "[oef] No match found for host: None";
+        let mut prefixes = Vec::new();
+        for tenant in ["gateway-a", "gateway-b"] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.load_vm_configuration(json!({ "tenant": tenant }).to_string().as_bytes());
+            assert_eq!(root_ctx.tenant.as_deref(), Some(tenant));
+            prefixes.push(namespace_log_line(root_ctx.tenant.as_deref(), line));
+        }
+        assert_eq!(
+            prefixes,
+            [
+                "[oef:gateway-a] No match found for host: None",
+                "[oef:gateway-b] No match found for host: None"
+            ]
+        );
+
+        // Without a tenant, and for lines without the prefix, nothing changes
+        assert_eq!(namespace_log_line(None, line), line);
+        assert_eq!(namespace_log_line(Some("gateway-a"), "other"), "other");
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        for vm_config in [
+            json!({ "tenant": "" }),
+            json!({ "tenant": "a b" }),
+            json!({ "tenant": 1 }),
+        ] {
+            root_ctx.load_vm_configuration(vm_config.to_string().as_bytes());
+            assert_eq!(root_ctx.tenant, None);
+        }
+    }
+
+    #[test]
+    fn test_host_match_order() {
+        // Host-specific and wildcard services with the same base path and template
+        let config = |host_match_order: Option<&str>| {
+            let mut config = json!({
+                "services": [
+                    {
+                        "name": "wildcardservice",
+                        "servers": [
+                            { "url": "/api" }
+                        ],
+                        "paths": {
+                            "/users/{id}": {}
+                        }
+                    },
+                    {
+                        "name": "hostservice",
+                        "servers": [
+                            { "url": "https://api.example.com/api" }
+                        ],
+                        "paths": {
+                            "/users/{id}": {}
+                        }
+                    }
+                ]
+            });
+            if let Some(host_match_order) = host_match_order {
+                config["hostMatchOrder"] = json!(host_match_order);
+            }
+            config
+        };
+        let host = Some("api.example.com");
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        for host_match_order in [None, Some("host-first")] {
+            root_ctx.configure(&config(host_match_order)).unwrap();
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(host, "get", "/api/users/42", &no_headers)),
+                Some(("/users/{id}", "hostservice"))
+            );
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/api/users/42", &no_headers)),
+                Some(("/users/{id}", "wildcardservice"))
+            );
+        }
+
+        root_ctx.configure(&config(Some("wildcard-first"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(host, "get", "/api/users/42", &no_headers)),
+            Some(("/users/{id}", "wildcardservice"))
+        );
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx.configure(&config(Some("random"))).err().unwrap();
+        assert!(error.to_string().contains("'hostMatchOrder' must be"));
+    }
+
+    #[test]
+    fn test_param_names_with_special_characters() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{user.id}/orders/{order-id}": {},
+                            "/files/{*file-path}": {},
+                            "/tags/{tag*name}": {}
+                        }
+                    }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+
+        let matched = http_ctx
+            .get_path_template(None, "get", "/users/42/orders/7", &no_headers)
+            .unwrap();
+        assert_eq!(matched.template, "/users/{user.id}/orders/{order-id}");
+        assert_eq!(
+            matched.params,
+            [
+                ("user.id".to_string(), "42".to_string()),
+                ("order-id".to_string(), "7".to_string())
+            ]
+        );
+
+        let matched = http_ctx
+            .get_path_template(None, "get", "/files/a/b/c", &no_headers)
+            .unwrap();
+        assert_eq!(matched.template, "/files/{*file-path}");
+        assert_eq!(
+            matched.params,
+            [("file-path".to_string(), "a/b/c".to_string())]
+        );
+
+        // The router alone rejects `*` inside a parameter name
+        let matched = http_ctx
+            .get_path_template(None, "get", "/tags/rust", &no_headers)
+            .unwrap();
+        assert_eq!(matched.template, "/tags/{tag*name}");
+        assert_eq!(
+            matched.params,
+            [("tag*name".to_string(), "rust".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_on_exact_overlap() {
+        let config = |on_exact_overlap: Option<&str>| {
+            let mut config = json!({
+                "services": [
+                    {
+                        "name": "firstservice",
+                        "paths": {
+                            "/health": {},
+                            "/users/{id}": {}
+                        }
+                    },
+                    {
+                        "name": "secondservice",
+                        "paths": {
+                            "/health": {},
+                            "/orders/{id}": {}
+                        }
+                    }
+                ]
+            });
+            if let Some(on_exact_overlap) = on_exact_overlap {
+                config["onExactOverlap"] = json!(on_exact_overlap);
+            }
+            config
+        };
+
+        for on_exact_overlap in [None, Some("error")] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx.configure(&config(on_exact_overlap)).err().unwrap();
+            assert!(error
+                .to_string()
+                .contains("Duplicate or conflicting route '/health'"));
+        }
+
+        for (on_exact_overlap, service) in [("first", "firstservice"), ("last", "secondservice")] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            root_ctx.configure(&config(Some(on_exact_overlap))).unwrap();
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/health", &no_headers)),
+                Some(("/health", service))
+            );
+            // Other routes of both services are unaffected
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/users/1", &no_headers)),
+                Some(("/users/{id}", "firstservice"))
+            );
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/orders/1", &no_headers)),
+                Some(("/orders/{id}", "secondservice"))
+            );
+            // The template that lost is not listed alongside the one that won
+            assert_eq!(
+                http_ctx.router_set.by_key[&None][0].templates,
+                ["/health", "/users/{id}", "/orders/{id}"]
+            );
+        }
+
+        // Parametric templates still conflict whatever the setting
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let mut parametric = config(Some("last"));
+        parametric["services"][1]["paths"] = json!({ "/users/{id}": {} });
+        assert!(root_ctx.configure(&parametric).is_err());
+
+        let error = root_ctx.configure(&config(Some("random"))).err().unwrap();
+        assert!(error.to_string().contains("'onExactOverlap' must be"));
+    }
+
+    #[test]
+    fn test_cache_fill() {
+        host::reset(&[]);
+        let gauges = || {
+            (
+                host::metric("oef_cache_entries"),
+                host::metric("oef_cache_capacity"),
+            )
+        };
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let mut config = json!({
+            "emitCacheMetrics": true,
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {}
+                    }
+                }
+            ]
+        });
+        // Without a cache, both gauges report 0
+        root_ctx.configure(&config).unwrap();
+        assert!(root_ctx.emit_cache_metrics);
+        assert_eq!(root_ctx.router_set.cache_fill(), (0, 0));
+        root_ctx.schedule_cache_metrics();
+        assert_eq!(gauges(), (Some(0), Some(0)));
+
+        config["normalizeCacheSize"] = json!(2);
+        root_ctx.configure(&config).unwrap();
+        root_ctx.schedule_cache_metrics();
+        assert_eq!(gauges(), (Some(0), Some(2)));
+        let http_ctx = root_ctx.create_filter();
+        // Each tick records the fill after the requests so far
+        for (path, entries) in [
+            ("/users/1", 1),
+            ("/users/1", 1),
+            ("/users/2", 2),
+            // Evictions keep the cache at capacity
+            ("/users/3", 2),
+        ] {
+            http_ctx.get_path_template(None, "get", path, &no_headers);
+            assert_eq!(root_ctx.router_set.cache_fill(), (entries, 2));
+            root_ctx.on_tick();
+            assert_eq!(gauges(), (Some(entries as u64), Some(2)), "{}", path);
+        }
+        // The gauges are defined once, however often the configuration changes
+        assert_eq!(host::with(|exchange| exchange.metrics.len()), 2);
+
+        // Turned off, they are reset to 0
+        config["emitCacheMetrics"] = json!(false);
+        root_ctx.configure(&config).unwrap();
+        root_ctx.schedule_cache_metrics();
+        assert_eq!(gauges(), (Some(0), Some(0)));
+
+        assert_eq!(metric_name(None, "cache_entries"), "oef_cache_entries");
+        assert_eq!(
+            metric_name(Some("gateway-a"), "cache_entries"),
+            "oef_gateway-a_cache_entries"
+        );
+    }
+
+    #[test]
+    fn test_uppercase_percent_encoding() {
+        let options = NormalizeOptions {
+            uppercase_percent_encoding: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_path_with("/files/a%2fb", &options),
+            Some("/files/a%2Fb".to_string())
+        );
+        assert_eq!(
+            normalize_path_with("/files/a%2Fb", &options),
+            Some("/files/a%2Fb".to_string())
+        );
+        // Only valid escapes are touched
+        assert_eq!(
+            normalize_path_with("/a%zz/b%e/100%", &options),
+            Some("/a%zz/b%e/100%".to_string())
+        );
+        assert_eq!(
+            normalize_path_with("/files/a%2fb", &NormalizeOptions::default()),
+            Some("/files/a%2fb".to_string())
+        );
+
+        let cache = NormalizeCache::new(4);
+        assert_eq!(
+            cache.normalize("/files/a%2fb", &options),
+            Some("/files/a%2Fb".to_string())
+        );
+        assert_eq!(
+            cache.normalize("/files/a%2Fb?x=1", &options),
+            Some("/files/a%2Fb".to_string())
+        );
+        assert_eq!(cache.len(), 1);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "uppercasePercentEncoding": true,
+                "services": [
+                    {
+                        "name": "fileservice",
+                        "paths": {
+                            "/files/{name}": {}
+                        }
+                    }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let lower = http_ctx
+            .get_path_template(None, "get", "/files/a%2fb", &no_headers)
+            .unwrap();
+        let upper = http_ctx
+            .get_path_template(None, "get", "/files/a%2Fb", &no_headers)
+            .unwrap();
+        assert_eq!(lower.params, upper.params);
+        assert_eq!(lower.params, [("name".to_string(), "a%2Fb".to_string())]);
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [
+                    {
+                        "name": "reportservice",
+                        "paths": {
+                            "/report": {
+                                "x-extensions": {
+                                    ".json": "jsonservice",
+                                    ".xml": "xmlservice"
+                                }
+                            },
+                            "/reports/{id}": {
+                                "x-extensions": {
+                                    ".json": "jsonservice"
+                                }
+                            },
+                            "/users/{id}": {}
+                        }
+                    }
+                ]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let cases = [
+            ("/report.json", Some(("/report", "jsonservice"))),
+            ("/report.XML", Some(("/report", "xmlservice"))),
+            // Unlisted extensions and no extension fall back to the base route
+            ("/report.csv", Some(("/report", "reportservice"))),
+            ("/report", Some(("/report", "reportservice"))),
+            ("/reports/42.json", Some(("/reports/{id}", "jsonservice"))),
+            ("/reports/42", Some(("/reports/{id}", "reportservice"))),
+            // Routes without `x-extensions` are matched as before
+            ("/users/a.json", Some(("/users/{id}", "reportservice"))),
+            ("/report.json/x", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", path, &no_headers)),
+                expected,
+                "{}",
+                path
+            );
+        }
+        // The parameter is captured without the extension
+        let matched = http_ctx
+            .get_path_template(None, "get", "/reports/42.json", &no_headers)
+            .unwrap();
+        assert_eq!(matched.params, [("id".to_string(), "42".to_string())]);
+        let matched = http_ctx
+            .get_path_template(None, "get", "/users/a.json", &no_headers)
+            .unwrap();
+        assert_eq!(matched.params, [("id".to_string(), "a.json".to_string())]);
+
+        for extensions in [
+            json!({ "json": "x" }),
+            json!({ ".json": "" }),
+            json!([".json"]),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx
+                .configure(&json!({
+                    "services": [
+                        {
+                            "name": "reportservice",
+                            "paths": { "/report": { "x-extensions": extensions } }
+                        }
+                    ]
+                }))
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .contains("'x-extensions' for path '/report'"));
+        }
+    }
+
+    #[test]
+    fn test_missing_method_behavior() {
+        let config = |behavior: Option<&str>| {
+            let mut config = json!({
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{id}": { "get": {} },
+                            "/health": {}
+                        }
+                    }
+                ]
+            });
+            if let Some(behavior) = behavior {
+                config["missingMethodBehavior"] = json!(behavior);
+            }
+            config
+        };
+        // What the request headers phase matches an absent `:method` with
+        fn resolve<'a>(
+            http_ctx: &'a OpenapiEndpointFilter,
+            path: &str,
+        ) -> Option<Option<(&'a str, &'a str)>> {
+            http_ctx.resolve_method(None).map(|method| {
+                endpoint(http_ctx.get_path_template(
+                    None,
+                    &method.to_ascii_lowercase(),
+                    path,
+                    &no_headers,
+                ))
+            })
+        }
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        for behavior in [None, Some("any")] {
+            root_ctx.configure(&config(behavior)).unwrap();
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(http_ctx.resolve_method(None).as_deref(), Some("unknown"));
+            assert_eq!(resolve(&http_ctx, "/users/42"), Some(None));
+            assert_eq!(
+                resolve(&http_ctx, "/health"),
+                Some(Some(("/health", "userservice")))
+            );
+        }
+
+        root_ctx.configure(&config(Some("get"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            resolve(&http_ctx, "/users/42"),
+            Some(Some(("/users/{id}", "userservice")))
+        );
+
+        root_ctx.configure(&config(Some("reject"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(resolve(&http_ctx, "/health"), None);
+        // A present method is used as is under every behavior
+        assert_eq!(
+            http_ctx.resolve_method(Some("POST".to_string())).as_deref(),
+            Some("POST")
+        );
+
+        let error = root_ctx.configure(&config(Some("head"))).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("'missingMethodBehavior' must be"));
+    }
+
+    #[test]
+    fn test_param_coercion() {
+        let config = |param_coercion: &str| {
+            json!({
+                "paramCoercion": param_coercion,
+                "emitParams": true,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{id}/active/{active}": {
+                                "parameters": [
+                                    { "name": "id", "in": "path", "schema": { "type": "integer" } },
+                                    { "name": "active", "in": "path", "schema": { "type": "boolean" } },
+                                    { "name": "id", "in": "query", "schema": { "type": "string" } }
+                                ]
+                            }
+                        }
+                    }
+                ]
+            })
+        };
+        fn params<'a>(
+            http_ctx: &'a OpenapiEndpointFilter,
+            path: &str,
+        ) -> Option<(&'a str, Vec<(String, String)>)> {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers)?;
+            let headers = http_ctx
+                .route_headers("GET", Some(&matched))
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("x-oef-param-"))
+                .collect();
+            Some((matched.template, headers))
+        }
+        let typed = "/users/{id}/active/{active}";
+        let expected = |id: &str, active: &str| {
+            vec![
+                ("x-oef-param-id".to_string(), id.to_string()),
+                ("x-oef-param-active".to_string(), active.to_string()),
+            ]
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config("strict")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            params(&http_ctx, "/users/042/active/TRUE"),
+            Some((typed, expected("42", "true")))
+        );
+        assert_eq!(
+            params(&http_ctx, "/users/-7/active/false"),
+            Some((typed, expected("-7", "false")))
+        );
+        // An invalid value fails the match
+        assert_eq!(params(&http_ctx, "/users/abc/active/true"), None);
+        assert_eq!(params(&http_ctx, "/users/42/active/yes"), None);
+
+        root_ctx.configure(&config("lenient")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            params(&http_ctx, "/users/042/active/yes"),
+            Some((typed, expected("42", "yes")))
+        );
+        assert_eq!(
+            params(&http_ctx, "/users/abc/active/True"),
+            Some((typed, expected("abc", "true")))
+        );
+
+        root_ctx.configure(&config("off")).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            params(&http_ctx, "/users/042/active/TRUE"),
+            Some((typed, expected("042", "TRUE")))
+        );
+
+        let error = root_ctx.configure(&config("loose")).err().unwrap();
+        assert!(error.to_string().contains("'paramCoercion' must be"));
+        assert_eq!(param_header_name("user.Id"), "x-oef-param-user-id");
+    }
+
+    #[test]
+    fn test_reconfigure_swaps_routes_and_cache_together() {
+        let config = |resolve_dot_segments: bool, template: &str| {
+            json!({
+                "normalizeCacheSize": 8,
+                "resolveDotSegments": resolve_dot_segments,
+                "services": [{ "name": "userservice", "paths": { template: {} } }]
+            })
+        };
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false, "/a/../b")).unwrap();
+        let old_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(old_ctx.get_path_template(None, "get", "/a/../b", &no_headers)),
+            Some(("/a/../b", "userservice"))
+        );
+
+        // Reconfigure while the old context is still in flight
+        root_ctx.configure(&config(true, "/b")).unwrap();
+        let new_ctx = root_ctx.create_filter();
+        assert_eq!(new_ctx.router_set.cache_fill(), (0, 8));
+        assert_eq!(
+            endpoint(new_ctx.get_path_template(None, "get", "/a/../b", &no_headers)),
+            Some(("/b", "userservice"))
+        );
+
+        // Interleaved lookups each see only their own routes and cache
+        for _ in 0..2 {
+            assert_eq!(
+                endpoint(old_ctx.get_path_template(None, "get", "/a/../b", &no_headers)),
+                Some(("/a/../b", "userservice"))
+            );
+            assert_eq!(
+                endpoint(old_ctx.get_path_template(None, "get", "/b", &no_headers)),
+                None
+            );
+            assert_eq!(
+                endpoint(new_ctx.get_path_template(None, "get", "/a/../b", &no_headers)),
+                Some(("/b", "userservice"))
+            );
+        }
+        assert_eq!(old_ctx.router_set.cache_fill(), (2, 8));
+        assert_eq!(new_ctx.router_set.cache_fill(), (1, 8));
+    }
+
+    #[test]
+    fn test_slug_header() {
+        let config = json!({
+            "slugHeader": "X-Route-Slug",
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/": {},
+                        "/users/{id}": {},
+                        "/users/{id}/posts/{post_id}": {},
+                        "/files/{*path}": {}
+                    }
+                }
+            ]
+        });
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let slug = |path: &str| {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers);
+            http_ctx
+                .route_headers("GET", matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-route-slug")
+                .map(|(_, value)| value)
+        };
+        assert_eq!(slug("/users/42").as_deref(), Some("users__id"));
+        assert_eq!(
+            slug("/users/42/posts/7").as_deref(),
+            Some("users__id__posts__post_id")
+        );
+        assert_eq!(slug("/files/a/b").as_deref(), Some("files__path"));
+        assert_eq!(slug("/").as_deref(), Some("root"));
+        assert_eq!(slug("/nothing/here/at/all"), None);
+
+        // Colliding slugs are only logged
+        assert_eq!(route_slug("/users/id"), route_slug("/users/{id}"));
+        let mut colliding = config.clone();
+        colliding["services"][0]["paths"]["/users/id"] = json!({});
+        assert!(root_ctx.configure(&colliding).is_ok());
+
+        let mut invalid = config.clone();
+        invalid["slugHeader"] = json!("");
+        let error = root_ctx.configure(&invalid).err().unwrap();
+        assert!(error.to_string().contains("'slugHeader' must be"));
+    }
+
+    #[test]
+    fn test_on_param_name_conflict() {
+        let config = |on_param_name_conflict: Option<&str>| {
+            let mut config = json!({
+                "emitParams": true,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{id}": {},
+                            "/users/{id}/files/{*path}": {}
+                        }
+                    },
+                    {
+                        "name": "accountservice",
+                        "paths": {
+                            "/users/{uid}": {},
+                            "/users/{uid}/files/{*rest}": {},
+                            "/accounts/{uid}": {}
+                        }
+                    }
+                ]
+            });
+            if let Some(on_param_name_conflict) = on_param_name_conflict {
+                config["onParamNameConflict"] = json!(on_param_name_conflict);
+            }
+            config
+        };
+        // Template, service and parameter headers of a match
+        type Emitted<'a> = Option<(&'a str, &'a str, Vec<(String, String)>)>;
+        fn params<'a>(http_ctx: &'a OpenapiEndpointFilter, path: &str) -> Emitted<'a> {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers)?;
+            let headers = http_ctx
+                .route_headers("GET", Some(&matched))
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("x-oef-param-"))
+                .collect();
+            Some((matched.template, matched.service.as_str(), headers))
+        }
+        let header = |name: &str, value: &str| (format!("x-oef-param-{}", name), value.to_string());
+
+        for on_param_name_conflict in [None, Some("error")] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let error = root_ctx
+                .configure(&config(on_param_name_conflict))
+                .err()
+                .unwrap();
+            assert!(error.to_string().contains("'/users/{uid}'"));
+        }
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(Some("first"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            params(&http_ctx, "/users/42"),
+            Some(("/users/{id}", "userservice", vec![header("id", "42")]))
+        );
+        assert_eq!(
+            params(&http_ctx, "/accounts/42"),
+            Some((
+                "/accounts/{uid}",
+                "accountservice",
+                vec![header("uid", "42")]
+            ))
+        );
+
+        root_ctx.configure(&config(Some("merge"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            params(&http_ctx, "/users/42"),
+            Some((
+                "/users/{id}",
+                "userservice",
+                vec![header("id", "42"), header("uid", "42")]
+            ))
+        );
+        assert_eq!(
+            params(&http_ctx, "/users/42/files/a/b"),
+            Some((
+                "/users/{id}/files/{*path}",
+                "userservice",
+                vec![
+                    header("id", "42"),
+                    header("uid", "42"),
+                    header("path", "a/b"),
+                    header("rest", "a/b")
+                ]
+            ))
+        );
+
+        // Templates with a different structure still conflict
+        let mut structural = config(Some("merge"));
+        structural["services"][1]["paths"] = json!({ "/users/{uid}.json": {} });
+        assert!(root_ctx.configure(&structural).is_ok());
+        let mut structural = config(Some("merge"));
+        structural["services"][1]["paths"] = json!({ "/users/{*rest}": {} });
+        assert!(root_ctx.configure(&structural).is_err());
+
+        let error = root_ctx.configure(&config(Some("random"))).err().unwrap();
+        assert!(error.to_string().contains("'onParamNameConflict' must be"));
+    }
+
+    #[test]
+    fn test_root_service() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let mut config = json!({
+            "rootService": "landingservice",
+            "services": [
+                {
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {}
+                    }
+                }
+            ]
+        });
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        for path in ["/", "", "//", "/?x=1"] {
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "post", path, &no_headers)),
+                Some(("/", "landingservice")),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "userservice"))
+        );
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/other", &no_headers)),
+            None
+        );
+
+        // Without rootService, `/` is matched as usual
+        config.as_object_mut().unwrap().remove("rootService");
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(None, "get", "/", &no_headers)),
+            None
+        );
+
+        config["rootService"] = json!("");
+        let error = root_ctx.configure(&config).err().unwrap();
+        assert!(error.to_string().contains("'rootService' must be"));
+    }
+
+    #[test]
+    fn test_emit_routing_key() {
+        let config = |separator: Option<&str>| {
+            let mut config = json!({
+                "emitRoutingKey": true,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "servers": [{ "url": "https://api.example.com" }],
+                        "paths": { "/users/{id}": {} }
+                    },
+                    {
+                        "name": "status|service",
+                        "paths": { "/status": {} }
+                    }
+                ]
+            });
+            if let Some(separator) = separator {
+                config["routingKeySeparator"] = json!(separator);
+            }
+            config
+        };
+        let key = |http_ctx: &OpenapiEndpointFilter, host: Option<&str>, path: &str| {
+            let matched = http_ctx.get_path_template(host, "get", path, &no_headers);
+            http_ctx
+                .route_headers("GET", matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-routing-key")
+                .map(|(_, value)| value)
+        };
+        let host = Some("api.example.com");
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(None)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            key(&http_ctx, host, "/users/42").as_deref(),
+            Some("api.example.com|userservice")
+        );
+        // Wildcard groups use `*`, and the separator is escaped in values
+        assert_eq!(
+            key(&http_ctx, host, "/status").as_deref(),
+            Some("*|status%7Cservice")
+        );
+        assert_eq!(key(&http_ctx, host, "/missing"), None);
+
+        root_ctx.configure(&config(Some("::"))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            key(&http_ctx, host, "/users/42").as_deref(),
+            Some("api.example.com::userservice")
+        );
+        assert_eq!(routing_key("a::b%", "c", "::"), "a%3A%3Ab%25::c");
+
+        let error = root_ctx.configure(&config(Some(""))).err().unwrap();
+        assert!(error.to_string().contains("'routingKeySeparator' must be"));
+    }
+
+    #[test]
+    fn test_max_param_headers() {
+        let config = |max_param_headers: Option<u64>| {
+            let mut config = json!({
+                "emitParams": true,
+                "services": [
+                    {
+                        "name": "orgservice",
+                        "paths": { "/orgs/{org}/teams/{team}/members/{member}/keys/{key}": {} }
+                    }
+                ]
+            });
+            if let Some(max) = max_param_headers {
+                config["maxParamHeaders"] = json!(max);
+            }
+            config
+        };
+        let param_headers = |root_ctx: &OpenapiEndpointRoot| {
+            let http_ctx = root_ctx.create_filter();
+            let matched = http_ctx.get_path_template(
+                None,
+                "get",
+                "/orgs/o/teams/t/members/m/keys/k",
+                &no_headers,
+            );
+            http_ctx
+                .route_headers("GET", matched.as_ref())
+                .into_iter()
+                .filter(|(name, _)| name.starts_with("x-oef-param-"))
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(None)).unwrap();
+        assert_eq!(param_headers(&root_ctx).len(), 4);
+
+        // Truncated in template order
+        root_ctx.configure(&config(Some(2))).unwrap();
+        assert_eq!(
+            param_headers(&root_ctx),
+            ["x-oef-param-org", "x-oef-param-team"]
+        );
+
+        root_ctx.configure(&config(Some(10))).unwrap();
+        assert_eq!(param_headers(&root_ctx).len(), 4);
+
+        let mut invalid = config(None);
+        invalid["maxParamHeaders"] = json!(0);
+        assert!(root_ctx.configure(&invalid).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_match_engines() {
+        let shapes = [
+            ("/items", "/items"),
+            ("/items/{id}", "/items/42"),
+            ("/items/{id}/tags", "/items/42/tags"),
+            ("/items/{id}/tags/{tag}", "/items/42/tags/red"),
+            ("/users/{id}", "/users/7"),
+            ("/files/{*path}", "/files/a/b/c.txt"),
+        ];
+        // 600 templates, with every template looked up once per round plus a miss
+        let (templates, mut paths): (Vec<String>, Vec<String>) = (0..100)
+            .flat_map(|i| {
+                shapes.map(|(template, path)| {
+                    (
+                        format!("/svc{}{}", i, template),
+                        format!("/svc{}{}", i, path),
+                    )
+                })
+            })
+            .unzip();
+        paths.push("/svc7/nope".to_string());
+        assert_eq!(templates.len(), 600);
+
+        let mut timings = Vec::new();
+        for engine in [MatchEngine::Matchit, MatchEngine::Trie] {
+            let mut matcher = engine.matcher();
+            for template in &templates {
+                matcher.insert(template, Route::default()).unwrap();
+            }
+            assert_eq!(
+                paths
+                    .iter()
+                    .filter(|path| matcher.at(path).is_some())
+                    .count(),
+                600
+            );
+            let per_round = time_per_call(1_000, || {
+                for path in &paths {
+                    std::hint::black_box(matcher.at(std::hint::black_box(path)));
+                }
+            });
+            timings.push((engine, per_round / paths.len() as u32));
+        }
+        println!("lookup among {} templates: {:?}", templates.len(), timings);
+    }
+
+    #[test]
+    fn test_match_engines_agree() {
+        let config = |engine: &str| {
+            json!({
+                "matchEngine": engine,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/": {},
+                            "/users/{id}": {},
+                            "/users/new": {},
+                            "/users/{id}/posts/{postId}": {},
+                            "/users/new/settings": {},
+                            "/a/{x}/c/w": {},
+                            "/a/b/{y}/z": {}
+                        }
+                    },
+                    {
+                        "name": "fileservice",
+                        "paths": {
+                            "/files/{*path}": {},
+                            "/files/static/logo": {},
+                            "/reports/{name}.json": {},
+                            "/reports/{name}": {},
+                            "/v{version}/status": {}
+                        }
+                    }
+                ]
+            })
+        };
+        type Matched = Option<(String, String, Vec<(String, String)>)>;
+        let matched = |root_ctx: &OpenapiEndpointRoot, path: &str| -> Matched {
+            let http_ctx = root_ctx.create_filter();
+            http_ctx
+                .get_path_template(None, "get", path, &no_headers)
+                .map(|m| (m.template.to_string(), m.service.to_string(), m.params))
+        };
+
+        let mut matchit = OpenapiEndpointRoot::new();
+        matchit.configure(&config("matchit")).unwrap();
+        let mut trie = OpenapiEndpointRoot::new();
+        trie.configure(&config("trie")).unwrap();
+        for path in [
+            "/",
+            "/users/42",
+            "/users/new",
+            "/users/new/posts/7",
+            "/users/new/settings",
+            "/users/",
+            "/a/b/c/w",
+            "/a/b/c/z",
+            "/files/a/b",
+            "/files/static/logo",
+            "/files/static",
+            "/files",
+            "/reports/q1.json",
+            "/reports/.json",
+            "/reports/q1",
+            "/v2/status",
+            "/v/status",
+            "/nope",
+        ] {
+            assert_eq!(matched(&matchit, path), matched(&trie, path), "{}", path);
+        }
+        assert_eq!(
+            matched(&trie, "/users/new/posts/7").unwrap().2,
+            [
+                ("id".to_string(), "new".to_string()),
+                ("postId".to_string(), "7".to_string())
+            ]
+        );
+
+        // Both reject conflicting templates
+        let mut conflicting = config("trie");
+        conflicting["services"][0]["paths"]["/files/{name}"] = json!({});
+        assert!(trie.configure(&conflicting).is_err());
+        conflicting["matchEngine"] = json!("matchit");
+        assert!(matchit.configure(&conflicting).is_err());
+
+        let error = trie.configure(&config("radix")).err().unwrap();
+        assert!(error.to_string().contains("'matchEngine' must be"));
+    }
+
+    #[test]
+    fn test_host_sources() {
+        let config = json!({
+            "services": [
+                {
+                    "name": "userservice",
+                    "servers": [{ "url": "https://api.example.com" }],
+                    "paths": { "/users/{id}": {} }
+                },
+                {
+                    "name": "adminservice",
+                    "servers": [{ "url": "https://admin.example.com" }],
+                    "paths": { "/users/{id}": {} }
+                }
+            ]
+        });
+        let service = |http_ctx: &OpenapiEndpointFilter, headers: &[(&str, &str)]| {
+            let get_header = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(header, _)| *header == name)
+                    .map(|(_, value)| value.to_string())
+            };
+            let host = http_ctx.resolve_host(&get_header);
+            endpoint(http_ctx.get_path_template(host.as_deref(), "get", "/users/42", &no_headers))
+                .map(|(_, service)| service.to_string())
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        // HTTP/1.1 sends `host`, HTTP/2 sends `:authority`
+        let http1 = service(&http_ctx, &[("host", "API.example.com:8080")]);
+        let http2 = service(&http_ctx, &[(":authority", "api.example.com")]);
+        assert_eq!(http1.as_deref(), Some("userservice"));
+        assert_eq!(http1, http2);
+        // `:authority` wins when both are present
+        assert_eq!(
+            service(
+                &http_ctx,
+                &[
+                    (":authority", "api.example.com"),
+                    ("host", "admin.example.com")
+                ]
+            )
+            .as_deref(),
+            Some("userservice")
+        );
+        assert_eq!(service(&http_ctx, &[]), None);
+
+        let mut config = config;
+        config["hostSource"] = json!(["X-Forwarded-Host", ":authority"]);
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(
+            service(
+                &http_ctx,
+                &[
+                    (":authority", "api.example.com"),
+                    ("x-forwarded-host", "admin.example.com")
+                ]
+            )
+            .as_deref(),
+            Some("adminservice")
+        );
+        assert_eq!(service(&http_ctx, &[("host", "api.example.com")]), None);
+
+        config["hostSource"] = json!([]);
+        let error = root_ctx.configure(&config).err().unwrap();
+        assert!(error.to_string().contains("'hostSource' must be"));
+    }
+
+    #[test]
+    fn test_prepend_route_to_body() {
+        let config = |prepend: bool| {
+            json!({
+                "prependRouteToBody": prepend,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            })
+        };
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx.get_path_template(None, "post", "/users/42", &no_headers);
+        let (service, template) = http_ctx.body_route(matched.as_ref()).unwrap();
+        assert_eq!(
+            (service.as_str(), template.as_str()),
+            ("userservice", "/users/{id}")
+        );
+        let unmatched = http_ctx.get_path_template(None, "post", "/orders/42", &no_headers);
+        assert_eq!(http_ctx.body_route(unmatched.as_ref()), None);
+
+        let wrapped = wrap_body_with_route(&service, &template, br#"{"name": "kim"}"#).unwrap();
+        assert_eq!(
+            String::from_utf8(wrapped).unwrap(),
+            r#"{"route":{"service":"userservice","template":"/users/{id}"},"body":{"name": "kim"}}"#
+        );
+        let wrapped = wrap_body_with_route(&service, &template, b"").unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&wrapped).unwrap(),
+            json!({ "route": { "service": "userservice", "template": "/users/{id}" }, "body": null })
+        );
+        // Service names are escaped, and bodies that are not JSON stay as they are
+        let wrapped = wrap_body_with_route("a\"b", &template, b"[1, 2]").unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&wrapped).unwrap()["route"]["service"],
+            "a\"b"
+        );
+        assert_eq!(wrap_body_with_route(&service, &template, b"name=kim"), None);
+
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx.get_path_template(None, "post", "/users/42", &no_headers);
+        assert_eq!(http_ctx.body_route(matched.as_ref()), None);
+
+        // JSON requests are held until the body is wrapped, then carry its length
+        let request = |content_type: &'static str| {
+            [
+                (":path", "/users/42"),
+                (":method", "POST"),
+                ("content-type", content_type),
+                ("content-length", "7"),
+            ]
+        };
+        let send_body = |http_ctx: &mut OpenapiEndpointFilter, body: &[u8]| {
+            host::with(|exchange| exchange.request_body = body.to_vec());
+            assert_eq!(
+                http_ctx.on_http_request_body(body.len(), true),
+                Action::Continue
+            );
+            host::with(|exchange| exchange.request_body.clone())
+        };
+        let (mut http_ctx, action) =
+            run_request_headers(&config(true), &request("application/json"), 4);
+        assert_eq!(action, Action::Pause);
+        assert_eq!(host::request_header("content-length").as_deref(), Some("7"));
+        let body = send_body(&mut http_ctx, br#"{"a":1}"#);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap()["body"],
+            json!({ "a": 1 })
+        );
+        assert_eq!(
+            host::request_header("content-length"),
+            Some(body.len().to_string())
+        );
+
+        // A declared JSON body that does not parse passes through with its length
+        let (mut http_ctx, _) = run_request_headers(&config(true), &request("application/json"), 4);
+        assert_eq!(send_body(&mut http_ctx, b"name=ki"), b"name=ki");
+        assert_eq!(host::request_header("content-length").as_deref(), Some("7"));
+
+        // Other content types are neither held nor touched
+        let (mut http_ctx, action) = run_request_headers(&config(true), &request("text/plain"), 4);
+        assert_eq!(action, Action::Continue);
+        assert_eq!(send_body(&mut http_ctx, b"[1, 2]"), b"[1, 2]");
+        assert_eq!(host::request_header("content-length").as_deref(), Some("7"));
+
+        // Nor are requests that end with their headers
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let mut http_ctx = root_ctx.create_filter();
+        host::reset(&request("application/json"));
+        assert_eq!(http_ctx.on_http_request_headers(4, true), Action::Continue);
+        assert_eq!(http_ctx.body_route, None);
+        assert_eq!(host::request_header("content-length").as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn test_default_host() {
+        let config = |default_host: Option<&str>| {
+            let mut config = json!({
+                "services": [
+                    {
+                        "name": "internalservice",
+                        "servers": [{ "url": "http://internal.local" }],
+                        "paths": { "/jobs/{id}": {} }
+                    },
+                    { "name": "publicservice", "paths": { "/status": {} } }
+                ]
+            });
+            if let Some(default_host) = default_host {
+                config["defaultHost"] = json!(default_host);
+            }
+            config
+        };
+        let service = |root_ctx: &OpenapiEndpointRoot, host: Option<&str>, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            let host = http_ctx.resolve_host(&|name| match name {
+                "host" => host.map(str::to_string),
+                _ => None,
+            });
+            endpoint(http_ctx.get_path_template(host.as_deref(), "get", path, &no_headers))
+                .map(|(_, service)| service.to_string())
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(None)).unwrap();
+        assert_eq!(service(&root_ctx, None, "/jobs/1"), None);
+
+        root_ctx
+            .configure(&config(Some("Internal.Local:8080")))
+            .unwrap();
+        assert_eq!(
+            service(&root_ctx, None, "/jobs/1").as_deref(),
+            Some("internalservice")
+        );
+        // Wildcard routes still apply, and a present host is not overridden
+        assert_eq!(
+            service(&root_ctx, None, "/status").as_deref(),
+            Some("publicservice")
+        );
+        assert_eq!(
+            service(&root_ctx, Some("other.example.com"), "/jobs/1"),
+            None
+        );
+        assert_eq!(
+            service(&root_ctx, Some("  "), "/jobs/1").as_deref(),
+            Some("internalservice")
+        );
+
+        let error = root_ctx.configure(&config(Some(""))).err().unwrap();
+        assert!(error.to_string().contains("'defaultHost' must be"));
+    }
+
+    #[test]
+    fn test_any_method_precedence() {
+        let config = |precedence: Option<&str>| {
+            let mut config = json!({
+                "services": [
+                    { "name": "itemservice", "paths": { "/items/{id}": { "get": {} } } },
+                    { "name": "overrideservice", "paths": { "/items/{id}": {} } },
+                    { "name": "fallbackservice", "prefixes": ["/items"] }
+                ]
+            });
+            if let Some(precedence) = precedence {
+                config["anyMethodPrecedence"] = json!(precedence);
+            }
+            config
+        };
+        let matched = |root_ctx: &OpenapiEndpointRoot, method: &str| {
+            let http_ctx = root_ctx.create_filter();
+            http_ctx
+                .get_path_template(None, method, "/items/1", &no_headers)
+                .map(|matched| {
+                    (
+                        matched.service.to_string(),
+                        matched.method.map(str::to_string),
+                    )
+                })
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        for precedence in [None, Some("low")] {
+            root_ctx.configure(&config(precedence)).unwrap();
+            assert_eq!(
+                matched(&root_ctx, "get"),
+                Some(("itemservice".to_string(), Some("get".to_string())))
+            );
+            assert_eq!(
+                matched(&root_ctx, "post"),
+                Some(("overrideservice".to_string(), None))
+            );
+        }
+
+        root_ctx.configure(&config(Some("high"))).unwrap();
+        assert_eq!(
+            matched(&root_ctx, "get"),
+            Some(("overrideservice".to_string(), None))
+        );
+        assert_eq!(
+            matched(&root_ctx, "post"),
+            Some(("overrideservice".to_string(), None))
+        );
+
+        // Prefix routes stay the last resort
+        let mut high = config(Some("high"));
+        high["services"][1]["paths"] = json!({ "/other": {} });
+        root_ctx.configure(&high).unwrap();
+        assert_eq!(
+            matched(&root_ctx, "get"),
+            Some(("itemservice".to_string(), Some("get".to_string())))
+        );
+
+        let error = root_ctx.configure(&config(Some("override"))).err().unwrap();
+        assert!(error.to_string().contains("'anyMethodPrecedence' must be"));
+    }
+
+    #[test]
+    fn test_unchanged_config_skips_rebuild() {
+        let config = json!({
+            "normalizeCacheSize": 16,
+            "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+        });
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx
+            .get_path_template(None, "get", "/users/42", &no_headers)
+            .is_some());
+        let router_set = Rc::clone(&root_ctx.router_set);
+        assert_eq!(router_set.cache_fill().0, 1);
+
+        // Same content in another key order: routes and cache are kept
+        let reordered: Value = serde_json::from_str(
+            r#"{"services": [{"paths": {"/users/{id}": {}}, "name": "userservice"}], "normalizeCacheSize": 16}"#,
+        )
+        .unwrap();
+        root_ctx.configure(&reordered).unwrap();
+        assert!(Rc::ptr_eq(&router_set, &root_ctx.router_set));
+        assert_eq!(root_ctx.router_set.cache_fill().0, 1);
+
+        let mut changed = config.clone();
+        changed["services"][0]["paths"]["/orders/{id}"] = json!({});
+        root_ctx.configure(&changed).unwrap();
+        assert!(!Rc::ptr_eq(&router_set, &root_ctx.router_set));
+        assert_eq!(root_ctx.router_set.cache_fill().0, 0);
+
+        // A rejected configuration leaves the last applied one in place
+        let mut invalid = changed.clone();
+        invalid["services"][0]["name"] = json!("");
+        assert!(root_ctx.configure(&invalid).is_err());
+        let router_set = Rc::clone(&root_ctx.router_set);
+        root_ctx.configure(&changed).unwrap();
+        assert!(Rc::ptr_eq(&router_set, &root_ctx.router_set));
+        root_ctx.configure(&config).unwrap();
+        assert!(!Rc::ptr_eq(&router_set, &root_ctx.router_set));
+    }
+
+    #[test]
+    fn test_authority_userinfo_is_stripped() {
+        for authority in [
+            "api.example.com",
+            "user@api.example.com",
+            "user:pass@API.example.com:443",
+            "a@b@api.example.com",
+        ] {
+            assert_eq!(
+                OpenapiEndpointFilter::normalize_host(authority).as_deref(),
+                Some("api.example.com"),
+                "{}",
+                authority
+            );
+        }
+        assert_eq!(
+            OpenapiEndpointFilter::normalize_host("user@[::1]:8080").as_deref(),
+            Some("[::1]")
+        );
+        assert_eq!(OpenapiEndpointFilter::normalize_host("user@"), None);
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "userservice",
+                    "servers": [{ "url": "https://api.example.com" }],
+                    "paths": { "/users/{id}": {} }
+                }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let host = http_ctx.resolve_host(&|name| {
+            (name == ":authority").then(|| "user:pass@api.example.com:443".to_string())
+        });
+        assert_eq!(
+            endpoint(http_ctx.get_path_template(host.as_deref(), "get", "/users/42", &no_headers)),
+            Some(("/users/{id}", "userservice"))
+        );
+    }
+
+    #[test]
+    fn test_log_fields() {
+        let config = |log_fields: Value| {
+            json!({
+                "auditSampleRate": 1,
+                "logFields": log_fields,
+                "services": [
+                    { "name": "userservice", "paths": { "/users/{id}/posts/{postId}": {} } }
+                ]
+            })
+        };
+        let audit_line = |root_ctx: &OpenapiEndpointRoot, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            let matched =
+                http_ctx.get_path_template(Some("api.example.com"), "get", path, &no_headers);
+            http_ctx
+                .audit_line(Some("api.example.com"), "get", path, matched.as_ref())
+                .unwrap()
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&config(json!(["service", "template", "params"])))
+            .unwrap();
+        assert_eq!(
+            audit_line(&root_ctx, "/users/42/posts/7?token=secret"),
+            "[oef] audit service=userservice template=/users/{id}/posts/{postId} params=id:42,postId:7"
+        );
+        assert_eq!(
+            audit_line(&root_ctx, "/orders"),
+            "[oef] audit service=- template=- params=-"
+        );
+
+        // Duplicates are dropped, and an empty list leaves only the prefix
+        root_ctx
+            .configure(&config(json!(["decision", "decision"])))
+            .unwrap();
+        assert_eq!(
+            audit_line(&root_ctx, "/orders"),
+            "[oef] audit decision=unmatched"
+        );
+        root_ctx.configure(&config(json!([]))).unwrap();
+        assert_eq!(audit_line(&root_ctx, "/orders"), "[oef] audit");
+
+        let error = root_ctx
+            .configure(&config(json!(["host", "cookie"])))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("'logFields' must be"));
     }
 
     #[test]
-    fn test_path_normalization() {
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx
-            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
-            .unwrap();
+    fn test_preserve_leading_double_slash() {
+        let options = NormalizeOptions {
+            preserve_leading_double_slash: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_path_with("//foo", &options).as_deref(),
+            Some("//foo")
+        );
+        assert_eq!(
+            normalize_path_with("///foo//bar/", &options).as_deref(),
+            Some("//foo/bar")
+        );
+        assert_eq!(normalize_path_with("//", &options).as_deref(), Some("//"));
+        assert_eq!(
+            normalize_path_with("/foo//bar", &options).as_deref(),
+            Some("/foo/bar")
+        );
+        assert_eq!(
+            normalize_path_with("//foo", &NormalizeOptions::default()).as_deref(),
+            Some("/foo")
+        );
 
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+        let config = |preserve: bool| {
+            json!({
+                "preserveLeadingDoubleSlash": preserve,
+                "services": [
+                    { "name": "fooservice", "paths": { "/foo": {} } },
+                    { "name": "legacyservice", "paths": { "//legacy": {} } }
+                ]
+            })
+        };
+        let service = |root_ctx: &OpenapiEndpointRoot, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            endpoint(http_ctx.get_path_template(None, "get", path, &no_headers))
+                .map(|(_, service)| service.to_string())
         };
 
-        let test_cases = vec![
-            (
-                "/users/42?sortBy=name&order=asc",
-                Some((
-                    "/users/{id}".to_string(),
-                    Rc::new("userservice".to_string()),
-                )),
-            ),
-            (
-                "/products?category=electronics&inStock=true",
-                Some((
-                    "/products".to_string(),
-                    Rc::new("productservice".to_string()),
-                )),
-            ),
-            (
-                "/categories/books/products?featured=true&limit=10",
-                Some((
-                    "/categories/{category_id}/products".to_string(),
-                    Rc::new("productservice".to_string()),
-                )),
-            ),
-        ];
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false)).unwrap();
+        assert_eq!(service(&root_ctx, "//foo").as_deref(), Some("fooservice"));
+        assert_eq!(
+            service(&root_ctx, "/legacy").as_deref(),
+            Some("legacyservice")
+        );
 
-        for (input_path, expected) in test_cases {
-            let result = http_ctx.get_path_template(None, "get", input_path);
-            assert_eq!(
-                result, expected,
-                "Path with query params '{}' should match '{:?}' but got '{:?}'",
-                input_path, expected, result
-            );
-        }
+        root_ctx.configure(&config(true)).unwrap();
+        assert_eq!(service(&root_ctx, "//foo"), None);
+        assert_eq!(service(&root_ctx, "/foo").as_deref(), Some("fooservice"));
+        assert_eq!(
+            service(&root_ctx, "//legacy").as_deref(),
+            Some("legacyservice")
+        );
+        assert_eq!(service(&root_ctx, "/legacy"), None);
     }
 
     #[test]
-    fn test_config_path_normalization() {
-        let config = json!({
-            "services": [
-                {
-                    "name": "userservice",
-                    "paths": {
-                        "/users/": {},
-                        "/api//v1/data": {}
+    fn test_emit_produces() {
+        let config = |emit_produces: bool| {
+            json!({
+                "emitProduces": emit_produces,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": {
+                            "/users/{id}": {
+                                "get": {
+                                    "responses": {
+                                        "404": { "content": { "application/problem+json": {} } },
+                                        "200": {
+                                            "content": {
+                                                "application/json": {},
+                                                "application/xml": {}
+                                            }
+                                        }
+                                    }
+                                },
+                                "put": { "produces": ["text/plain", "application/json"] },
+                                "delete": { "responses": { "204": {} } }
+                            },
+                            "/health": {}
+                        }
                     }
-                }
-            ]
-        });
-
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx.configure(&config).unwrap();
-
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+                ]
+            })
+        };
+        let produces = |root_ctx: &OpenapiEndpointRoot, method: &str, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            let matched = http_ctx.get_path_template(None, method, path, &no_headers);
+            assert!(matched.is_some());
+            http_ctx
+                .route_headers(&method.to_ascii_uppercase(), matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-produces")
+                .map(|(_, value)| value)
         };
 
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
         assert_eq!(
-            http_ctx.get_path_template(None, "get", "/users"),
-            Some(("/users".to_string(), Rc::new("userservice".to_string())))
+            produces(&root_ctx, "get", "/users/42").as_deref(),
+            Some("application/json")
         );
         assert_eq!(
-            http_ctx.get_path_template(None, "get", "/api/v1/data"),
-            Some(("/api/v1/data".to_string(), Rc::new("userservice".to_string())))
+            produces(&root_ctx, "put", "/users/42").as_deref(),
+            Some("text/plain")
         );
+        assert_eq!(produces(&root_ctx, "delete", "/users/42"), None);
+        assert_eq!(produces(&root_ctx, "get", "/health"), None);
+
+        root_ctx.configure(&config(false)).unwrap();
+        assert_eq!(produces(&root_ctx, "get", "/users/42"), None);
     }
 
     #[test]
-    fn test_advanced_path_normalization() {
-        let test_cases = vec![
-            // Trailing slash
-            ("/users/", "/users"),
-            ("/products/", "/products"),
-            // Root path should remain as /
-            ("/", "/"),
-            // Fragment removal
-            ("/users#section", "/users"),
-            ("/products#top", "/products"),
-            // Duplicate slashes
-            ("/users//profile", "/users/profile"),
-            ("//users", "/users"),
-            ("/users///profile///", "/users/profile"),
-            ("///users///profile///", "/users/profile"),
-            // Combined: query + fragment + trailing slash
-            ("/users/?query=1#section", "/users"),
-            ("/products?a=1&b=2#top", "/products"),
-            // Combined: duplicate slashes + query + fragment
-            ("//users//profile//?key=value#section", "/users/profile"),
-            // Complex real-world cases
-            ("/api//v1///users/{id}/?format=json#details", "/api/v1/users/{id}"),
-            ("/dockebi/v1/stuff/{id_}//child/{child_id}/hello/?test=1", "/dockebi/v1/stuff/{id_}/child/{child_id}/hello"),
-        ];
+    fn test_denied_hosts() {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
 
-        for (input, expected) in test_cases {
-            let result = normalize_path(input);
+        let config = json!({
+            "deniedHosts": ["Compromised.example.com:443"],
+            "rootService": "rootservice",
+            "routeTokenSecret": "s3cret",
+            "autoOptions": true,
+            "services": [
+                {
+                    "name": "tenantservice",
+                    "servers": [
+                        { "url": "https://api.example.com" },
+                        { "url": "https://compromised.example.com" }
+                    ],
+                    "paths": { "/users/{id}": { "get": {} } }
+                },
+                { "name": "publicservice", "paths": { "/status": {} } },
+                { "name": "rootservice", "paths": { "/other": {} } }
+            ]
+        });
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(b"billing|/invoices/{id}|3000");
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let token = format!("billing|/invoices/{{id}}|3000|{}", signature);
+
+        // Blocked for its own routes, wildcard routes, rootService and route
+        // tokens alike, by the one check made before any of them
+        for host in ["api.example.com", "compromised.example.com"] {
+            let request = |path: &'static str, method: &'static str| {
+                [
+                    (":path", path),
+                    (":method", method),
+                    (":authority", host),
+                    ("x-oef-route-token", token.as_str()),
+                ]
+            };
+            let service = |path: &'static str| {
+                run_request_headers(&config, &request(path, "GET")[..3], 3);
+                host::request_header("x-service-name")
+            };
+            let blocked = host == "compromised.example.com";
+            let expected = |service: &'static str| Some(if blocked { "unknown" } else { service });
+            assert_eq!(service("/users/42").as_deref(), expected("tenantservice"));
+            assert_eq!(service("/status").as_deref(), expected("publicservice"));
+            assert_eq!(service("/").as_deref(), expected("rootservice"));
+
+            run_request_headers(&config, &request("/users/42", "GET"), 4);
             assert_eq!(
-                result, expected,
-                "normalize_path('{}') should return '{}' but got '{}'",
-                input, expected, result
+                host::request_header("x-service-name").as_deref(),
+                expected("billing")
             );
+            // No Allow listing either
+            let (_, action) =
+                run_request_headers(&config, &request("/users/42", "OPTIONS")[..3], 3);
+            assert_eq!(action == Action::Pause, !blocked);
         }
     }
 
     #[test]
-    fn test_normalized_path_matching() {
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx
-            .configure(&serde_json::from_str(TEST_CONFIG).unwrap())
-            .unwrap();
+    fn test_path_whitespace() {
+        let options = NormalizeOptions {
+            trim_path: true,
+            reject_whitespace: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            normalize_path_with(" \t/users/42/ \r\n", &options).as_deref(),
+            Some("/users/42")
+        );
+        assert_eq!(normalize_path_with("/users/4\t2", &options), None);
+        assert_eq!(normalize_path_with("/users/4 2", &options), None);
+        assert_eq!(normalize_path_with("/users/4\x0b2", &options), None);
+        // The query is not checked
+        assert_eq!(
+            normalize_path_with("/users/42?q=a b", &options).as_deref(),
+            Some("/users/42")
+        );
+        // By default whitespace is kept, and ends up in segments
+        assert_eq!(
+            normalize_path_with(" /users/42", &NormalizeOptions::default()).as_deref(),
+            Some("/ /users/42")
+        );
 
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+        let config = |trim: bool, reject: bool| {
+            json!({
+                "trimPath": trim,
+                "rejectWhitespacePath": reject,
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            })
         };
+        let template = |root_ctx: &OpenapiEndpointRoot, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            endpoint(http_ctx.get_path_template(None, "get", path, &no_headers))
+                .map(|(template, _)| template.to_string())
+        };
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(false, false)).unwrap();
+        assert_eq!(template(&root_ctx, "  /users/42  "), None);
+        assert!(template(&root_ctx, "/users/4\t2").is_some());
 
-        // Test that normalized paths match correctly
-        let test_cases = vec![
-            // Trailing slash should match
-            (
-                "/users/",
-                Some(("/users".to_string(), Rc::new("userservice".to_string()))),
-            ),
-            // Fragment should be ignored
-            (
-                "/users#section",
-                Some(("/users".to_string(), Rc::new("userservice".to_string()))),
-            ),
-            // Duplicate slashes should match
-            (
-                "/users//42",
-                Some((
-                    "/users/{id}".to_string(),
-                    Rc::new("userservice".to_string()),
-                )),
-            ),
-            // Combined normalization
-            (
-                "/users/42//profile/?query=1#section",
-                Some((
-                    "/users/{id}/profile".to_string(),
-                    Rc::new("userservice".to_string()),
-                )),
-            ),
-            // Root path
-            ("/", None),
-        ];
+        root_ctx.configure(&config(true, false)).unwrap();
+        assert_eq!(
+            template(&root_ctx, "  /users/42  ").as_deref(),
+            Some("/users/{id}")
+        );
+        assert!(template(&root_ctx, "/users/4\t2").is_some());
 
-        for (input_path, expected) in test_cases {
-            let result = http_ctx.get_path_template(None, "get", input_path);
-            assert_eq!(
-                result, expected,
-                "Path '{}' should match '{:?}' but got '{:?}'",
-                input_path, expected, result
-            );
-        }
+        root_ctx.configure(&config(true, true)).unwrap();
+        assert!(template(&root_ctx, "  /users/42  ").is_some());
+        assert_eq!(template(&root_ctx, "/users/4\t2"), None);
     }
 
     #[test]
-    fn test_complex_path_patterns() {
-        let config = json!({
-            "services": [
-                {
-                    "name": "complexapi",
+    fn test_large_body_service() {
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "fileservice",
                     "paths": {
-                        "/api/v1/resources/{resource_id}/subresources/{subresource_id}/items/{item_id}": {},
-                        "/api/v1/users/{user_id}/orders/{order_id}/items/{item_id}/tracking": {},
-                        "/{tenant_id}/dashboard": {}
+                        "/files/{name}": {
+                            "x-large-body-service": "uploadservice",
+                            "x-large-body-threshold": 1048576,
+                            "put": {}
+                        },
+                        "/files": {}
                     }
-                }
-            ]
-        });
+                }]
+            }))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let service = |path: &str, content_length: Option<&str>| {
+            let get_header = |name: &str| {
+                (name == "content-length")
+                    .then(|| content_length.map(str::to_string))
+                    .flatten()
+            };
+            let matched = http_ctx.get_path_template(None, "put", path, &get_header);
+            endpoint(matched).map(|(_, service)| service.to_string())
+        };
 
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx.configure(&config).unwrap();
+        assert_eq!(
+            service("/files/a.iso", Some("1048577")).as_deref(),
+            Some("uploadservice")
+        );
+        assert_eq!(
+            service("/files/a.iso", Some(" 1048576")).as_deref(),
+            Some("fileservice")
+        );
+        assert_eq!(
+            service("/files/a.iso", Some("12")).as_deref(),
+            Some("fileservice")
+        );
+        // Missing or invalid lengths keep the default service
+        assert_eq!(
+            service("/files/a.iso", None).as_deref(),
+            Some("fileservice")
+        );
+        assert_eq!(
+            service("/files/a.iso", Some("-5")).as_deref(),
+            Some("fileservice")
+        );
+        assert_eq!(
+            service("/files/a.iso", Some("big")).as_deref(),
+            Some("fileservice")
+        );
+        // Other paths are unaffected
+        assert_eq!(
+            service("/files", Some("99999999")).as_deref(),
+            Some("fileservice")
+        );
 
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+        let error = root_ctx
+            .configure(&json!({
+                "services": [{
+                    "name": "fileservice",
+                    "paths": { "/files/{name}": { "x-large-body-service": "uploadservice" } }
+                }]
+            }))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("together"));
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let config = |tie_break: Option<&str>, services: Value| {
+            let mut config = json!({ "services": services });
+            if let Some(tie_break) = tie_break {
+                config["tieBreak"] = json!(tie_break);
+            }
+            config
+        };
+        let overlapping = || {
+            json!([
+                { "name": "beta", "paths": { "/health": {} } },
+                { "name": "alpha", "paths": { "/health": {} } }
+            ])
         };
 
-        let test_cases = vec![
-            (
-                "/api/v1/resources/r123/subresources/sub456/items/i789",
-                Some((
-                    "/api/v1/resources/{resource_id}/subresources/{subresource_id}/items/{item_id}"
-                        .to_string(),
-                    Rc::new("complexapi".to_string()),
-                )),
-            ),
-            (
-                "/api/v1/users/u123/orders/o456/items/i789/tracking",
-                Some((
-                    "/api/v1/users/{user_id}/orders/{order_id}/items/{item_id}/tracking"
-                        .to_string(),
-                    Rc::new("complexapi".to_string()),
-                )),
-            ),
-            (
-                "/tenant1/dashboard",
-                Some((
-                    "/{tenant_id}/dashboard".to_string(),
-                    Rc::new("complexapi".to_string()),
-                )),
-            ),
-            ("/api/v1/resources/r123/something_else", None),
-        ];
+        for (tie_break, service) in [
+            (None, "beta"),
+            (Some("config-order"), "beta"),
+            (Some("service-name"), "alpha"),
+        ] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let mut config = config(tie_break, overlapping());
+            config["onExactOverlap"] = json!("first");
+            root_ctx.configure(&config).unwrap();
+            let http_ctx = root_ctx.create_filter();
+            assert_eq!(
+                endpoint(http_ctx.get_path_template(None, "get", "/health", &no_headers)),
+                Some(("/health", service))
+            );
+        }
 
-        for (input_path, expected) in test_cases {
-            let result = http_ctx.get_path_template(None, "get", input_path);
+        // A method-specific and an any-method route under preferLongestTemplate
+        let equal_length = || {
+            json!([
+                { "name": "beta", "paths": { "/items/{id}": { "get": {} } } },
+                { "name": "alpha", "paths": { "/items/{id}": {} } }
+            ])
+        };
+        for (tie_break, service) in [(None, "beta"), (Some("service-name"), "alpha")] {
+            let mut root_ctx = OpenapiEndpointRoot::new();
+            let mut config = config(tie_break, equal_length());
+            config["preferLongestTemplate"] = json!(true);
+            root_ctx.configure(&config).unwrap();
+            let http_ctx = root_ctx.create_filter();
             assert_eq!(
-                result, expected,
-                "Complex path '{}' should match '{:?}' but got '{:?}'",
-                input_path, expected, result
+                endpoint(http_ctx.get_path_template(None, "get", "/items/1", &no_headers)),
+                Some(("/items/{id}", service))
             );
         }
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        let error = root_ctx
+            .configure(&config(Some("random"), overlapping()))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("'tieBreak' must be"));
     }
 
     #[test]
-    fn test_servers_and_method_matching() {
-        let config = json!({
-            "services": [
-                {
-                    "name": "userservice",
-                    "servers": [
-                        {
-                            "url": "https://{env}.example.com/v1",
-                            "variables": {
-                                "env": {
-                                    "default": "api",
-                                    "enum": ["api", "staging"]
-                                }
-                            }
+    fn test_emit_security_scheme() {
+        let config = |emit_security_scheme: bool| {
+            json!({
+                "emitSecurityScheme": emit_security_scheme,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "security": [{ "apiKey": [] }],
+                        "paths": {
+                            "/users/{id}": {
+                                "get": {
+                                    "security": [
+                                        { "oauth2": ["users:read"] },
+                                        { "apiKey": [] }
+                                    ]
+                                },
+                                "put": {},
+                                "delete": { "security": [] }
+                            },
+                            "/health": {}
                         }
-                    ],
-                    "paths": {
-                        "/users": {
-                            "get": {}
-                        },
-                        "/admin": {}
                     }
-                }
-            ]
-        });
-
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx.configure(&config).unwrap();
-
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+                ]
+            })
+        };
+        let scheme = |root_ctx: &OpenapiEndpointRoot, method: &str, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            let matched = http_ctx.get_path_template(None, method, path, &no_headers);
+            assert!(matched.is_some());
+            http_ctx
+                .route_headers(&method.to_ascii_uppercase(), matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-security-scheme")
+                .map(|(_, value)| value)
         };
 
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
         assert_eq!(
-            http_ctx.get_path_template(Some("api.example.com"), "get", "/v1/users"),
-            Some(("/users".to_string(), Rc::new("userservice".to_string())))
-        );
-        assert_eq!(
-            http_ctx.get_path_template(Some("staging.example.com"), "post", "/v1/admin"),
-            Some(("/admin".to_string(), Rc::new("userservice".to_string())))
-        );
-        assert_eq!(
-            http_ctx.get_path_template(Some("api.example.com"), "post", "/v1/users"),
-            None
-        );
-        assert_eq!(
-            http_ctx.get_path_template(Some("api.example.com"), "get", "/users"),
-            None
+            scheme(&root_ctx, "get", "/users/42").as_deref(),
+            Some("oauth2")
         );
+        // The service-wide requirement applies unless the operation overrides it
         assert_eq!(
-            http_ctx.get_path_template(Some("other.example.com"), "get", "/v1/users"),
-            None
+            scheme(&root_ctx, "put", "/users/42").as_deref(),
+            Some("apiKey")
         );
+        assert_eq!(scheme(&root_ctx, "delete", "/users/42"), None);
+        assert_eq!(scheme(&root_ctx, "get", "/health"), None);
+
+        root_ctx.configure(&config(false)).unwrap();
+        assert_eq!(scheme(&root_ctx, "get", "/users/42"), None);
     }
 
     #[test]
-    fn test_host_matching_disabled() {
-        let config = json!({
-            "useHostInMatch": false,
-            "services": [
-                {
+    fn test_normalization_profile() {
+        let config = |profile: &str, overrides: Value| {
+            let mut config = json!({
+                "normalizationProfile": profile,
+                "services": [{
                     "name": "userservice",
-                    "servers": [
-                        { "url": "https://api.example.com/v1" }
-                    ],
                     "paths": {
-                        "/users": {
-                            "get": {}
-                        }
+                        "/users/{id}": {},
+                        "/files/{name}": {},
+                        "//legacy": {}
                     }
-                }
-            ]
-        });
+                }]
+            });
+            for (key, value) in overrides.as_object().unwrap() {
+                config[key] = value.clone();
+            }
+            config
+        };
+        let param = |root_ctx: &OpenapiEndpointRoot, path: &str| {
+            let http_ctx = root_ctx.create_filter();
+            http_ctx
+                .get_path_template(None, "get", path, &no_headers)
+                .map(|matched| {
+                    matched
+                        .params
+                        .iter()
+                        .map(|(_, value)| value.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+        };
 
         let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx.configure(&config).unwrap();
+        root_ctx.configure(&config("strict", json!({}))).unwrap();
+        assert_eq!(param(&root_ctx, "  /users/42"), None);
+        assert_eq!(param(&root_ctx, "/users/4 2"), None);
+        assert_eq!(param(&root_ctx, "/a/../users/42"), None);
+        assert_eq!(param(&root_ctx, "/files/a%2fb").as_deref(), Some("a%2fb"));
+        assert_eq!(param(&root_ctx, "/files/a+b").as_deref(), Some("a+b"));
+        assert_eq!(param(&root_ctx, "//users/42"), None);
+        assert!(param(&root_ctx, "//legacy").is_some());
 
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
-        };
+        root_ctx.configure(&config("lenient", json!({}))).unwrap();
+        assert_eq!(param(&root_ctx, "  /users/42").as_deref(), Some("42"));
+        assert_eq!(param(&root_ctx, "/a/../users/42").as_deref(), Some("42"));
+        assert_eq!(param(&root_ctx, "/files/a%2fb").as_deref(), Some("a%2Fb"));
+        assert_eq!(param(&root_ctx, "/files/a+b").as_deref(), Some("a%20b"));
+        assert_eq!(param(&root_ctx, "//users/42").as_deref(), Some("42"));
 
-        assert_eq!(
-            http_ctx.get_path_template(Some("other.example.com"), "get", "/v1/users"),
-            Some(("/users".to_string(), Rc::new("userservice".to_string())))
-        );
+        root_ctx.configure(&config("rfc3986", json!({}))).unwrap();
+        assert_eq!(param(&root_ctx, "  /users/42"), None);
+        assert_eq!(param(&root_ctx, "/users/4 2"), None);
+        assert_eq!(param(&root_ctx, "/a/../users/42").as_deref(), Some("42"));
+        assert_eq!(param(&root_ctx, "/files/a%2fb").as_deref(), Some("a%2Fb"));
+        assert_eq!(param(&root_ctx, "/files/a+b").as_deref(), Some("a+b"));
+        assert_eq!(param(&root_ctx, "//users/42").as_deref(), Some("42"));
+
+        // Explicit keys override the profile
+        root_ctx
+            .configure(&config("strict", json!({ "trimPath": true })))
+            .unwrap();
+        assert_eq!(param(&root_ctx, "  /users/42").as_deref(), Some("42"));
+        assert_eq!(param(&root_ctx, "/files/a%2fb").as_deref(), Some("a%2fb"));
+
+        let error = root_ctx
+            .configure(&config("relaxed", json!({})))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("'normalizationProfile' must be"));
     }
 
     #[test]
-    fn test_invalid_configurations() {
-        let test_cases = vec![
-            // Missing services array
-            (
-                json!({
-                    "cacheSize": 10
-                }),
-                "Invalid or missing 'services' in configuration",
-            ),
-            // Service without name
-            (
-                json!({
-                    "services": [
-                        {
-                            "paths": {
-                                "/test": {}
-                            }
-                        }
-                    ]
-                }),
-                "Missing 'name' in service configuration",
-            ),
-            // Service without paths
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test"
-                        }
-                    ]
-                }),
-                "Invalid or missing 'paths' in service configuration",
-            ),
-            // Invalid paths type
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test",
-                            "paths": "invalid"
-                        }
-                    ]
-                }),
-                "Invalid or missing 'paths' in service configuration",
-            ),
-            // Empty services array
-            (
-                json!({
-                    "services": []
-                }),
-                "Services array cannot be empty",
-            ),
-            // Empty service name
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "",
-                            "paths": {
-                                "/test": {}
-                            }
-                        }
-                    ]
-                }),
-                "Service name cannot be empty",
-            ),
-            // Empty paths object
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test",
-                            "paths": {}
-                        }
-                    ]
-                }),
-                "has no paths",
-            ),
-            // Path not starting with /
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test",
-                            "paths": {
-                                "test": {}
-                            }
-                        }
-                    ]
-                }),
-                "must start with '/'",
-            ),
-            // Path too long
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test",
-                            "paths": {
-                                format!("/{}", "x".repeat(1024)): {}
-                            }
-                        }
-                    ]
-                }),
-                "too long",
-            ),
-            // Path with space
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "test",
-                            "paths": {
-                                "/test path": {}
-                            }
-                        }
-                    ]
-                }),
-                "contains space",
-            ),
-            // Duplicate paths
-            (
-                json!({
-                    "services": [
-                        {
-                            "name": "service1",
-                            "paths": {
-                                "/test": {}
-                            }
-                        },
-                        {
-                            "name": "service2",
-                            "paths": {
-                                "/test": {}
-                            }
+    fn test_method_aliases() {
+        let config = |aliases: Value| {
+            json!({
+                "services": [{
+                    "name": "userservice",
+                    "paths": {
+                        "/users/{id}": {
+                            "x-method-aliases": aliases,
+                            "get": {},
+                            "put": { "summary": "Replace user" }
                         }
-                    ]
-                }),
-                "Duplicate or conflicting route",
-            ),
-        ];
+                    }
+                }]
+            })
+        };
 
-        for (config, expected_error) in test_cases {
-            let mut root_ctx = OpenapiEndpointRoot::new();
-            let result = root_ctx.configure(&config);
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx
+            .configure(&config(json!({ "PATCH": "put" })))
+            .unwrap();
+        let http_ctx = root_ctx.create_filter();
+        let matched = http_ctx.get_path_template(None, "patch", "/users/42", &no_headers);
+        let matched = matched.unwrap();
+        assert_eq!(matched.template, "/users/{id}");
+        assert_eq!(matched.method, Some("put"));
+        assert_eq!(matched.params, vec![("id".to_string(), "42".to_string())]);
+        let headers = http_ctx.route_headers("PATCH", Some(&matched));
+        assert!(headers.contains(&("x-api-endpoint".to_string(), "PUT /users/{id}".to_string())));
+        // Declared methods are unaffected, and other methods still miss
+        let matched = http_ctx.get_path_template(None, "put", "/users/42", &no_headers);
+        assert_eq!(matched.unwrap().method, Some("put"));
+        assert!(http_ctx
+            .get_path_template(None, "post", "/users/42", &no_headers)
+            .is_none());
 
-            assert!(result.is_err(), "Configuration should fail: {:?}", config);
-            let error = result.err().unwrap();
-            assert!(
-                error.to_string().contains(expected_error),
-                "Error message should contain '{}', but got '{}'",
-                expected_error,
-                error
-            );
+        // Without aliases, PATCH misses
+        root_ctx.configure(&config(json!({}))).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx
+            .get_path_template(None, "patch", "/users/42", &no_headers)
+            .is_none());
+
+        for aliases in [
+            json!({ "patch": "delete" }),
+            json!({ "get": "put" }),
+            json!({ "fetch": "get" }),
+            json!(["patch"]),
+        ] {
+            let error = root_ctx.configure(&config(aliases)).err().unwrap();
+            assert!(error.to_string().contains("'x-method-aliases'"));
         }
     }
 
     #[test]
-    fn test_multiple_service_overlapping_routes() {
+    fn test_expect_continue_unmatched() {
         let config = json!({
-            "services": [
-                {
-                    "name": "service1",
-                    "paths": {
-                        "/api/v1/shared": {},
-                        "/api/v1/service1/specific": {}
-                    }
-                },
-                {
-                    "name": "service2",
-                    "paths": {
-                        "/api/v1/shared/{id}": {},
-                        "/api/v1/service2/specific": {}
-                    }
-                }
-            ]
+            "rejectUnmatched": true,
+            "unmatchedBody": "no route for {method} {path}",
+            "services": [{ "name": "uploadservice", "paths": { "/uploads": { "put": {} } } }]
         });
-
-        let mut root_ctx = OpenapiEndpointRoot::new();
-        root_ctx.configure(&config).unwrap();
-
-        let http_ctx = OpenapiEndpointFilter {
-            router_set: Rc::clone(&root_ctx.router_set),
-            preserve_existing_headers: true,
-            config_error: None,
+        let request = |path: &'static str| {
+            [
+                (":path", path),
+                (":method", "PUT"),
+                ("expect", "100-continue"),
+                ("content-length", "1048576"),
+            ]
         };
 
-        // For exact path matches, the first service should win
+        // The 404 goes out from the header phase, before any body is asked for
+        let (_, action) = run_request_headers(&config, &request("/nope"), 4);
+        assert_eq!(action, Action::Pause);
         assert_eq!(
-            http_ctx.get_path_template(None, "get", "/api/v1/shared"),
+            host::with(|exchange| exchange.local_response.clone()),
             Some((
-                "/api/v1/shared".to_string(),
-                Rc::new("service1".to_string())
+                404,
+                vec![("content-type".to_string(), "text/plain".to_string())],
+                b"no route for PUT /nope".to_vec()
             ))
         );
 
-        // For parameterized paths, the match should work correctly
-        assert_eq!(
-            http_ctx.get_path_template(None, "get", "/api/v1/shared/123"),
-            Some((
-                "/api/v1/shared/{id}".to_string(),
-                Rc::new("service2".to_string())
-            ))
-        );
+        // Matched requests are left to the proxy's own 100-continue handling,
+        // and their body is not held without prependRouteToBody
+        let (mut http_ctx, action) = run_request_headers(&config, &request("/uploads"), 4);
+        assert_eq!(action, Action::Continue);
+        assert_eq!(host::with(|exchange| exchange.local_response.clone()), None);
+        assert_eq!(http_ctx.on_http_request_body(16, false), Action::Continue);
+    }
 
-        // Service-specific paths should go to the correct service
+    #[test]
+    fn test_template_stats() {
+        let config = |template_stats: bool| {
+            json!({
+                "templateStats": template_stats,
+                "debugHeaders": true,
+                "services": [
+                    {
+                        "name": "userservice",
+                        "paths": { "/users/{id}": {}, "/users/{id}/posts/{postId}": {} }
+                    },
+                    { "name": "orderservice", "paths": { "/orders/{id}": {} } }
+                ]
+            })
+        };
+        let hits = |http_ctx: &OpenapiEndpointFilter, path: &str| {
+            let matched = http_ctx.get_path_template(None, "get", path, &no_headers);
+            assert!(matched.is_some());
+            http_ctx
+                .route_headers("GET", matched.as_ref())
+                .into_iter()
+                .find(|(name, _)| name == "x-oef-template-hits")
+                .map(|(_, value)| value)
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        for id in 1..=50 {
+            http_ctx.get_path_template(None, "get", &format!("/users/{}", id), &no_headers);
+            if id % 5 == 0 {
+                let path = format!("/users/{}/posts/{}", id, id * 2);
+                http_ctx.get_path_template(None, "get", &path, &no_headers);
+            }
+        }
+        http_ctx.get_path_template(None, "get", "/missing", &no_headers);
+        let template_hits = http_ctx.router_set.template_hits.as_ref().unwrap();
+        assert_eq!(template_hits.count("userservice", "/users/{id}"), 50);
         assert_eq!(
-            http_ctx.get_path_template(None, "get", "/api/v1/service1/specific"),
-            Some((
-                "/api/v1/service1/specific".to_string(),
-                Rc::new("service1".to_string())
-            ))
+            template_hits.count("userservice", "/users/{id}/posts/{postId}"),
+            10
         );
+        assert_eq!(template_hits.count("orderservice", "/orders/{id}"), 0);
+
+        // Filters of a worker share the counts, and the header shows them
+        let other_ctx = root_ctx.create_filter();
+        assert_eq!(hits(&other_ctx, "/users/51").as_deref(), Some("51"));
+        assert_eq!(hits(&other_ctx, "/orders/7").as_deref(), Some("1"));
 
+        // Reconfiguration starts over
+        root_ctx.configure(&config(false)).unwrap();
+        root_ctx.configure(&config(true)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert_eq!(hits(&http_ctx, "/users/1").as_deref(), Some("1"));
+
+        root_ctx.configure(&config(false)).unwrap();
+        let http_ctx = root_ctx.create_filter();
+        assert!(http_ctx.router_set.template_hits.is_none());
+        assert_eq!(hits(&http_ctx, "/users/1"), None);
+    }
+
+    #[test]
+    fn test_dynamic_target_header() {
+        let config = |allow: bool| {
+            json!({
+                "allowDynamicTargetHeader": allow,
+                "dynamicTargetHeaders": ["X-Route", "x-gateway-endpoint"],
+                "services": [{ "name": "userservice", "paths": { "/users/{id}": {} } }]
+            })
+        };
+        let target = |root_ctx: &OpenapiEndpointRoot, path: &str, requested: &str| {
+            let http_ctx = root_ctx.create_filter();
+            let requested = requested.to_string();
+            let get_header =
+                |name: &str| (name == "x-oef-target-header").then(|| requested.clone());
+            let matched = http_ctx.get_path_template(None, "get", path, &get_header);
+            let headers = http_ctx.route_headers("GET", matched.as_ref());
+            http_ctx.dynamic_target_header(&headers, matched.is_some(), &get_header)
+        };
+
+        let mut root_ctx = OpenapiEndpointRoot::new();
+        root_ctx.configure(&config(true)).unwrap();
         assert_eq!(
-            http_ctx.get_path_template(None, "get", "/api/v1/service2/specific"),
-            Some((
-                "/api/v1/service2/specific".to_string(),
-                Rc::new("service2".to_string())
-            ))
+            target(&root_ctx, "/users/42", " X-Route "),
+            Some(("x-route".to_string(), "GET /users/{id}".to_string()))
         );
+        // Names outside the allowlist are ignored, as are misses
+        assert_eq!(target(&root_ctx, "/users/42", "x-service-name"), None);
+        assert_eq!(target(&root_ctx, "/users/42", "x-route\r\nx-evil: 1"), None);
+        assert_eq!(target(&root_ctx, "/nope", "x-route"), None);
+
+        root_ctx.configure(&config(false)).unwrap();
+        assert_eq!(target(&root_ctx, "/users/42", "x-route"), None);
+
+        let services = json!([{ "name": "userservice", "paths": { "/users/{id}": {} } }]);
+        for (names, message) in [
+            (None, "needs a non-empty 'dynamicTargetHeaders'"),
+            (Some(json!([])), "needs a non-empty 'dynamicTargetHeaders'"),
+            (
+                Some(json!(["x route"])),
+                "Invalid header name in 'dynamicTargetHeaders'",
+            ),
+        ] {
+            let mut config = json!({ "allowDynamicTargetHeader": true, "services": services });
+            if let Some(names) = names {
+                config["dynamicTargetHeaders"] = names;
+            }
+            let error = root_ctx.configure(&config).err().unwrap();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
     }
 }
@@ -0,0 +1,64 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::LogLevel;
+use std::cell::RefCell;
+
+const LOG_PREFIX: &str = "[oef]";
+
+thread_local! {
+    /// Tenant of this VM from the VM configuration, if any.
+    static TENANT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Forwards log records to the host like the proxy-wasm logger, with the
+/// `[oef]` prefix namespaced by the VM's tenant.
+struct TenantLogger;
+
+static LOGGER: TenantLogger = TenantLogger;
+
+/// Installs the logger in place of `proxy_wasm::set_log_level`.
+pub(crate) fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        std::panic::set_hook(Box::new(|panic_info| {
+            let _ = hostcalls::log(LogLevel::Critical, &panic_info.to_string());
+        }));
+    }
+    log::set_max_level(level);
+}
+
+pub(crate) fn set_tenant(tenant: Option<String>) {
+    TENANT.with(|current| *current.borrow_mut() = tenant);
+}
+
+/// Rewrites a leading `[oef]` to `[oef:<tenant>]`, so that logs of VMs
+/// serving different tenants can be told apart.
+pub(crate) fn namespace_log_line(tenant: Option<&str>, line: &str) -> String {
+    match (tenant, line.strip_prefix(LOG_PREFIX)) {
+        (Some(tenant), Some(rest)) => format!("[oef:{}]{}", tenant, rest),
+        _ => line.to_string(),
+    }
+}
+
+impl Log for TenantLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Trace => LogLevel::Trace,
+            Level::Debug => LogLevel::Debug,
+            Level::Info => LogLevel::Info,
+            Level::Warn => LogLevel::Warn,
+            Level::Error => LogLevel::Error,
+        };
+        let line = record.args().to_string();
+        let line = TENANT.with(|tenant| namespace_log_line(tenant.borrow().as_deref(), &line));
+        let _ = hostcalls::log(level, &line);
+    }
+
+    fn flush(&self) {}
+}
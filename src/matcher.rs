@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use crate::router::Route;
+
+/// Which [`PathMatcher`] implementation holds a route group's templates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MatchEngine {
+    /// The `matchit` radix tree.
+    Matchit,
+    /// A tree with one level per path segment, see [`SegmentTrie`].
+    Trie,
+}
+
+impl MatchEngine {
+    pub(crate) fn matcher(self) -> Box<dyn PathMatcher> {
+        match self {
+            Self::Matchit => Box::new(MatchitMatcher(matchit::Router::new())),
+            Self::Trie => Box::new(SegmentTrie::default()),
+        }
+    }
+}
+
+/// A route found by [`PathMatcher::at`], with parameter values borrowed
+/// from the matched path.
+pub(crate) struct PathMatch<'m, 'p> {
+    pub(crate) value: &'m Route,
+    pub(crate) params: Vec<(&'m str, &'p str)>,
+}
+
+/// Maps path templates in `matchit` syntax (`{name}`, `{*rest}`) to routes.
+pub(crate) trait PathMatcher {
+    /// Registers `route` under `path`, failing if the template is invalid or
+    /// conflicts with one already registered.
+    fn insert(&mut self, path: &str, route: Route) -> Result<(), String>;
+
+    fn at<'m, 'p>(&'m self, path: &'p str) -> Option<PathMatch<'m, 'p>>;
+
+    fn at_mut(&mut self, path: &str) -> Option<&mut Route>;
+
+    /// Removes the route registered under exactly `path`.
+    fn remove(&mut self, path: &str) -> Option<Route>;
+}
+
+struct MatchitMatcher(matchit::Router<Route>);
+
+impl PathMatcher for MatchitMatcher {
+    fn insert(&mut self, path: &str, route: Route) -> Result<(), String> {
+        self.0.insert(path, route).map_err(|e| e.to_string())
+    }
+
+    fn at<'m, 'p>(&'m self, path: &'p str) -> Option<PathMatch<'m, 'p>> {
+        let matched = self.0.at(path).ok()?;
+        Some(PathMatch {
+            value: matched.value,
+            params: matched.params.iter().collect(),
+        })
+    }
+
+    fn at_mut(&mut self, path: &str) -> Option<&mut Route> {
+        self.0.at_mut(path).ok().map(|matched| matched.value)
+    }
+
+    fn remove(&mut self, path: &str) -> Option<Route> {
+        self.0.remove(path)
+    }
+}
+
+/// One segment of a template.
+enum Token<'t> {
+    Static(&'t str),
+    /// A parameter, possibly with static text around it in its segment.
+    Param {
+        prefix: &'t str,
+        suffix: &'t str,
+        name: &'t str,
+    },
+    /// A catch-all, possibly with static text before it in its segment.
+    CatchAll {
+        prefix: &'t str,
+        name: &'t str,
+    },
+}
+
+fn tokenize(path: &str) -> Result<Vec<Token<'_>>, String> {
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err("Routes must begin with '/'".to_string());
+    };
+    let segments: Vec<&str> = rest.split('/').collect();
+    let mut tokens = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(open) = segment.find('{') else {
+            if segment.contains('}') {
+                return Err("Unmatched '}' in route".to_string());
+            }
+            tokens.push(Token::Static(segment));
+            continue;
+        };
+        let Some(close) = segment[open..].find('}').map(|close| open + close) else {
+            return Err("Unmatched '{' in route".to_string());
+        };
+        let (prefix, name, suffix) = (
+            &segment[..open],
+            &segment[open + 1..close],
+            &segment[close + 1..],
+        );
+        if prefix.contains('}') || suffix.contains(['{', '}']) {
+            return Err("Only one parameter is allowed per path segment".to_string());
+        }
+        match name.strip_prefix('*') {
+            Some(name) => {
+                if i + 1 < segments.len() || !suffix.is_empty() {
+                    return Err(
+                        "Catch-all parameters are only allowed at the end of a route".to_string(),
+                    );
+                }
+                if name.is_empty() {
+                    return Err("Parameters must be registered with a valid name".to_string());
+                }
+                tokens.push(Token::CatchAll { prefix, name });
+            }
+            None => {
+                if name.is_empty() {
+                    return Err("Parameters must be registered with a valid name".to_string());
+                }
+                tokens.push(Token::Param {
+                    prefix,
+                    suffix,
+                    name,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Leaf {
+    /// The template as inserted, for conflict messages and `remove`.
+    path: String,
+    names: Vec<String>,
+    route: Route,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    statics: HashMap<String, TrieNode>,
+    /// Parameters with static text around them, longest text first.
+    affixed: Vec<(String, String, TrieNode)>,
+    param: Option<Box<TrieNode>>,
+    /// Catch-alls by the static text before them, longest first.
+    catch_alls: Vec<(String, Leaf)>,
+    leaf: Option<Leaf>,
+}
+
+/// A tree with one level per path segment. Each segment is tried as a static
+/// segment, then as a parameter with static text around it, then as a bare
+/// parameter and finally as the start of a catch-all, backtracking to the
+/// next alternative whenever the rest of the path does not match.
+///
+/// Matches like `matchit` except that it also backtracks out of parameters
+/// with a suffix, and it accepts some combinations of prefixed and suffixed
+/// parameters in one position that `matchit` rejects as conflicting.
+#[derive(Default)]
+pub(crate) struct SegmentTrie {
+    root: TrieNode,
+}
+
+impl SegmentTrie {
+    /// The node reached by following exactly the shape of `tokens`, which
+    /// must not contain a catch-all.
+    fn node_mut(&mut self, tokens: &[Token]) -> Option<&mut TrieNode> {
+        let mut node = &mut self.root;
+        for token in tokens {
+            node = match token {
+                Token::Static(segment) => node.statics.get_mut(*segment)?,
+                Token::Param {
+                    prefix: "",
+                    suffix: "",
+                    ..
+                } => node.param.as_deref_mut()?,
+                Token::Param { prefix, suffix, .. } => node
+                    .affixed
+                    .iter_mut()
+                    .find(|(p, s, _)| p == prefix && s == suffix)
+                    .map(|(_, _, child)| child)?,
+                Token::CatchAll { .. } => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// The leaf registered under exactly `path`.
+    fn leaf_mut(&mut self, path: &str) -> Option<&mut Leaf> {
+        let tokens = tokenize(path).ok()?;
+        let leaf = match tokens.split_last()? {
+            (Token::CatchAll { prefix, .. }, parents) => self
+                .node_mut(parents)?
+                .catch_alls
+                .iter_mut()
+                .find(|(p, _)| p == prefix)
+                .map(|(_, leaf)| leaf)?,
+            _ => self.node_mut(&tokens)?.leaf.as_mut()?,
+        };
+        (leaf.path == path).then_some(leaf)
+    }
+}
+
+/// The segments of `path` with their offsets, for catch-alls.
+fn segments(path: &str) -> Option<Vec<(usize, &str)>> {
+    let rest = path.strip_prefix('/')?;
+    let mut segments = Vec::new();
+    let mut start = 1;
+    for segment in rest.split('/') {
+        segments.push((start, segment));
+        start += segment.len() + 1;
+    }
+    Some(segments)
+}
+
+impl PathMatcher for SegmentTrie {
+    fn insert(&mut self, path: &str, route: Route) -> Result<(), String> {
+        let tokens = tokenize(path)?;
+        let conflict = |existing: &Leaf| {
+            format!(
+                "Insertion failed due to conflict with previously registered route: {}",
+                existing.path
+            )
+        };
+        let mut names = Vec::new();
+        let mut node = &mut self.root;
+        for token in &tokens {
+            match token {
+                Token::Static(segment) => {
+                    node = node.statics.entry(segment.to_string()).or_default();
+                }
+                Token::Param {
+                    prefix,
+                    suffix,
+                    name,
+                } => {
+                    names.push(name.to_string());
+                    if prefix.is_empty() && suffix.is_empty() {
+                        if let Some((_, existing)) =
+                            node.catch_alls.iter().find(|(p, _)| p.is_empty())
+                        {
+                            return Err(conflict(existing));
+                        }
+                        node = node.param.get_or_insert_with(Default::default);
+                        continue;
+                    }
+                    if !suffix.is_empty() {
+                        if let Some((_, existing)) =
+                            node.catch_alls.iter().find(|(p, _)| p.is_empty())
+                        {
+                            return Err(conflict(existing));
+                        }
+                    }
+                    let position = match node
+                        .affixed
+                        .iter()
+                        .position(|(p, s, _)| p == prefix && s == suffix)
+                    {
+                        Some(position) => position,
+                        None => {
+                            let len = prefix.len() + suffix.len();
+                            let position = node
+                                .affixed
+                                .iter()
+                                .position(|(p, s, _)| p.len() + s.len() < len)
+                                .unwrap_or(node.affixed.len());
+                            node.affixed.insert(
+                                position,
+                                (prefix.to_string(), suffix.to_string(), TrieNode::default()),
+                            );
+                            position
+                        }
+                    };
+                    node = &mut node.affixed[position].2;
+                }
+                Token::CatchAll { prefix, name } => {
+                    names.push(name.to_string());
+                    if let Some((_, existing)) = node.catch_alls.iter().find(|(p, _)| p == prefix) {
+                        return Err(conflict(existing));
+                    }
+                    if prefix.is_empty()
+                        && (node.param.is_some()
+                            || node.affixed.iter().any(|(_, s, _)| !s.is_empty()))
+                    {
+                        return Err(format!(
+                            "Insertion failed due to conflict with a parameter registered before route: {}",
+                            path
+                        ));
+                    }
+                    let position = node
+                        .catch_alls
+                        .iter()
+                        .position(|(p, _)| p.len() < prefix.len())
+                        .unwrap_or(node.catch_alls.len());
+                    node.catch_alls.insert(
+                        position,
+                        (
+                            prefix.to_string(),
+                            Leaf {
+                                path: path.to_string(),
+                                names,
+                                route,
+                            },
+                        ),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(existing) = &node.leaf {
+            return Err(conflict(existing));
+        }
+        node.leaf = Some(Leaf {
+            path: path.to_string(),
+            names,
+            route,
+        });
+        Ok(())
+    }
+
+    fn at<'m, 'p>(&'m self, path: &'p str) -> Option<PathMatch<'m, 'p>> {
+        let mut values = Vec::new();
+        let leaf = find(&self.root, path, &segments(path)?, &mut values)?;
+        Some(PathMatch {
+            value: &leaf.route,
+            params: leaf.names.iter().map(String::as_str).zip(values).collect(),
+        })
+    }
+
+    fn at_mut(&mut self, path: &str) -> Option<&mut Route> {
+        let found = find(&self.root, path, &segments(path)?, &mut Vec::new())?;
+        let found = found.path.clone();
+        self.leaf_mut(&found).map(|leaf| &mut leaf.route)
+    }
+
+    fn remove(&mut self, path: &str) -> Option<Route> {
+        let tokens = tokenize(path).ok()?;
+        self.leaf_mut(path)?;
+        let leaf = match tokens.split_last()? {
+            (Token::CatchAll { prefix, .. }, parents) => {
+                let catch_alls = &mut self.node_mut(parents)?.catch_alls;
+                let position = catch_alls.iter().position(|(p, _)| p == prefix)?;
+                catch_alls.remove(position).1
+            }
+            _ => self.node_mut(&tokens)?.leaf.take()?,
+        };
+        Some(leaf.route)
+    }
+}
+
+fn find<'m, 'p>(
+    node: &'m TrieNode,
+    path: &'p str,
+    segments: &[(usize, &'p str)],
+    values: &mut Vec<&'p str>,
+) -> Option<&'m Leaf> {
+    let Some(&(start, segment)) = segments.first() else {
+        return node.leaf.as_ref();
+    };
+    let rest = &segments[1..];
+    if let Some(leaf) = node
+        .statics
+        .get(segment)
+        .and_then(|child| find(child, path, rest, values))
+    {
+        return Some(leaf);
+    }
+    for (prefix, suffix, child) in &node.affixed {
+        if segment.len() > prefix.len() + suffix.len()
+            && segment.starts_with(prefix.as_str())
+            && segment.ends_with(suffix.as_str())
+        {
+            values.push(&segment[prefix.len()..segment.len() - suffix.len()]);
+            if let Some(leaf) = find(child, path, rest, values) {
+                return Some(leaf);
+            }
+            values.pop();
+        }
+    }
+    if let Some(child) = node.param.as_deref().filter(|_| !segment.is_empty()) {
+        values.push(segment);
+        if let Some(leaf) = find(child, path, rest, values) {
+            return Some(leaf);
+        }
+        values.pop();
+    }
+    let remainder = &path[start..];
+    node.catch_alls.iter().find_map(|(prefix, leaf)| {
+        let value = remainder
+            .strip_prefix(prefix.as_str())
+            .filter(|value| !value.is_empty())?;
+        values.push(value);
+        Some(leaf)
+    })
+}
@@ -1,122 +1,1101 @@
 use log::debug;
-use matchit::Router;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
 
-#[derive(Default)]
+use crate::matcher::{MatchEngine, PathMatcher};
+use crate::sampler::Sampler;
+
+#[derive(Debug, Default)]
+pub(crate) struct Route {
+    pub(crate) template: String,
+    pub(crate) service_name: Rc<String>,
+    pub(crate) required_header: Option<String>,
+    pub(crate) summary: Option<String>,
+    /// Matched path parameter appended to the emitted service name.
+    pub(crate) service_param: Option<String>,
+    /// Timeout hint in milliseconds from the path's `x-timeout-ms`.
+    pub(crate) timeout_ms: Option<u64>,
+    /// Template as emitted in headers when the service sets a
+    /// `templateStyle` other than `openapi`.
+    pub(crate) emitted_template: Option<String>,
+    /// Whether the route was registered with its trailing slash and only
+    /// matches requests that carry one.
+    pub(crate) preserve_trailing_slash: bool,
+    /// Number of segments in the template, `0` for `/`.
+    pub(crate) depth: usize,
+    /// `Sunset` response header value from the path's `x-sunset`.
+    pub(crate) sunset: Option<String>,
+    /// First response media type of the operation, for `emitProduces`.
+    pub(crate) produces: Option<String>,
+    /// Scheme of the operation's first security requirement, for
+    /// `emitSecurityScheme`.
+    pub(crate) security_scheme: Option<String>,
+    /// The declared method this route stands in for when it is registered
+    /// under an `x-method-aliases` alias, e.g. `put` under `patch`.
+    pub(crate) canonical_method: Option<String>,
+    /// Router-safe parameter names and the template names they stand for,
+    /// see [`matchit_template`].
+    pub(crate) param_aliases: Vec<(String, String)>,
+    /// Services from `x-extensions` for requests whose last segment has
+    /// one of these extensions (e.g. `.json`).
+    pub(crate) extensions: ExtensionServices,
+    /// Service for requests whose `content-length` exceeds a threshold,
+    /// from `x-large-body-service`.
+    pub(crate) large_body: Option<LargeBody>,
+    /// Types of path parameters from the path item's `parameters` schemas.
+    pub(crate) param_types: Vec<(String, ParamType)>,
+    /// Slug of the template when `slugHeader` is set.
+    pub(crate) slug: Option<String>,
+    /// Parameter names of templates merged into this one under
+    /// `onParamNameConflict: merge`, as `(name, merged name)` pairs.
+    pub(crate) merged_param_names: Vec<(String, String)>,
+    /// Routing key value (host) of the route's group, `None` for groups
+    /// that apply to every request.
+    pub(crate) group_key: Option<String>,
+}
+
+/// Path parameter schema type whose values are coerced to canonical form.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ParamType {
+    Integer,
+    Boolean,
+}
+
+impl ParamType {
+    /// The canonical form of `value` (e.g. `42` for `042`, `true` for
+    /// `TRUE`), or `None` if it is not of this type.
+    pub(crate) fn coerce(self, value: &str) -> Option<String> {
+        match self {
+            ParamType::Integer => value.parse::<i64>().ok().map(|value| value.to_string()),
+            ParamType::Boolean => ["true", "false"]
+                .into_iter()
+                .find(|boolean| value.eq_ignore_ascii_case(boolean))
+                .map(str::to_string),
+        }
+    }
+}
+
+/// How captured parameters are checked against their schema types.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ParamCoercion {
+    /// Values are used as captured.
+    Off,
+    /// Values are coerced; a value not of its type fails the match.
+    Strict,
+    /// Values are coerced; a value not of its type is used as captured.
+    Lenient,
+}
+
+/// Extensions (lowercase, with the dot) and the services they select.
+pub(crate) type ExtensionServices = Vec<(String, Rc<String>)>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct LargeBody {
+    /// Largest `content-length` still sent to the route's own service.
+    pub(crate) threshold: u64,
+    pub(crate) service: Rc<String>,
+}
+
+impl Route {
+    /// The template name of a parameter captured under `name`.
+    fn param_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.param_aliases
+            .iter()
+            .find(|(alias, _)| alias == name)
+            .map_or(name, |(_, original)| original)
+    }
+}
+
+pub(crate) const WEBSOCKET_METHOD: &str = "websocket";
+/// Path item key that explicitly registers a route for every method.
+pub(crate) const ANY_METHOD: &str = "*";
+
+/// The result of matching a request against the configured routes.
+#[derive(Debug)]
+pub(crate) struct Match<'a> {
+    /// The matched OpenAPI path template, without the server base path.
+    pub(crate) template: &'a str,
+    pub(crate) service: &'a Rc<String>,
+    /// The operation method the route was registered under (the aliased
+    /// one for `x-method-aliases`), or `None` for path items without
+    /// operations, prefixes and WebSocket upgrades.
+    pub(crate) method: Option<&'a str>,
+    /// Path parameters captured by the template, in template order.
+    pub(crate) params: Vec<(String, String)>,
+    /// The server base path stripped before matching (empty if none).
+    pub(crate) base_path: &'a str,
+    /// Whether the route was matched as a WebSocket upgrade.
+    pub(crate) websocket: bool,
+    /// The matched route, for per-route extensions such as the summary.
+    pub(crate) route: &'a Route,
+}
+
+impl<'a> Match<'a> {
+    /// A match of `route` as a whole, without captured parameters.
+    pub(crate) fn whole(route: &'a Route, base_path: &'a str) -> Self {
+        Self {
+            template: &route.template,
+            service: &route.service_name,
+            method: None,
+            params: Vec::new(),
+            base_path,
+            websocket: false,
+            route,
+        }
+    }
+}
+
 pub(crate) struct RouterSet {
-    pub(crate) by_host: HashMap<Option<String>, Vec<RouteGroup>>,
+    /// Route groups by routing key value; `None` holds the groups that
+    /// apply to every request.
+    pub(crate) by_key: HashMap<Option<String>, Vec<RouteGroup>>,
+    pub(crate) routing_key: RoutingKey,
+    /// Whether a request for exactly the server base path (e.g. `/api/v1`)
+    /// may match the `/` path item of that server.
+    pub(crate) match_base_path_root: bool,
+    pub(crate) base_path_match: BasePathMatch,
+    pub(crate) normalize_options: NormalizeOptions,
+    /// Memo of recent normalizations when `normalizeCacheSize` is set.
+    /// It lives and is replaced with the routes and `normalize_options` it
+    /// was filled under, so a request never pairs new routes with entries
+    /// from an earlier configuration.
+    pub(crate) normalize_cache: Option<NormalizeCache>,
+    /// Match counts per template when `templateStats` is set, replaced
+    /// with the routes like `normalize_cache`.
+    pub(crate) template_hits: Option<TemplateHits>,
+    pub(crate) miss_log_sampler: Sampler,
+    pub(crate) match_precedence: MatchPrecedence,
+    pub(crate) host_match_order: HostMatchOrder,
+    pub(crate) any_method_precedence: AnyMethodPrecedence,
+    pub(crate) param_coercion: ParamCoercion,
+    /// When set, only these hosts may select host-specific route groups;
+    /// other hosts are matched against wildcard groups only.
+    pub(crate) trusted_hosts: Option<HashSet<String>>,
+    /// Hosts whose requests never match, whatever the routes.
+    pub(crate) denied_hosts: HashSet<String>,
+    /// Minimum number of static segments a matched template must have.
+    pub(crate) min_specificity: Option<usize>,
+    /// Whether the candidate with the most template segments wins over the
+    /// first one in precedence order.
+    pub(crate) prefer_longest_template: bool,
+    /// Breaks `prefer_longest_template` ties between equally long templates.
+    pub(crate) tie_break: TieBreak,
+    /// Route for the `/` path from `rootService`, taken before any group.
+    pub(crate) root_route: Option<Route>,
+}
+
+/// Request attribute that selects the route groups to match against.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RoutingKey {
+    /// The request host, compared with the `servers.url` hosts.
+    Host,
+    /// The value of this request header, compared with each service's
+    /// `routingValue`.
+    Header(String),
+}
+
+/// Order in which route groups and method routers are tried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MatchPrecedence {
+    /// Method-specific then any-method routes of a group before the next group.
+    GroupFirst,
+    /// Method-specific routes of all groups before any-method routes of any group.
+    MethodFirst,
+}
+
+/// Whether a group's any-method routes are tried after or before its
+/// method-specific routes. Prefix routes are always tried last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AnyMethodPrecedence {
+    /// Method-specific routes win.
+    Low,
+    /// Any-method routes override method-specific ones.
+    High,
+}
+
+/// How ties between services are broken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TieBreak {
+    /// The service declared first wins, as does the earlier candidate in
+    /// precedence order.
+    ConfigOrder,
+    /// Services are taken in name order, and the candidate whose service
+    /// name sorts first wins.
+    ServiceName,
+}
+
+/// Whether key-specific route groups are tried before or after the groups
+/// that apply to every request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HostMatchOrder {
+    /// Key-specific groups win over wildcard groups.
+    HostFirst,
+    /// Wildcard groups win over key-specific groups.
+    WildcardFirst,
+}
+
+/// How a request path is tested against a server base path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BasePathMatch {
+    /// The base path must be followed by `/` or end the path, so `/api`
+    /// matches `/api/users` but not `/apiv1/users`.
+    Segment,
+    /// Raw string prefix, so `/api` also matches `/apiv1/users` and leaves
+    /// `/v1/users` to be matched.
+    Prefix,
 }
 
 impl RouterSet {
     pub(crate) fn new() -> Self {
         Self {
-            by_host: HashMap::new(),
+            by_key: HashMap::new(),
+            routing_key: RoutingKey::Host,
+            match_base_path_root: true,
+            base_path_match: BasePathMatch::Segment,
+            normalize_options: NormalizeOptions::default(),
+            normalize_cache: None,
+            template_hits: None,
+            miss_log_sampler: Sampler::new(1),
+            match_precedence: MatchPrecedence::GroupFirst,
+            host_match_order: HostMatchOrder::HostFirst,
+            any_method_precedence: AnyMethodPrecedence::Low,
+            param_coercion: ParamCoercion::Off,
+            trusted_hosts: None,
+            denied_hosts: HashSet::new(),
+            min_specificity: None,
+            prefer_longest_template: false,
+            tie_break: TieBreak::ConfigOrder,
+            root_route: None,
         }
     }
 
-    pub(crate) fn match_route(
-        &self,
+    pub(crate) fn match_route<'a>(
+        &'a self,
+        host: Option<&str>,
+        method: &str,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Match<'a>> {
+        let result = self.find_route(host, method, path, get_header);
+        if let (Some(hits), Some(matched)) = (&self.template_hits, &result) {
+            hits.record(matched.service, matched.template);
+        }
+        result
+    }
+
+    /// Matches the request, without counting it for `templateStats`.
+    fn find_route<'a>(
+        &'a self,
         host: Option<&str>,
         method: &str,
         path: &str,
-    ) -> Option<(String, Rc<String>)> {
-        let normalized_path = normalize_path(path);
-        let host_key = host.map(|h| h.to_ascii_lowercase());
-        let mut groups = Vec::new();
-
-        if let Some(host) = host_key.as_ref() {
-            if let Some(host_groups) = self.by_host.get(&Some(host.clone())) {
-                groups.extend(host_groups.iter());
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Match<'a>> {
+        let normalized_path = self.request_path(path)?;
+        if normalized_path == "/" {
+            if let Some(route) = &self.root_route {
+                debug!("[oef] {} matched rootService {}", path, route.service_name);
+                return Some(Match::whole(route, ""));
             }
         }
-        if let Some(wildcard_groups) = self.by_host.get(&None) {
-            groups.extend(wildcard_groups.iter());
+        let trailing_slash = has_trailing_slash(path, &self.normalize_options);
+        let websocket = get_header("upgrade")
+            .is_some_and(|upgrade| upgrade.trim().eq_ignore_ascii_case("websocket"));
+        let mut candidates = self.candidate_groups(host, get_header, &normalized_path);
+        // An exclusive base path owns its requests, hiding all later groups
+        let exclusive = candidates
+            .iter()
+            .position(|(group, _)| group.exclusive.is_some());
+        if let Some(position) = exclusive {
+            candidates.truncate(position + 1);
         }
-
-        for group in groups {
-            if let Some(stripped_path) = group.strip_base_path(&normalized_path) {
-                if let Some(router) = group.methods.get(method) {
-                    if let Some(result) = Self::match_router(router, &stripped_path, path) {
-                        return Some(result);
+        // Routers to try, in precedence order
+        let mut attempts = Vec::new();
+        match self.match_precedence {
+            MatchPrecedence::GroupFirst => {
+                for (group, stripped_path) in &candidates {
+                    let (first, fallback) =
+                        group.ordered_routers(method, websocket, self.any_method_precedence);
+                    for (router, router_method) in first.into_iter().chain(fallback) {
+                        attempts.push((*group, stripped_path, router, router_method));
                     }
                 }
-                if let Some(result) = Self::match_router(&group.any_method, &stripped_path, path) {
-                    return Some(result);
+            }
+            MatchPrecedence::MethodFirst => {
+                let mut fallbacks = Vec::new();
+                for (group, stripped_path) in &candidates {
+                    let (first, fallback) =
+                        group.ordered_routers(method, websocket, self.any_method_precedence);
+                    for (router, router_method) in first {
+                        attempts.push((*group, stripped_path, router, router_method));
+                    }
+                    for (router, router_method) in fallback {
+                        fallbacks.push((*group, stripped_path, router, router_method));
+                    }
                 }
+                attempts.extend(fallbacks);
             }
         }
 
-        debug!(
-            "[oef] No match found for host: {:?}, method: {}, path: {}",
-            host, method, normalized_path
-        );
+        let mut matches =
+            attempts
+                .into_iter()
+                .filter_map(|(group, stripped_path, router, router_method)| {
+                    self.match_router(router, stripped_path, trailing_slash, path, get_header)
+                        .filter(|result| self.is_specific_enough(result.route))
+                        .map(|mut result| {
+                            result.websocket = router_method == Some(WEBSOCKET_METHOD);
+                            result.method = result
+                                .route
+                                .canonical_method
+                                .as_deref()
+                                .or(router_method)
+                                .filter(|_| !result.websocket);
+                            result.base_path = &group.base_path;
+                            result
+                        })
+                });
+        let result = if self.prefer_longest_template {
+            // Unless ties go by service name, earlier candidates win them, so
+            // precedence still breaks them
+            matches.fold(None, |longest: Option<Match<'a>>, candidate| {
+                let Some(longest) = longest else {
+                    return Some(candidate);
+                };
+                let order = segment_count(longest.template)
+                    .cmp(&segment_count(candidate.template))
+                    .then_with(|| match self.tie_break {
+                        TieBreak::ConfigOrder => Ordering::Equal,
+                        TieBreak::ServiceName => candidate.service.cmp(longest.service),
+                    });
+                if order == Ordering::Less {
+                    Some(candidate)
+                } else {
+                    Some(longest)
+                }
+            })
+        } else {
+            matches.next()
+        };
+        if result.is_some() {
+            return result;
+        }
+        if let Some((group, route)) = exclusive.and_then(|position| {
+            let group = candidates[position].0;
+            group.exclusive.as_ref().map(|route| (group, route))
+        }) {
+            debug!(
+                "[oef] {} fell back to {} under exclusive base path '{}'",
+                path, route.service_name, group.base_path
+            );
+            return Some(Match::whole(route, &group.base_path));
+        }
+
+        if self.miss_log_sampler.sample() {
+            debug!(
+                "[oef] No match found for host: {:?}, method: {}, path: {} (miss #{})",
+                host,
+                method,
+                normalized_path,
+                self.miss_log_sampler.count()
+            );
+        }
         None
     }
 
-    fn match_router(
-        router: &Router<(String, Rc<String>)>,
+    /// Uppercased methods with a route for `path` in any route group that
+    /// applies to the request, deduplicated and sorted. Any-method, prefix
+    /// and WebSocket routes are not listed.
+    pub(crate) fn allowed_methods(
+        &self,
+        host: Option<&str>,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Vec<String> {
+        let Some(normalized_path) = self.request_path(path) else {
+            return Vec::new();
+        };
+        let trailing_slash = has_trailing_slash(path, &self.normalize_options);
+        let mut methods: Vec<String> = self
+            .candidate_groups(host, get_header, &normalized_path)
+            .into_iter()
+            .flat_map(|(group, stripped_path)| {
+                group
+                    .methods
+                    .iter()
+                    // The same checks as matching, so every listed method
+                    // would match this request
+                    .filter(move |(method, router)| {
+                        method.as_str() != WEBSOCKET_METHOD
+                            && self
+                                .match_router(
+                                    router.as_ref(),
+                                    &stripped_path,
+                                    trailing_slash,
+                                    path,
+                                    get_header,
+                                )
+                                .is_some()
+                    })
+                    .map(|(method, _)| method.to_ascii_uppercase())
+            })
+            .collect();
+        methods.sort();
+        methods.dedup();
+        methods
+    }
+
+    /// Finds the registered template sharing the most leading segments with
+    /// `path`, for diagnosing near misses. Ties go to the shorter template.
+    pub(crate) fn nearest_template(
+        &self,
+        host: Option<&str>,
+        path: &str,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<&str> {
+        let normalized_path = self.request_path(path)?;
+        let mut nearest: Option<(usize, usize, &str)> = None;
+        for (group, stripped_path) in self.candidate_groups(host, get_header, &normalized_path) {
+            let segments: Vec<&str> = stripped_path.split('/').filter(|s| !s.is_empty()).collect();
+            for template in &group.templates {
+                let template_segments: Vec<&str> =
+                    template.split('/').filter(|s| !s.is_empty()).collect();
+                let score = segments
+                    .iter()
+                    .zip(&template_segments)
+                    .take_while(|(segment, template_segment)| {
+                        template_segment.starts_with('{') || segment == template_segment
+                    })
+                    .count();
+                let better = nearest.is_none_or(|(best_score, best_len, _)| {
+                    score > best_score
+                        || (score == best_score && template_segments.len() < best_len)
+                });
+                if score > 0 && better {
+                    nearest = Some((score, template_segments.len(), template));
+                }
+            }
+        }
+        nearest.map(|(_, _, template)| template)
+    }
+
+    /// Whether requests from `host` must be treated as unmatched, whatever
+    /// the routes or route tokens say.
+    pub(crate) fn is_denied_host(&self, host: Option<&str>) -> bool {
+        host.is_some_and(|host| self.denied_hosts.contains(&host.to_ascii_lowercase()))
+    }
+
+    /// Whether a route token from `host` may be honored: with
+    /// `trustedHosts`, only from a trusted host.
+    pub(crate) fn trusts_token_host(&self, host: Option<&str>) -> bool {
+        self.trusted_hosts.as_ref().is_none_or(|trusted_hosts| {
+            host.is_some_and(|host| trusted_hosts.contains(&host.to_ascii_lowercase()))
+        })
+    }
+
+    /// The normalized path of a request, or `None` if it fails normalization.
+    fn request_path(&self, path: &str) -> Option<String> {
+        let normalized_path = self.normalize(path);
+        if normalized_path.is_none() {
+            debug!(
+                "[oef] Path exceeds maxPathSegments or has whitespace, treating as no match: {:?}",
+                path
+            );
+        }
+        normalized_path
+    }
+
+    /// Normalizes `path` with the configured options, through the cache if
+    /// one is configured.
+    fn normalize(&self, path: &str) -> Option<String> {
+        match &self.normalize_cache {
+            Some(cache) => cache.normalize(path, &self.normalize_options),
+            None => normalize_path_with(path, &self.normalize_options),
+        }
+    }
+
+    /// Route groups applicable to the request's routing key, key-specific
+    /// ones first, paired with the path relative to each group's base path.
+    fn candidate_groups(
+        &self,
+        host: Option<&str>,
+        get_header: &dyn Fn(&str) -> Option<String>,
+        normalized_path: &str,
+    ) -> Vec<(&RouteGroup, String)> {
+        let key_groups = self
+            .routing_key_value(host, get_header)
+            .and_then(|key| self.by_key.get(&Some(key)))
+            .into_iter()
+            .flatten();
+        let wildcard_groups = self.by_key.get(&None).into_iter().flatten();
+        let groups: Vec<&RouteGroup> = match self.host_match_order {
+            HostMatchOrder::HostFirst => key_groups.chain(wildcard_groups).collect(),
+            HostMatchOrder::WildcardFirst => wildcard_groups.chain(key_groups).collect(),
+        };
+
+        groups
+            .into_iter()
+            .filter_map(|group| {
+                group
+                    .strip_base_path(
+                        normalized_path,
+                        self.base_path_match,
+                        self.match_base_path_root,
+                    )
+                    .map(|stripped_path| (group, stripped_path))
+            })
+            .collect()
+    }
+
+    /// The lowercased routing key value of the request, if it has one that
+    /// may select key-specific groups.
+    fn routing_key_value(
+        &self,
+        host: Option<&str>,
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        match &self.routing_key {
+            RoutingKey::Host => host.map(|h| h.to_ascii_lowercase()).filter(|host| {
+                let trusted = self
+                    .trusted_hosts
+                    .as_ref()
+                    .is_none_or(|trusted_hosts| trusted_hosts.contains(host));
+                if !trusted {
+                    debug!(
+                        "[oef] Host '{}' is not trusted, using wildcard routes only",
+                        host
+                    );
+                }
+                trusted
+            }),
+            RoutingKey::Header(name) => get_header(name)
+                .map(|value| value.trim().to_ascii_lowercase())
+                .filter(|value| !value.is_empty()),
+        }
+    }
+
+    /// Coerces parameters to the types declared by the route's path item.
+    /// Returns `None` if a value is not of its type and coercion is strict.
+    fn coerce_params(
+        &self,
+        route: &Route,
+        params: Vec<(String, String)>,
+    ) -> Option<Vec<(String, String)>> {
+        if self.param_coercion == ParamCoercion::Off || route.param_types.is_empty() {
+            return Some(params);
+        }
+        params
+            .into_iter()
+            .map(|(name, value)| {
+                let Some((_, param_type)) =
+                    route.param_types.iter().find(|(typed, _)| *typed == name)
+                else {
+                    return Some((name, value));
+                };
+                match param_type.coerce(&value) {
+                    Some(coerced) => Some((name, coerced)),
+                    None if self.param_coercion == ParamCoercion::Lenient => Some((name, value)),
+                    None => {
+                        debug!(
+                            "[oef] Rejected {}, {}: '{}' is not a valid {:?} for '{}'",
+                            route.service_name, route.template, value, param_type, name
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Entries and capacity of the normalize cache, both `0` without one.
+    pub(crate) fn cache_fill(&self) -> (usize, usize) {
+        self.normalize_cache
+            .as_ref()
+            .map_or((0, 0), |cache| (cache.len(), cache.capacity()))
+    }
+
+    fn is_specific_enough(&self, route: &Route) -> bool {
+        let Some(min) = self.min_specificity else {
+            return true;
+        };
+        let static_segments = route
+            .template
+            .split('/')
+            .filter(|segment| !segment.is_empty() && !segment.starts_with('{') && *segment != "*")
+            .count();
+        if static_segments < min {
+            debug!(
+                "[oef] Rejected {}, {}: {} static segments, minSpecificity is {}",
+                route.service_name, route.template, static_segments, min
+            );
+            return false;
+        }
+        true
+    }
+
+    fn match_router<'a>(
+        &self,
+        router: &'a dyn PathMatcher,
         stripped_path: &str,
+        trailing_slash: bool,
         original_path: &str,
-    ) -> Option<(String, Rc<String>)> {
-        match router.at(stripped_path) {
-            Ok(matched) => {
-                let (matched_path, service_name) = matched.value.clone();
+        get_header: &dyn Fn(&str) -> Option<String>,
+    ) -> Option<Match<'a>> {
+        // Routes that preserve their trailing slash get the first chance at
+        // a request that had one
+        let slashed =
+            (trailing_slash && stripped_path != "/").then(|| format!("{}/", stripped_path));
+        let preserved = slashed
+            .as_deref()
+            .and_then(|slashed| router.at(slashed))
+            .filter(|matched| matched.value.preserve_trailing_slash);
+        // Then routes with `x-extensions`, matched without the extension
+        let extension = split_extension(stripped_path);
+        let (matched, extension) = match preserved {
+            Some(matched) => (matched, None),
+            None => match extension
+                .and_then(|(base, _)| router.at(base))
+                .filter(|matched| !matched.value.extensions.is_empty())
+            {
+                Some(matched) => (matched, extension.map(|(_, extension)| extension)),
+                None => (router.at(stripped_path)?, None),
+            },
+        };
+
+        let route = matched.value;
+        if let Some(header) = &route.required_header {
+            if get_header(header).is_none() {
                 debug!(
-                    "[oef] {} matched with {}, {}",
-                    original_path, service_name, matched_path
+                    "[oef] {} skipped {}, {}: missing required header '{}'",
+                    original_path, route.service_name, route.template, header
                 );
-                Some((matched_path, service_name))
+                return None;
             }
-            Err(_) => None,
         }
+        // An extension the route does not list falls back to its own service
+        let service = extension
+            .and_then(|extension| {
+                route
+                    .extensions
+                    .iter()
+                    .find(|(declared, _)| declared.eq_ignore_ascii_case(extension))
+            })
+            .map_or(&route.service_name, |(_, service)| service);
+        // Large uploads go to their dedicated service, whatever the extension
+        let service = match &route.large_body {
+            Some(large_body)
+                if get_header("content-length")
+                    .and_then(|length| length.trim().parse::<u64>().ok())
+                    .is_some_and(|length| length > large_body.threshold) =>
+            {
+                &large_body.service
+            }
+            _ => service,
+        };
+        debug!(
+            "[oef] {} matched with {}, {}",
+            original_path, service, route.template
+        );
+        let params = matched
+            .params
+            .iter()
+            .map(|(name, value)| (route.param_name(name).to_string(), value.to_string()))
+            .collect();
+        let params = self.coerce_params(route, params)?;
+        Some(Match {
+            template: &route.template,
+            service,
+            method: None,
+            params,
+            base_path: "",
+            websocket: false,
+            route,
+        })
     }
 }
 
+/// Splits a trailing extension such as `.json` off the last path segment,
+/// returning the path without it and the extension. Dot files such as
+/// `/.well-known` have no extension.
+fn split_extension(path: &str) -> Option<(&str, &str)> {
+    let segment_start = path.rfind('/').map_or(0, |slash| slash + 1);
+    let dot = path[segment_start..].rfind('.')? + segment_start;
+    (dot > segment_start && dot + 1 < path.len()).then(|| (&path[..dot], &path[dot..]))
+}
+
+/// Routers paired with the method they hold, `None` for any method.
+type RouterList<'a> = Vec<(&'a dyn PathMatcher, Option<&'a str>)>;
+
 pub(crate) struct RouteGroup {
     pub(crate) base_path: String,
-    pub(crate) any_method: Router<(String, Rc<String>)>,
-    pub(crate) methods: HashMap<String, Router<(String, Rc<String>)>>,
+    pub(crate) any_method: Box<dyn PathMatcher>,
+    pub(crate) methods: HashMap<String, Box<dyn PathMatcher>>,
+    /// Catch-all routes from service `prefixes`, consulted after templates.
+    pub(crate) prefixes: Box<dyn PathMatcher>,
+    /// Path templates registered in this group, kept for near-miss diagnostics.
+    pub(crate) templates: Vec<String>,
+    /// Fallback route of a `basePathExclusive` service, used when nothing
+    /// else in the group matches; later groups are then never tried.
+    pub(crate) exclusive: Option<Route>,
 }
 
 impl RouteGroup {
-    pub(crate) fn new(base_path: String) -> Self {
+    pub(crate) fn new(base_path: String, match_engine: MatchEngine) -> Self {
         Self {
             base_path,
-            any_method: Router::new(),
+            any_method: match_engine.matcher(),
             methods: HashMap::new(),
+            prefixes: match_engine.matcher(),
+            templates: Vec::new(),
+            exclusive: None,
+        }
+    }
+
+    /// Method-specific routers to try, paired with the method they hold.
+    fn method_routers(&self, method: &str, websocket: bool) -> RouterList<'_> {
+        let mut routers = Vec::new();
+        if websocket {
+            if let Some((key, router)) = self.methods.get_key_value(WEBSOCKET_METHOD) {
+                routers.push((router.as_ref(), Some(key.as_str())));
+            }
+        }
+        if let Some((key, router)) = self.methods.get_key_value(method) {
+            routers.push((router.as_ref(), Some(key.as_str())));
+        }
+        routers
+    }
+
+    /// Routers to try for `method`, split into those tried first and the
+    /// fallbacks that `MatchPrecedence::MethodFirst` defers past all groups.
+    fn ordered_routers(
+        &self,
+        method: &str,
+        websocket: bool,
+        any_method_precedence: AnyMethodPrecedence,
+    ) -> (RouterList<'_>, RouterList<'_>) {
+        let any_method = (self.any_method.as_ref(), None);
+        let prefixes = (self.prefixes.as_ref(), None);
+        let mut first = self.method_routers(method, websocket);
+        match any_method_precedence {
+            AnyMethodPrecedence::Low => (first, vec![any_method, prefixes]),
+            AnyMethodPrecedence::High => {
+                first.insert(0, any_method);
+                (first, vec![prefixes])
+            }
         }
     }
 
-    pub(crate) fn strip_base_path(&self, path: &str) -> Option<String> {
+    pub(crate) fn strip_base_path(
+        &self,
+        path: &str,
+        mode: BasePathMatch,
+        match_root: bool,
+    ) -> Option<String> {
         if self.base_path.is_empty() {
             return Some(path.to_string());
         }
-        if path == self.base_path {
-            return Some("/".to_string());
+        let remainder = path.strip_prefix(self.base_path.as_str())?;
+        if remainder.is_empty() {
+            return match_root.then(|| "/".to_string());
         }
-        if path.starts_with(&self.base_path) {
-            let remainder = &path[self.base_path.len()..];
-            if remainder.starts_with('/') {
-                return Some(remainder.to_string());
-            }
+        if remainder.starts_with('/') {
+            return Some(remainder.to_string());
+        }
+        match mode {
+            BasePathMatch::Segment => None,
+            BasePathMatch::Prefix => Some(format!("/{}", remainder)),
+        }
+    }
+}
+
+pub(crate) fn segment_count(template: &str) -> usize {
+    template
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count()
+}
+
+/// Rewrites the template for insertion into a router. Parameter names with
+/// characters other than ASCII letters, digits and `_` (after the `*` of a
+/// catch-all) are replaced, since the router rejects or misreads some of
+/// them. Returns the rewritten template and the replaced names as
+/// `(alias, original)` pairs.
+pub(crate) fn matchit_template(template: &str) -> (String, Vec<(String, String)>) {
+    let mut rewritten = String::with_capacity(template.len());
+    let mut aliases = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        rewritten.push_str(&rest[..=start]);
+        let mut name = &rest[start + 1..end];
+        if let Some(catch_all) = name.strip_prefix('*') {
+            rewritten.push('*');
+            name = catch_all;
+        }
+        if !name.is_empty()
+            && name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+        {
+            rewritten.push_str(name);
+        } else {
+            let alias = format!("__oef_param{}", aliases.len());
+            rewritten.push_str(&alias);
+            aliases.push((alias, name.to_string()));
+        }
+        rewritten.push('}');
+        rest = &rest[end + 1..];
+    }
+    rewritten.push_str(rest);
+    (rewritten, aliases)
+}
+
+#[derive(Clone)]
+pub(crate) struct NormalizeOptions {
+    /// Maximum number of segments a request path may have; longer paths
+    /// are rejected during normalization.
+    pub(crate) max_segments: Option<usize>,
+    /// Whether `+` in the path is decoded to `%20`, as some clients
+    /// encode spaces that way outside the query string.
+    pub(crate) decode_plus: bool,
+    /// Whether everything from a literal `#` on is dropped as a fragment.
+    /// An encoded `%23` is never treated as a fragment.
+    pub(crate) strip_fragment: bool,
+    /// Whether `.` and `..` segments are removed as in RFC 3986 section
+    /// 5.2.4; `..` above the root is dropped.
+    pub(crate) resolve_dot_segments: bool,
+    /// Whether the hex digits of percent-escapes are uppercased, so `%2f`
+    /// and `%2F` normalize the same.
+    pub(crate) uppercase_percent_encoding: bool,
+    /// Whether a leading `//` is kept instead of collapsed, so that
+    /// protocol-relative-looking paths such as `//foo` stay distinct.
+    pub(crate) preserve_leading_double_slash: bool,
+    /// Whether ASCII whitespace around the raw path is removed first.
+    pub(crate) trim_path: bool,
+    /// Whether paths with whitespace (tabs, spaces, line breaks) left in
+    /// them are rejected.
+    pub(crate) reject_whitespace: bool,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        Self {
+            max_segments: None,
+            decode_plus: false,
+            strip_fragment: true,
+            resolve_dot_segments: false,
+            uppercase_percent_encoding: false,
+            preserve_leading_double_slash: false,
+            trim_path: false,
+            reject_whitespace: false,
         }
-        None
     }
 }
 
 pub(crate) fn normalize_path(path: &str) -> String {
-    let without_query = path.split('?').next().unwrap_or("");
-    let without_fragment = without_query.split('#').next().unwrap_or("");
+    normalize_path_with(path, &NormalizeOptions::default()).unwrap_or_default()
+}
 
-    let segments: Vec<&str> = without_fragment
-        .split('/')
-        .filter(|s| !s.is_empty())
-        .collect();
+/// Raw paths longer than this are normalized but never cached.
+const MAX_CACHED_PATH_BYTES: usize = 1024;
+
+/// Bounded memo of raw path to normalized path. The oldest entry is evicted
+/// first. Keys exclude the query string, which normalization drops anyway.
+pub(crate) struct NormalizeCache {
+    capacity: usize,
+    entries: RefCell<HashMap<String, Option<String>>>,
+    order: RefCell<VecDeque<String>>,
+}
+
+impl NormalizeCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: RefCell::new(HashMap::new()),
+            order: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn normalize(&self, path: &str, options: &NormalizeOptions) -> Option<String> {
+        let key = path.split('?').next().unwrap_or_default();
+        // Equivalent escapes share an entry
+        let key = if options.uppercase_percent_encoding {
+            uppercase_percent_escapes(key)
+        } else {
+            Cow::Borrowed(key)
+        };
+        let key: &str = &key;
+        if key.len() > MAX_CACHED_PATH_BYTES {
+            return normalize_path_with(key, options);
+        }
+        if let Some(normalized) = self.entries.borrow().get(key) {
+            return normalized.clone();
+        }
+
+        let normalized = normalize_path_with(key, options);
+        let mut entries = self.entries.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        if entries.len() >= self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key.to_string(), normalized.clone());
+        order.push_back(key.to_string());
+        normalized
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Match counts per service and template, for `templateStats`. Only
+/// configured templates are counted, so the map stays bounded.
+#[derive(Default)]
+pub(crate) struct TemplateHits {
+    counts: RefCell<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl TemplateHits {
+    pub(crate) fn record(&self, service: &str, template: &str) {
+        let mut counts = self.counts.borrow_mut();
+        let templates = match counts.get_mut(service) {
+            Some(templates) => templates,
+            None => counts.entry(service.to_string()).or_default(),
+        };
+        match templates.get_mut(template) {
+            Some(count) => *count += 1,
+            None => {
+                templates.insert(template.to_string(), 1);
+            }
+        }
+    }
 
+    /// Matches of `template` of `service` so far.
+    pub(crate) fn count(&self, service: &str, template: &str) -> u64 {
+        self.counts
+            .borrow()
+            .get(service)
+            .and_then(|templates| templates.get(template))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// The raw request path, without surrounding whitespace under `trim_path`.
+fn trim_path<'a>(path: &'a str, options: &NormalizeOptions) -> &'a str {
+    if options.trim_path {
+        path.trim_matches(|c: char| c.is_ascii_whitespace())
+    } else {
+        path
+    }
+}
+
+/// Whether the request path ends with `/` before normalization, ignoring
+/// the query and fragment.
+fn has_trailing_slash(path: &str, options: &NormalizeOptions) -> bool {
+    let without_query = trim_path(path, options).split('?').next().unwrap_or("");
+    let without_fragment = if options.strip_fragment {
+        without_query.split('#').next().unwrap_or("")
+    } else {
+        without_query
+    };
+    without_fragment.len() > 1 && without_fragment.ends_with('/')
+}
+
+/// Uppercases the hex digits of every `%XX` escape, per RFC 3986 section
+/// 6.2.2.1. Other `%` characters are left alone.
+fn uppercase_percent_escapes(path: &str) -> Cow<'_, str> {
+    let bytes = path.as_bytes();
+    let is_lowercase_escape = |i: usize| {
+        bytes[i] == b'%'
+            && bytes.get(i + 1..i + 3).is_some_and(|hex| {
+                hex.iter().all(u8::is_ascii_hexdigit) && hex.iter().any(u8::is_ascii_lowercase)
+            })
+    };
+    if !(0..bytes.len()).any(is_lowercase_escape) {
+        return Cow::Borrowed(path);
+    }
+
+    let mut canonical = bytes.to_vec();
+    let mut i = 0;
+    while i < canonical.len() {
+        if is_lowercase_escape(i) {
+            canonical[i + 1..i + 3].make_ascii_uppercase();
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    // Only ASCII letters changed, so the bytes are still valid UTF-8
+    Cow::Owned(String::from_utf8(canonical).unwrap_or_else(|_| path.to_string()))
+}
+
+pub(crate) fn normalize_path_with(path: &str, options: &NormalizeOptions) -> Option<String> {
+    // Query and fragment are cut on the raw path's literal delimiters only;
+    // any decoding below must never introduce new ones.
+    let without_query = trim_path(path, options).split('?').next().unwrap_or("");
+    let without_fragment = if options.strip_fragment {
+        without_query.split('#').next().unwrap_or("")
+    } else {
+        without_query
+    };
+    // Vertical tab is not `is_ascii_whitespace`, but no less suspicious
+    if options.reject_whitespace
+        && without_fragment
+            .bytes()
+            .any(|byte| byte.is_ascii_whitespace() || byte == b'\x0b')
+    {
+        return None;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in without_fragment.split('/').filter(|s| !s.is_empty()) {
+        if options.resolve_dot_segments {
+            match segment {
+                "." => continue,
+                ".." => {
+                    segments.pop();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        if options
+            .max_segments
+            .is_some_and(|max| segments.len() >= max)
+        {
+            return None;
+        }
+        segments.push(segment);
+    }
+
+    let root = if options.preserve_leading_double_slash && without_fragment.starts_with("//") {
+        "//"
+    } else {
+        "/"
+    };
     if segments.is_empty() {
-        return "/".to_string();
+        return Some(root.to_string());
     }
 
-    format!("/{}", segments.join("/"))
+    let mut normalized = format!("{}{}", root, segments.join("/"));
+    if options.uppercase_percent_encoding {
+        normalized = uppercase_percent_escapes(&normalized).into_owned();
+    }
+    // Templates spell a space `%20`, so that is what a decoded `+` becomes
+    if options.decode_plus {
+        return Some(normalized.replace('+', "%20"));
+    }
+    Some(normalized)
 }
@@ -0,0 +1,28 @@
+use std::cell::Cell;
+
+/// Counter-based 1-in-N sampler shared by all HTTP contexts of a root context.
+pub(crate) struct Sampler {
+    rate: u64,
+    count: Cell<u64>,
+}
+
+impl Sampler {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            count: Cell::new(0),
+        }
+    }
+
+    /// Records an event and returns whether it is sampled. The first event
+    /// is always sampled, then every `rate`-th one after it.
+    pub(crate) fn sample(&self) -> bool {
+        let count = self.count.get();
+        self.count.set(count.wrapping_add(1));
+        count.is_multiple_of(self.rate)
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.get()
+    }
+}